@@ -0,0 +1,52 @@
+#!/bin/bash
+# Runs the same benchmark against two revisions and prints both reports:
+#
+#     scripts/compare-bench.sh BEFORE AFTER [paxos-bench options]
+#
+# Each revision is built in a temporary worktree, against the dependencies
+# already built in ./build (see `make deps`).  A revision without paxos-bench
+# is measured by running its runner for --duration seconds (10 by default)
+# instead, and only the CPU time it used is reported.
+set -e
+
+if [ $# -lt 2 ]; then
+	echo "Usage: $0 BEFORE AFTER [paxos-bench options]" >&2
+	exit 64
+fi
+before=$1
+after=$2
+shift 2
+
+root=$(git rev-parse --show-toplevel)
+rustflags=${RUSTFLAGS:--Z debug-info}
+
+duration=10
+args=("$@")
+for ((i = 0; i < ${#args[@]}; i++)); do
+	case ${args[$i]} in
+		-d|--duration) duration=${args[$((i + 1))]} ;;
+		--duration=*) duration=${args[$i]#--duration=} ;;
+	esac
+done
+
+run() {
+	local rev=$1
+	shift
+	local dir
+	dir=$(mktemp -d)
+	git -C "$root" worktree add --detach "$dir" "$rev" >/dev/null
+	echo "== $rev ($(git -C "$root" rev-parse --short "$rev"))"
+	if [ -f "$dir/src/bench/main.rs" ]; then
+		make -s -C "$dir" paxos bench BUILDDIR="$dir/build" RUSTFLAGS="$rustflags -L $root/build"
+		"$dir/build/paxos-bench" "$@"
+	else
+		make -s -C "$dir" paxos runner BUILDDIR="$dir/build" RUSTFLAGS="$rustflags -L $root/build"
+		# The runner's replicas never exit on their own
+		local TIMEFORMAT="CPU time: user %U s, system %S s in %R s"
+		(cd "$dir" && time timeout "$duration" build/runner >/dev/null 2>&1) || true
+	fi
+	git -C "$root" worktree remove --force "$dir"
+}
+
+run "$before" "$@"
+run "$after" "$@"
@@ -0,0 +1,390 @@
+extern mod extra;
+extern mod paxos;
+
+use std::os;
+use std::libc;
+use std::vec;
+use std::cmp::max;
+use std::path::Path;
+use std::io::stderr;
+use std::io::timer::sleep;
+
+use extra::time::precise_time_ns;
+use extra::getopts::groups::{getopts, optopt, optmulti, optflag, usage};
+
+use paxos::internal::replica::{Replica, ReplicaBuilder};
+use paxos::internal::config::ReplicaConfig;
+use paxos::internal::client::{Client, Disconnected};
+use paxos::internal::transport::{Address, Transport, SocketTransport};
+use paxos::internal::memory::MemoryTransport;
+
+// Drives a cluster through the client protocol and reports throughput and
+// commit latency:
+//
+//     paxos-bench --replica ADDR [--replica ADDR ...]   a running cluster,
+//                                                       through the client
+//                                                       ports given
+//     paxos-bench [--replicas N] [--config PATH]        a cluster started in
+//                                                       this process over a
+//                                                       MemoryTransport
+//     paxos-bench --idle [--replicas N] [--config PATH] the same cluster,
+//                                                       left without load
+//
+// Every worker has one connection and one command in flight at a time, and
+// the workers take turns over the replicas.  With a rate, commands are sent
+// on a fixed schedule and their latency counts from when they were due, so
+// that a stalled cluster doesn't hide behind the commands it kept from being
+// sent.  Without one, every worker sends its next command as soon as the
+// last commits.
+//
+// With the cluster in this process, the CPU time the process used while
+// measuring is reported as well, which is what tells an idle replica that
+// polls its channels from one that blocks on them.
+
+// Exit codes, as in sysexits.h
+static EXIT_USAGE: int = 64;
+static EXIT_UNAVAILABLE: int = 69;
+static EXIT_CONFIG: int = 78;
+
+static DEFAULT_REPLICAS: uint = 3;
+static DEFAULT_SIZE: uint = 16;
+static DEFAULT_CONCURRENCY: uint = 8;
+static DEFAULT_DURATION: uint = 10;
+
+static NS_PER_SEC: u64 = 1000000000;
+
+// From sys/resource.h
+static RUSAGE_SELF: libc::c_int = 0;
+
+struct timeval {
+    tv_sec: libc::time_t,
+    tv_usec: libc::suseconds_t,
+}
+
+struct rusage {
+    ru_utime: timeval,
+    ru_stime: timeval,
+    // The counters we don't read
+    ru_other: [libc::c_long, ..14],
+}
+
+extern {
+    fn getrusage(who: libc::c_int, usage: *mut rusage) -> libc::c_int;
+}
+
+// What the workers are to do together
+struct Load {
+    // Commands per second over all workers, or 0 for as many as they can
+    rate: uint,
+    // Bytes per command
+    size: uint,
+    concurrency: uint,
+    // When to start sending, when to start recording latencies, and when to
+    // stop, in precise_time_ns
+    start: u64,
+    measure_from: u64,
+    deadline: u64,
+}
+
+// What a worker saw
+struct Results {
+    // Commit latencies in nanoseconds, of the commands due after warmup
+    latencies: ~[u64],
+    failures: uint,
+    // When the last command returned, in precise_time_ns
+    finished: u64,
+}
+
+fn main() {
+    let args = os::args();
+    let opts = ~[
+        optmulti("r", "replica", "the client address of a replica of a running cluster", "ADDR"),
+        optopt("n", "replicas", format!("how many replicas to start in this process (default {})",
+            DEFAULT_REPLICAS), "N"),
+        optopt("c", "config", "a replica config to take the in-process replicas' tuning from", "PATH"),
+        optopt("", "rate", "commands per second, or 0 for as many as possible (default 0)", "N"),
+        optopt("s", "size", format!("bytes per command (default {})", DEFAULT_SIZE), "BYTES"),
+        optopt("w", "concurrency", format!("commands in flight at a time (default {})",
+            DEFAULT_CONCURRENCY), "N"),
+        optopt("d", "duration", format!("seconds to run for (default {})", DEFAULT_DURATION), "SECS"),
+        optopt("", "warmup", "seconds to run for before recording (default 0)", "SECS"),
+        optflag("", "idle", "only measure the CPU usage of the replicas started in this process"),
+        optflag("h", "help", "print this help and exit"),
+    ];
+    let matches = match getopts(args.tail(), opts) {
+        Ok(m) => m,
+        Err(f) => return fail_with(EXIT_USAGE, f.to_err_msg()),
+    };
+    if matches.opt_present("help") {
+        println(usage(format!("Usage: {} [--replica ADDR ... | [--idle] --replicas N [--config PATH]] [options]",
+            args[0]), opts));
+        return;
+    }
+    let number = |name: &str, default: uint| -> Option<uint> {
+        match matches.opt_str(name) {
+            Some(s) => from_str(s.as_slice()),
+            None => Some(default),
+        }
+    };
+    let (rate, size, concurrency, duration, warmup) = match (number("rate", 0), number("size", DEFAULT_SIZE),
+            number("concurrency", DEFAULT_CONCURRENCY), number("duration", DEFAULT_DURATION),
+            number("warmup", 0)) {
+        (Some(r), Some(s), Some(c), Some(d), Some(w)) if c > 0 && d > 0 => (r, s, c, d, w),
+        _ => return fail_with(EXIT_USAGE,
+            ~"--rate, --size, --concurrency, --duration and --warmup take numbers, the middle two positive"),
+    };
+    let addrs: ~[Option<Address>] = matches.opt_strs("replica").iter().map(|s| from_str(s.as_slice())).collect();
+    if addrs.iter().any(|a| a.is_none()) {
+        return fail_with(EXIT_USAGE, ~"--replica must be given an address");
+    }
+    let addrs: ~[Address] = addrs.move_iter().map(|a| a.unwrap()).collect();
+    let idle = matches.opt_present("idle");
+    if idle && !addrs.is_empty() {
+        return fail_with(EXIT_USAGE, ~"--idle only applies to replicas started in this process");
+    }
+
+    let load_from = |start: u64| Load{
+        rate: rate,
+        size: size,
+        concurrency: concurrency,
+        start: start,
+        measure_from: start + warmup as u64 * NS_PER_SEC,
+        deadline: start + (warmup + duration) as u64 * NS_PER_SEC,
+    };
+
+    if !addrs.is_empty() {
+        println!("Driving the replicas at {}", addrs.map(|a| a.to_str()).connect(", "));
+        let load = load_from(precise_time_ns());
+        match run(SocketTransport, addrs, load) {
+            Some(results) => report(results, load.measure_from),
+            None => fail_with(EXIT_UNAVAILABLE, ~"can't connect to every replica"),
+        }
+        return;
+    }
+
+    let replicas = match number("replicas", DEFAULT_REPLICAS) {
+        Some(n) if n > 0 => n,
+        _ => return fail_with(EXIT_USAGE, ~"--replicas must be a positive number"),
+    };
+    let template = match matches.opt_str("config") {
+        Some(path) => match ReplicaConfig::load(&Path::new(path.as_slice())) {
+            Ok(config) => Some(config),
+            Err(err) => return fail_with(EXIT_CONFIG, format!("{}: {}", path, err.to_str())),
+        },
+        None => None,
+    };
+    let (transport, cluster) = match start_cluster(replicas, template) {
+        Ok(started) => started,
+        Err(err) => return fail_with(EXIT_UNAVAILABLE, err),
+    };
+    let load = load_from(precise_time_ns());
+    let cpu = measure_cpu(load.measure_from, load.deadline);
+    if idle {
+        println!("Leaving {} replicas in this process idle", replicas);
+        report_cpu(cpu.recv());
+        unsafe { libc::exit(0) };
+    }
+    println!("Driving {} replicas in this process", replicas);
+    let clients: ~[Address] = cluster.iter().map(|r| r.client_address().unwrap()).collect();
+    let code = match run(transport, clients, load) {
+        Some(results) => {
+            report(results, load.measure_from);
+            report_cpu(cpu.recv());
+            for replica in cluster.iter() {
+                for &(peer, ref stats) in replica.batch_stats().iter() {
+                    println!("Replica {} wrote {} messages to replica {} in {} writes, {:.2f} per write",
+                        replica.id, stats.messages, peer, stats.batches, stats.mean_batch_size());
+                }
+            }
+            0
+        },
+        None => {
+            fail_with(EXIT_UNAVAILABLE, ~"can't connect to the replicas");
+            EXIT_UNAVAILABLE
+        },
+    };
+    // The replicas' tasks never finish on their own, and would keep the
+    // process alive
+    unsafe { libc::exit(code as libc::c_int) };
+}
+
+// Starts a cluster over a MemoryTransport, each replica listening for
+// clients too.  The replicas take their tuning from the template, if there
+// is one.
+fn start_cluster(replicas: uint, template: Option<ReplicaConfig>)
+                 -> Result<(MemoryTransport, ~[Replica]), ~str> {
+    let transport = MemoryTransport::new();
+    let any = from_str::<Address>("127.0.0.1:0").unwrap();
+    let mut builders = ~[];
+    for _ in range(0, replicas) {
+        match ReplicaBuilder::bind(transport.clone(), &any) {
+            Ok(builder) => builders.push(builder),
+            Err(err) => return Err(err.to_str()),
+        }
+    }
+    let peers: ~[Address] = builders.iter().map(|b| b.address()).collect();
+    let mut cluster = ~[];
+    for (id, builder) in builders.move_iter().enumerate() {
+        let mut config = ReplicaConfig::new(id, peers.clone());
+        config.cluster_id = ~"bench";
+        config.client_address = Some(any.clone());
+        for t in template.iter() {
+            config.cluster_key = t.cluster_key.clone();
+            config.send_queue = t.send_queue.clone();
+            config.batching = t.batching.clone();
+            config.proposal_batching = t.proposal_batching.clone();
+            config.max_in_flight = t.max_in_flight;
+            config.failure_detector = t.failure_detector.clone();
+        }
+        match builder.start(config) {
+            Ok(replica) => cluster.push(replica),
+            Err(err) => return Err(err.to_str()),
+        }
+    }
+    Ok((transport, cluster))
+}
+
+// Runs the load with one task per worker, and collects what they saw.
+// Returns None if a worker couldn't connect.
+fn run<T: Transport>(transport: T, addrs: ~[Address], load: Load) -> Option<Results> {
+    let (port, chan) = SharedChan::new();
+    for w in range(0, load.concurrency) {
+        let chan = chan.clone();
+        let transport = transport.clone();
+        let addr = addrs[w % addrs.len()].clone();
+        let load = load;
+        do spawn {
+            chan.send(work(w, &transport, &addr, &load));
+        }
+    }
+
+    let mut all = Results{ latencies: ~[], failures: 0, finished: 0 };
+    let mut connected = true;
+    for _ in range(0, load.concurrency) {
+        match port.recv() {
+            Some(results) => {
+                all.latencies.push_all_move(results.latencies);
+                all.failures += results.failures;
+                all.finished = max(all.finished, results.finished);
+            },
+            None => connected = false,
+        }
+    }
+    if connected { Some(all) } else { None }
+}
+
+// Submits commands until the deadline, as worker w of the load.  With a
+// rate, commands that fell behind the schedule aren't caught up on past the
+// deadline; the run stops on time either way, but for the command in flight.
+fn work<T: Transport>(w: uint, transport: &T, addr: &Address, load: &Load) -> Option<Results> {
+    let mut client = match Client::with_transport(transport, addr) {
+        Some(client) => client,
+        None => return None,
+    };
+    let value = vec::from_elem(load.size, 0u8);
+    let mut results = Results{ latencies: ~[], failures: 0, finished: 0 };
+    // Each worker sends every concurrency-th command of the schedule
+    let interval = if load.rate > 0 { NS_PER_SEC * load.concurrency as u64 / load.rate as u64 } else { 0 };
+    let mut due = load.start;
+    if load.rate > 0 {
+        due += NS_PER_SEC * w as u64 / load.rate as u64;
+    }
+
+    loop {
+        let now = precise_time_ns();
+        if now >= load.deadline {
+            break;
+        }
+        if load.rate > 0 {
+            if due >= load.deadline {
+                break;
+            }
+            if due > now {
+                sleep((due - now) / 1000000);
+            }
+        } else {
+            due = now;
+        }
+        let outcome = client.submit(value.clone());
+        if due >= load.measure_from {
+            match outcome {
+                Ok(_) => results.latencies.push(precise_time_ns() - due),
+                Err(_) => results.failures += 1,
+            }
+        }
+        match outcome {
+            Err(Disconnected) => break,
+            _ => {},
+        }
+        due += interval;
+    }
+    results.finished = precise_time_ns();
+    Some(results)
+}
+
+// Reports the throughput over the time from measure_from until the last
+// worker finished, which runs a little past the deadline while the last
+// commands commit
+fn report(mut results: Results, measure_from: u64) {
+    results.latencies.sort();
+    let committed = results.latencies.len();
+    let elapsed = if results.finished > measure_from { results.finished - measure_from } else { 0 };
+    let elapsed = max(elapsed, 1) as f64 / NS_PER_SEC as f64;
+    println!("{} commands committed in {:.2f} s, {:.1f} per second, {} failed",
+        committed, elapsed, committed as f64 / elapsed, results.failures);
+    println!("Commit latency: p50 {:.3f} ms, p99 {:.3f} ms, p999 {:.3f} ms, max {:.3f} ms",
+        percentile(results.latencies, 0.5), percentile(results.latencies, 0.99),
+        percentile(results.latencies, 0.999), percentile(results.latencies, 1.0));
+}
+
+// The latency below which the fraction q of the sorted latencies are, in
+// milliseconds
+fn percentile(sorted: &[u64], q: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (q * sorted.len() as f64).ceil() as uint;
+    sorted[max(rank, 1) - 1] as f64 / 1000000.0
+}
+
+// The CPU time the process has used so far, in nanoseconds
+fn cpu_time() -> u64 {
+    let mut usage = rusage{
+        ru_utime: timeval{ tv_sec: 0, tv_usec: 0 },
+        ru_stime: timeval{ tv_sec: 0, tv_usec: 0 },
+        ru_other: [0, ..14],
+    };
+    unsafe { getrusage(RUSAGE_SELF, &mut usage) };
+    let micros = |t: &timeval| t.tv_sec as u64 * 1000000 + t.tv_usec as u64;
+    (micros(&usage.ru_utime) + micros(&usage.ru_stime)) * 1000
+}
+
+// Sends the CPU time the process uses between two points in precise_time_ns,
+// along with how long that actually was
+fn measure_cpu(from: u64, until: u64) -> Port<(u64, u64)> {
+    let (port, chan) = Chan::new();
+    do spawn {
+        let now = precise_time_ns();
+        if from > now {
+            sleep((from - now) / 1000000);
+        }
+        let (cpu_start, start) = (cpu_time(), precise_time_ns());
+        let now = precise_time_ns();
+        if until > now {
+            sleep((until - now) / 1000000);
+        }
+        chan.send((cpu_time() - cpu_start, precise_time_ns() - start));
+    }
+    port
+}
+
+fn report_cpu((cpu, wall): (u64, u64)) {
+    println!("CPU time: {:.2f} s in {:.2f} s, {:.1f}% of one core, workers included",
+        cpu as f64 / NS_PER_SEC as f64, wall as f64 / NS_PER_SEC as f64,
+        100.0 * cpu as f64 / max(wall, 1) as f64);
+}
+
+fn fail_with(code: int, msg: ~str) {
+    stderr().write_line(format!("paxos-bench: {}", msg));
+    os::set_exit_status(code);
+}
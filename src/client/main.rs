@@ -0,0 +1,150 @@
+extern mod extra;
+extern mod paxos;
+
+use std::os;
+use std::path::Path;
+use std::io::{stdin, stdout, stderr, io_error};
+use std::io::fs::File;
+
+use extra::getopts::groups::{getopts, optopt, optflag, usage};
+
+use paxos::internal::client::Client;
+use paxos::internal::transport::Address;
+
+// Talks to a replica's client port:
+//
+//     paxos-client --replica ADDR submit [VALUE]   gets VALUE decided, or the
+//                                                  contents of --file or of
+//                                                  stdin without a VALUE
+//     paxos-client --replica ADDR get SLOT         prints what the instance
+//                                                  SLOT, written
+//                                                  "<replica>.<number>",
+//                                                  decided
+//     paxos-client --replica ADDR status           prints how the cluster
+//                                                  looks to the replica
+
+// Exit codes, as in sysexits.h, besides 1 for a request that failed
+static EXIT_FAILED: int = 1;
+static EXIT_USAGE: int = 64;
+static EXIT_NOINPUT: int = 66;
+static EXIT_UNAVAILABLE: int = 69;
+
+fn main() {
+    let args = os::args();
+    let opts = ~[
+        optopt("r", "replica", "the client address of the replica to talk to", "ADDR"),
+        optopt("f", "file", "submit the contents of the file", "PATH"),
+        optflag("h", "help", "print this help and exit"),
+    ];
+    let brief = format!("Usage: {} --replica ADDR (submit [VALUE] | get SLOT | status)", args[0]);
+    let matches = match getopts(args.tail(), opts) {
+        Ok(m) => m,
+        Err(f) => return fail_with(EXIT_USAGE, f.to_err_msg()),
+    };
+    if matches.opt_present("help") {
+        println(usage(brief, opts));
+        return;
+    }
+    let addr = match matches.opt_str("replica").and_then(|s| from_str::<Address>(s)) {
+        Some(addr) => addr,
+        None => return fail_with(EXIT_USAGE, ~"--replica must be given an address"),
+    };
+    if matches.free.is_empty() {
+        return fail_with(EXIT_USAGE, brief);
+    }
+
+    let mut client = match Client::connect(&addr) {
+        Some(client) => client,
+        None => return fail_with(EXIT_UNAVAILABLE, format!("can't connect to {}", addr.to_str())),
+    };
+
+    let command = matches.free[0].clone();
+    let operands = matches.free.slice_from(1);
+    match (command.as_slice(), operands.len()) {
+        ("submit", n) if n <= 1 => {
+            let value = match read_value(operands, matches.opt_str("file")) {
+                Ok(value) => value,
+                Err(err) => return fail_with(EXIT_NOINPUT, err),
+            };
+            match client.submit(value) {
+                Ok(((replica, number), position)) => {
+                    println!("committed in slot {}.{} at position {}", replica, number, position);
+                },
+                Err(err) => fail_with(EXIT_FAILED, format!("submit failed: {}", err.to_str())),
+            }
+        },
+        ("get", 1) => {
+            let iid = match parse_slot(operands[0]) {
+                Some(iid) => iid,
+                None => return fail_with(EXIT_USAGE, format!("{} is not a slot", operands[0])),
+            };
+            match client.get(iid) {
+                Ok(Some(commands)) => {
+                    let mut out = stdout();
+                    for command in commands.iter() {
+                        out.write(*command);
+                        out.write(['\n' as u8]);
+                    }
+                },
+                Ok(None) => fail_with(EXIT_FAILED, format!("slot {} isn't known to be decided", operands[0])),
+                Err(err) => fail_with(EXIT_FAILED, format!("get failed: {}", err.to_str())),
+            }
+        },
+        ("status", 0) => match client.status() {
+            Ok(status) => {
+                println!("cluster {}, as seen by replica {} with {} instances in flight",
+                    status.cluster_id, status.replicas[status.replica_id].stable_id, status.in_flight);
+                for replica in status.replicas.iter() {
+                    let address = replica.address.as_ref().map_default(~"unknown", |a| a.to_str());
+                    let heard = match replica.last_heard {
+                        Some(ms) => format!("heard from {} ms ago", ms),
+                        None => ~"never heard from",
+                    };
+                    println!("{:>3} {:<20} {:<25} {:<5} {}", replica.id, replica.stable_id, address,
+                        if replica.alive { "up" } else { "down" }, heard);
+                }
+            },
+            Err(err) => fail_with(EXIT_FAILED, format!("status failed: {}", err.to_str())),
+        },
+        _ => fail_with(EXIT_USAGE, brief),
+    }
+}
+
+// The value to submit: the operand if there is one, else the file if one was
+// given, else everything on stdin
+fn read_value(operands: &[~str], file: Option<~str>) -> Result<~[u8], ~str> {
+    if !operands.is_empty() {
+        return Ok(operands[0].as_bytes().to_owned());
+    }
+    let mut failure = None;
+    let value = io_error::cond.trap(|err| {
+        failure = Some(err.to_str());
+    }).inside(|| {
+        match file {
+            Some(ref path) => File::open(&Path::new(path.as_slice())).map(|mut f| f.read_to_end()),
+            None => Some(stdin().read_to_end()),
+        }
+    });
+    match (value, failure) {
+        (Some(value), None) => Ok(value),
+        (_, Some(err)) => Err(err),
+        (None, None) => Err(~"can't read the value"),
+    }
+}
+
+// Reads a slot written "<replica>.<number>"
+fn parse_slot(s: &str) -> Option<(uint, u64)> {
+    let parts: ~[&str] = s.split('.').collect();
+    if parts.len() != 2 {
+        return None;
+    }
+    match (from_str::<uint>(parts[0]), from_str::<u64>(parts[1])) {
+        (Some(replica), Some(number)) => Some((replica, number)),
+        _ => None,
+    }
+}
+
+fn fail_with(code: int, msg: ~str) {
+    stderr().write_line(format!("paxos-client: {}", msg));
+    os::set_exit_status(code);
+}
@@ -0,0 +1,398 @@
+extern mod extra;
+
+use std::os;
+use std::libc;
+use std::libc::{c_int, pid_t};
+use std::path::Path;
+use std::comm::Select;
+use std::io;
+use std::io::{stdin, stderr, io_error};
+use std::io::fs::{File, mkdir_recursive};
+use std::io::buffered::BufferedReader;
+use std::io::signal::{Listener, Interrupt};
+use std::io::process::{Process, ProcessConfig, ProcessExit, ExitStatus, ExitSignal, Ignored, InheritFd};
+
+use extra::json;
+use extra::getopts::groups::{getopts, optopt, optflag, usage};
+
+// Runs a cluster of paxos-server processes on loopback and takes commands on
+// stdin, one per line, to play with them:
+//
+//     start ID     launches the replica
+//     stop ID      lets the replica finish its instances in flight and exit
+//     kill ID      kills the replica with SIGKILL
+//     restart ID   stops the replica and launches it again
+//     pause ID     freezes the replica with SIGSTOP
+//     resume ID    lets a paused replica go on
+//     status       lists the replicas
+//     quit         stops every replica and exits
+//
+// ID is a replica's ID or "all".  Replica i gets the directory DIR/i, with
+// its config, its data directory and the log of its output.  Configs that
+// already exist are left as they are, so they can be edited between runs.
+//
+// Restarting a replica is safe because every config sets data_dir: the
+// replica records its incarnation there, so its new instance numbers never
+// collide with those of an earlier run, even within the same second, and
+// the stable IDs, so that an edited config can't shift them (see
+// next_incarnation and check_members in replica.rs).  Nothing else is kept
+// there; a restarted replica starts without any decided values.
+
+// Exit codes, as in sysexits.h
+static EXIT_USAGE: int = 64;
+static EXIT_CANTCREAT: int = 73;
+
+static DEFAULT_REPLICAS: uint = 3;
+static DEFAULT_PORT: uint = 4001;
+// How far above its peer port a replica serves clients
+static CLIENT_PORT_OFFSET: uint = 1000;
+
+// Not in std::libc
+#[cfg(target_os = "linux")] static SIGSTOP: c_int = 19;
+#[cfg(target_os = "linux")] static SIGCONT: c_int = 18;
+#[cfg(target_os = "macos")] #[cfg(target_os = "freebsd")] static SIGSTOP: c_int = 17;
+#[cfg(target_os = "macos")] #[cfg(target_os = "freebsd")] static SIGCONT: c_int = 19;
+
+// rw-r--r--
+static LOG_MODE: c_int = 420;
+
+struct Node {
+    id: uint,
+    dir: Path,
+    peer_address: ~str,
+    client_address: ~str,
+    // None while the replica isn't running
+    pid: Option<pid_t>,
+    paused: bool,
+}
+
+impl Node {
+    fn config_path(&self) -> Path { self.dir.join("config.json") }
+    fn log_path(&self) -> Path { self.dir.join("replica.log") }
+}
+
+// A replica's process ended: its ID, its pid and how it ended
+type Exit = (uint, pid_t, ProcessExit);
+
+struct Cluster {
+    server: Path,
+    nodes: ~[Node],
+    exits: Port<Exit>,
+    exits_chan: SharedChan<Exit>,
+}
+
+enum Event {
+    Line(Option<~str>),
+    Exited(Exit),
+    Interrupted,
+}
+
+fn main() {
+    let args = os::args();
+    let opts = ~[
+        optopt("n", "replicas", format!("how many replicas to run (default {})", DEFAULT_REPLICAS), "N"),
+        optopt("d", "dir", "where the replicas' directories go (default ./cluster)", "DIR"),
+        optopt("p", "port", format!("the port of replica 0, the others following (default {})", DEFAULT_PORT), "PORT"),
+        optopt("s", "server", "the paxos-server to run (default the one next to paxos-cluster)", "PATH"),
+        optflag("h", "help", "print this help and exit"),
+    ];
+    let matches = match getopts(args.tail(), opts) {
+        Ok(m) => m,
+        Err(f) => return fail_with(EXIT_USAGE, f.to_err_msg()),
+    };
+    if matches.opt_present("help") {
+        println(usage(format!("Usage: {} [--replicas N] [--dir DIR] [--port PORT] [--server PATH]", args[0]), opts));
+        return;
+    }
+    let replicas = match matches.opt_str("replicas").map_default(Some(DEFAULT_REPLICAS), |s| from_str(s)) {
+        Some(n) if n > 0 => n,
+        _ => return fail_with(EXIT_USAGE, ~"--replicas must be a positive number"),
+    };
+    let port = match matches.opt_str("port").map_default(Some(DEFAULT_PORT), |s| from_str(s)) {
+        Some(p) if p > 0 && p + CLIENT_PORT_OFFSET + replicas <= 65536 => p,
+        _ => return fail_with(EXIT_USAGE, ~"--port must leave room for every replica's ports"),
+    };
+    let dir = os::make_absolute(&Path::new(matches.opt_str("dir").unwrap_or(~"cluster")));
+    let server = match matches.opt_str("server") {
+        Some(path) => os::make_absolute(&Path::new(path)),
+        None => match os::self_exe_path() {
+            Some(bin) => bin.join("paxos-server"),
+            None => return fail_with(EXIT_USAGE, ~"can't tell where paxos-server is, use --server"),
+        },
+    };
+
+    let nodes: ~[Node] = range(0, replicas).map(|i| Node{
+        id: i,
+        dir: dir.join(i.to_str()),
+        peer_address: format!("127.0.0.1:{}", port + i),
+        client_address: format!("127.0.0.1:{}", port + CLIENT_PORT_OFFSET + i),
+        pid: None,
+        paused: false,
+    }).collect();
+    for node in nodes.iter() {
+        match write_config(node, nodes) {
+            Ok(()) => {},
+            Err(err) => return fail_with(EXIT_CANTCREAT, format!("{}: {}", node.dir.display(), err)),
+        }
+    }
+
+    let (exits, exits_chan) = SharedChan::new();
+    let mut cluster = Cluster{ server: server, nodes: nodes, exits: exits, exits_chan: exits_chan };
+
+    // Interrupts reach the replicas too, since they share the terminal; they
+    // drain and exit on their own, and this waits for them
+    let mut signals = Listener::new();
+    signals.register(Interrupt);
+    let mut lines = read_lines();
+
+    cluster.run("start", "all");
+    loop {
+        let event = {
+            let sel = Select::new();
+            let mut l = sel.add(&mut lines);
+            let mut e = sel.add(&mut cluster.exits);
+            let mut s = sel.add(&mut signals.port);
+            let ret = sel.wait();
+            if ret == l.id {
+                Line(l.recv_opt())
+            } else if ret == e.id {
+                Exited(e.recv())
+            } else {
+                assert!(ret == s.id);
+                s.recv();
+                Interrupted
+            }
+        };
+        match event {
+            Line(Some(line)) => {
+                let words: ~[&str] = line.words().collect();
+                match words.as_slice() {
+                    [] => {},
+                    ["status"] => cluster.status(),
+                    ["quit"] => break,
+                    [command, target] => cluster.run(command, target),
+                    _ => println!("Unknown command: {}", line.trim()),
+                }
+            },
+            Line(None) | Interrupted => break,
+            Exited(exit) => cluster.exited(exit),
+        }
+    }
+    cluster.run("stop", "all");
+}
+
+// Writes the replica's config, unless it's already there, and creates its
+// data directory
+fn write_config(node: &Node, nodes: &[Node]) -> Result<(), ~str> {
+    let data_dir = node.dir.join("data");
+    let peers: ~[~str] = nodes.iter().map(|n| json::String(n.peer_address.clone()).to_str()).collect();
+    let config = format!("\\{\n\t\"id\": {},\n\t\"cluster_id\": \"local\",\n\t\"peers\": [{}],\n\t\
+                          \"client_address\": {},\n\t\"data_dir\": {}\n\\}\n",
+        node.id, peers.connect(", "), json::String(node.client_address.clone()).to_str(),
+        json::String(data_dir.as_str().unwrap().to_owned()).to_str());
+
+    let mut failure = None;
+    io_error::cond.trap(|err| {
+        failure = Some(err.to_str());
+    }).inside(|| {
+        if !data_dir.is_dir() {
+            mkdir_recursive(&data_dir, io::UserDir);
+        }
+        let path = node.config_path();
+        if !path.exists() {
+            File::create(&path).map(|mut f| f.write(config.as_bytes()));
+        }
+    });
+    match failure {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+// Sends the lines read from stdin, until it's closed
+fn read_lines() -> Port<~str> {
+    let (port, chan) = Chan::new();
+    do spawn {
+        let mut input = BufferedReader::new(stdin());
+        io_error::cond.trap(|_| {}).inside(|| {
+            loop {
+                match input.read_line() {
+                    Some(line) => chan.send(line),
+                    None => break,
+                }
+            }
+        });
+    }
+    port
+}
+
+impl Cluster {
+    // Runs the command on the replica named by target, or on every replica
+    fn run(&mut self, command: &str, target: &str) {
+        let ids: ~[uint] = if target == "all" {
+            range(0, self.nodes.len()).collect()
+        } else {
+            match from_str::<uint>(target) {
+                Some(id) if id < self.nodes.len() => ~[id],
+                _ => return println!("No replica {}", target),
+            }
+        };
+        for &id in ids.iter() {
+            match command {
+                "start" => self.start(id),
+                "stop" => self.stop(id),
+                "kill" => self.kill(id),
+                "restart" => { self.stop(id); self.start(id) },
+                "pause" => self.pause(id),
+                "resume" => self.resume(id),
+                _ => return println!("Unknown command: {}", command),
+            }
+        }
+    }
+
+    fn start(&mut self, id: uint) {
+        if self.nodes[id].pid.is_some() {
+            return println!("Replica {} is already running", id);
+        }
+        let (pid_port, pid_chan) = Chan::new();
+        let exits = self.exits_chan.clone();
+        let server = self.server.clone();
+        let dir = self.nodes[id].dir.clone();
+        let config = self.nodes[id].config_path();
+        let log = self.nodes[id].log_path();
+
+        // The process is waited for by its own task, which reports when it
+        // ends
+        do spawn {
+            let fd = log.with_c_str(|path| unsafe {
+                libc::open(path, libc::O_WRONLY | libc::O_CREAT | libc::O_APPEND, LOG_MODE)
+            });
+            let args = ~[~"--config", config.as_str().unwrap().to_owned()];
+            let process = if fd < 0 {
+                None
+            } else {
+                io_error::cond.trap(|_| {}).inside(|| {
+                    Process::new(ProcessConfig{
+                        program: server.as_str().unwrap(),
+                        args: args.as_slice(),
+                        env: None,
+                        cwd: Some(&dir),
+                        io: &[Ignored, InheritFd(fd), InheritFd(fd)],
+                    })
+                })
+            };
+            if fd >= 0 {
+                unsafe { libc::close(fd) };
+            }
+            match process {
+                Some(mut process) => {
+                    let pid = process.id();
+                    pid_chan.send(Some(pid));
+                    let exit = process.wait();
+                    exits.send((id, pid, exit));
+                },
+                None => pid_chan.send(None),
+            }
+        }
+
+        let node = &mut self.nodes[id];
+        match pid_port.recv() {
+            Some(pid) => {
+                node.pid = Some(pid);
+                node.paused = false;
+                println!("Replica {} started with pid {}, logging to {}", id, pid, node.log_path().display());
+            },
+            None => println!("Replica {} can't be started; see {}", id, node.log_path().display()),
+        }
+    }
+
+    // Lets the replica drain, and waits for it to exit
+    fn stop(&mut self, id: uint) {
+        let pid = match self.nodes[id].pid {
+            Some(pid) => pid,
+            None => return,
+        };
+        if self.nodes[id].paused {
+            signal(pid, SIGCONT);
+        }
+        signal(pid, libc::SIGINT);
+        self.wait_for(id);
+    }
+
+    fn kill(&mut self, id: uint) {
+        for &pid in self.nodes[id].pid.iter() {
+            signal(pid, libc::SIGKILL);
+        }
+        self.wait_for(id);
+    }
+
+    fn pause(&mut self, id: uint) {
+        let node = &mut self.nodes[id];
+        match node.pid {
+            Some(pid) if !node.paused => {
+                signal(pid, SIGSTOP);
+                node.paused = true;
+                println!("Replica {} is paused", id);
+            },
+            Some(_) => println!("Replica {} is already paused", id),
+            None => println!("Replica {} isn't running", id),
+        }
+    }
+
+    fn resume(&mut self, id: uint) {
+        let node = &mut self.nodes[id];
+        match node.pid {
+            Some(pid) if node.paused => {
+                signal(pid, SIGCONT);
+                node.paused = false;
+                println!("Replica {} is resumed", id);
+            },
+            Some(_) => println!("Replica {} isn't paused", id),
+            None => println!("Replica {} isn't running", id),
+        }
+    }
+
+    // Blocks until the replica's process has ended, taking note of any other
+    // that ends in the meantime
+    fn wait_for(&mut self, id: uint) {
+        while self.nodes[id].pid.is_some() {
+            let exit = self.exits.recv();
+            self.exited(exit);
+        }
+    }
+
+    fn exited(&mut self, (id, pid, exit): Exit) {
+        let node = &mut self.nodes[id];
+        // A report about an earlier run of a restarted replica is stale
+        if node.pid != Some(pid) {
+            return;
+        }
+        node.pid = None;
+        node.paused = false;
+        match exit {
+            ExitStatus(code) => println!("Replica {} exited with status {}", id, code),
+            ExitSignal(signum) => println!("Replica {} was killed by signal {}", id, signum),
+        }
+    }
+
+    fn status(&self) {
+        for node in self.nodes.iter() {
+            let state = match node.pid {
+                Some(pid) if node.paused => format!("paused (pid {})", pid),
+                Some(pid) => format!("running (pid {})", pid),
+                None => ~"stopped",
+            };
+            println!("{:>3} {:<20} peers at {:<16} clients at {:<16} {}", node.id, state,
+                node.peer_address, node.client_address, node.dir.display());
+        }
+    }
+}
+
+fn signal(pid: pid_t, signum: c_int) {
+    unsafe { libc::kill(pid, signum) };
+}
+
+fn fail_with(code: int, msg: ~str) {
+    stderr().write_line(format!("paxos-cluster: {}", msg));
+    os::set_exit_status(code);
+}
@@ -0,0 +1,155 @@
+use std::io::mem::MemWriter;
+
+use openssl::crypto::hash::SHA256;
+use openssl::crypto::hmac::HMAC;
+
+// Shared-secret authentication of Paxos messages, for clusters that don't
+// want to pay for TLS.
+//
+// Every replica picks a random nonce for each connection and announces it
+// during the handshake.  Each message sent on the connection carries a counter that
+// starts at 1 and goes up by one with every message, and an HMAC-SHA256 tag
+// computed with the cluster key over the receiver's nonce, the counter and the
+// payload.  The receiver only accepts a tag computed with its own nonce and a
+// counter higher than any it has seen, so messages can neither be replayed on
+// the same connection nor on a different one.
+pub struct Authenticator {
+    priv key: ~[u8],
+    // The nonce the peer picked; the messages we send are bound to it
+    priv their_nonce: u64,
+    // The nonce we picked; the messages we receive must be bound to it
+    priv our_nonce: u64,
+    priv send_counter: u64,
+    priv recv_counter: u64,
+}
+
+pub static TAG_LEN: uint = 32;
+
+impl Authenticator {
+    pub fn new(key: ~[u8], our_nonce: u64, their_nonce: u64) -> Authenticator {
+        Authenticator{
+            key: key,
+            their_nonce: their_nonce,
+            our_nonce: our_nonce,
+            send_counter: 0,
+            recv_counter: 0,
+        }
+    }
+
+    // Returns the counter and tag to send along with the payload
+    pub fn seal(&mut self, payload: &[u8]) -> (u64, ~[u8]) {
+        self.send_counter += 1;
+        (self.send_counter, mac(self.key, self.their_nonce, self.send_counter, payload))
+    }
+
+    // Checks a received payload, returning an explanation if it's forged or
+    // replayed
+    pub fn open(&mut self, counter: u64, payload: &[u8], tag: &[u8]) -> Result<(), ~str> {
+        if counter <= self.recv_counter {
+            return Err(format!("replayed message (counter {}, expected more than {})",
+                counter, self.recv_counter));
+        }
+        let expected = mac(self.key, self.our_nonce, counter, payload);
+        if !constant_time_eq(expected, tag) {
+            return Err(~"invalid message authentication code");
+        }
+        self.recv_counter = counter;
+        Ok(())
+    }
+}
+
+// Gossip messages travel one per connection, without a handshake to agree
+// on nonces, so they're tagged with the cluster key alone.  The counter of a
+// Paxos message is never 0, so a gossip tag can't pass for one of theirs.
+// Gossip replayed this way only repeats news that's already been superseded
+// or taken in.
+pub fn gossip_tag(key: &[u8], payload: &[u8]) -> ~[u8] {
+    mac(key, 0, 0, payload)
+}
+
+pub fn check_gossip_tag(key: &[u8], payload: &[u8], tag: &[u8]) -> bool {
+    constant_time_eq(gossip_tag(key, payload), tag)
+}
+
+fn mac(key: &[u8], nonce: u64, counter: u64, payload: &[u8]) -> ~[u8] {
+    let mut header = MemWriter::new();
+    header.write_be_u64(nonce);
+    header.write_be_u64(counter);
+
+    let mut hmac = HMAC(SHA256, key.to_owned());
+    hmac.update(header.inner_ref().as_slice());
+    hmac.update(payload);
+    hmac.final()
+}
+
+// Compares two tags without leaking how long their common prefix is
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut diff = 0u8;
+    for (x, y) in a.iter().zip(b.iter()) {
+        diff |= *x ^ *y;
+    }
+    diff == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Authenticator, gossip_tag, check_gossip_tag};
+
+    static KEY: &'static [u8] = bytes!("cluster key");
+
+    // The two ends of a connection, from the sender's point of view and the
+    // receiver's
+    fn pair() -> (Authenticator, Authenticator) {
+        (Authenticator::new(KEY.to_owned(), 1, 2), Authenticator::new(KEY.to_owned(), 2, 1))
+    }
+
+    #[test]
+    fn test_sealed_messages_open() {
+        let (mut sender, mut receiver) = pair();
+        for payload in [bytes!("one"), bytes!("two")].iter() {
+            let (counter, tag) = sender.seal(*payload);
+            assert_eq!(receiver.open(counter, *payload, tag.as_slice()), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_replayed_or_lower_counters_are_refused() {
+        let (mut sender, mut receiver) = pair();
+        let (first, first_tag) = sender.seal(bytes!("one"));
+        let (second, second_tag) = sender.seal(bytes!("two"));
+        assert!(receiver.open(second, bytes!("two"), second_tag.as_slice()).is_ok());
+        assert!(receiver.open(second, bytes!("two"), second_tag.as_slice()).is_err());
+        assert!(receiver.open(first, bytes!("one"), first_tag.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_tampered_messages_are_refused() {
+        let (mut sender, mut receiver) = pair();
+        let (counter, mut tag) = sender.seal(bytes!("payload"));
+        assert!(receiver.open(counter, bytes!("Payload"), tag.as_slice()).is_err());
+        tag[0] ^= 1;
+        assert!(receiver.open(counter, bytes!("payload"), tag.as_slice()).is_err());
+        tag[0] ^= 1;
+        // A failed check doesn't use up the counter
+        assert!(receiver.open(counter, bytes!("payload"), tag.as_slice()).is_ok());
+    }
+
+    #[test]
+    fn test_messages_are_bound_to_the_connection() {
+        let (mut sender, _) = pair();
+        let mut other = Authenticator::new(KEY.to_owned(), 3, 1);
+        let (counter, tag) = sender.seal(bytes!("payload"));
+        assert!(other.open(counter, bytes!("payload"), tag.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_gossip_tags() {
+        let tag = gossip_tag(KEY, bytes!("gossip"));
+        assert!(check_gossip_tag(KEY, bytes!("gossip"), tag.as_slice()));
+        assert!(!check_gossip_tag(KEY, bytes!("gossiP"), tag.as_slice()));
+        assert!(!check_gossip_tag(bytes!("other key"), bytes!("gossip"), tag.as_slice()));
+    }
+}
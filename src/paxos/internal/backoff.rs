@@ -0,0 +1,36 @@
+use std::cmp;
+use std::rand::{Rng, task_rng};
+
+// Exponential backoff with jitter.  Used by the ConnectionHandler when a peer
+// can't be reached, so that we don't hammer it with connection attempts and
+// so that replicas that lost each other at the same time don't retry in
+// lockstep.  All durations are in milliseconds.
+pub struct Backoff {
+    initial: u64,
+    max: u64,
+    current: u64,
+}
+
+impl Backoff {
+    pub fn new(initial: u64, max: u64) -> Backoff {
+        Backoff{
+            initial: initial,
+            max: max,
+            current: initial,
+        }
+    }
+
+    // Returns how long to wait before the next attempt, and doubles the
+    // delay for the attempt after that (up to the maximum).
+    pub fn next_delay(&mut self) -> u64 {
+        let base = self.current;
+        self.current = cmp::min(self.current * 2, self.max);
+        // Up to 50% of random jitter on top of the base delay
+        base + task_rng().gen_range(0, base / 2 + 1)
+    }
+
+    // Called once a connection has been established.
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
@@ -0,0 +1,279 @@
+use std::util;
+use std::comm::Select;
+use std::io::io_error;
+use std::io::mem::{MemWriter, BufReader};
+use std::io::timer::Timer;
+
+use super::instance::InstanceID;
+use super::replica::{Proposer, SubmitError, Abandoned};
+
+// The value an instance decides is a list of commands, encoded as their
+// number followed by every command as its length and its bytes.  All
+// integers are big-endian u32s.  A value submitted on its own is a list of
+// one command.
+//
+// The batcher groups commands that arrive close together into a single
+// value, so that one instance decides all of them.  A batch is proposed once
+// it holds limits.max_commands commands or limits.max_bytes bytes, or once
+// limits.window milliseconds have passed since its first command arrived,
+// whichever comes first.  A command that would take the batch past
+// limits.max_bytes goes to the next batch instead, so only a batch of a
+// single command can be larger.  Every command is answered, once its instance is
+// done, with the instance and the command's position in it.
+
+// Bounds on the batches the batcher proposes
+#[deriving(Clone, Eq)]
+pub struct BatchLimits {
+    max_commands: uint,
+    max_bytes: uint,
+    // How long (in milliseconds) the first command of a batch waits for
+    // others to join it
+    window: u64,
+}
+
+pub static DEFAULT_BATCH_LIMITS: BatchLimits = BatchLimits{
+    max_commands: 128,
+    max_bytes: 1024 * 1024,
+    window: 5,
+};
+
+// What a command submitted through the batcher comes to
+pub type CommandResult = Result<(InstanceID, uint), SubmitError>;
+
+// A command waiting to be proposed, and where to report what became of it
+pub struct Command {
+    value: ~[u8],
+    done: Chan<CommandResult>,
+}
+
+// The commands an instance decided, in the order they were submitted
+#[deriving(Clone, ToStr)]
+pub struct Decision {
+    instance_id: InstanceID,
+    commands: ~[~[u8]],
+}
+
+pub struct Batcher {
+    limits: BatchLimits,
+    commands: Port<Command>,
+    // New limits, when the config is reloaded
+    settings: Port<BatchLimits>,
+    proposer: Proposer,
+}
+
+impl Batcher {
+    pub fn run(mut self) {
+        let mut timer = Timer::new().unwrap();
+        let mut pending: ~[Command] = ~[];
+        let mut bytes = 0;
+        // Fires when the pending batch has waited long enough
+        let mut window = None;
+
+        loop {
+            let mut expired = false;
+            match self.next_command(&mut window) {
+                Some(Some(cmd)) => {
+                    if !pending.is_empty() && bytes + cmd.value.len() > self.limits.max_bytes {
+                        propose_batch(&self.proposer, util::replace(&mut pending, ~[]));
+                        bytes = 0;
+                        window = None;
+                    }
+                    if pending.is_empty() && self.limits.window > 0 {
+                        window = Some(timer.oneshot(self.limits.window));
+                    }
+                    bytes += cmd.value.len();
+                    pending.push(cmd);
+                },
+                Some(None) => expired = true,
+                // The replica is gone; propose what's left and stop
+                None => {
+                    if !pending.is_empty() {
+                        propose_batch(&self.proposer, pending);
+                    }
+                    return;
+                },
+            }
+
+            if expired || self.limits.window == 0 ||
+                    pending.len() >= self.limits.max_commands ||
+                    bytes >= self.limits.max_bytes {
+                if !pending.is_empty() {
+                    propose_batch(&self.proposer, pending);
+                }
+                pending = ~[];
+                bytes = 0;
+                window = None;
+            }
+        }
+    }
+
+    // Blocks until a command arrives or the window closes.  Returns
+    // Some(None) for the latter, and None once the replica is gone.  New
+    // limits are taken in the meantime, and apply from the next command on.
+    fn next_command(&mut self, window: &mut Option<Port<()>>) -> Option<Option<Command>> {
+        loop {
+            let sel = Select::new();
+            let mut commands = sel.add(&mut self.commands);
+            let mut settings = sel.add(&mut self.settings);
+            let mut expiry = window.as_mut().map(|p| sel.add(p));
+
+            let ret = sel.wait();
+            for h in expiry.mut_iter() {
+                if ret == h.id {
+                    h.recv_opt();
+                    return Some(None);
+                }
+            }
+            if ret == settings.id {
+                match settings.recv_opt() {
+                    Some(limits) => {
+                        debug!("Replica {}'s batcher has new limits", self.proposer.id);
+                        self.limits = limits;
+                        continue;
+                    },
+                    None => return None,
+                }
+            }
+            assert!(ret == commands.id);
+            return commands.recv_opt().map(|cmd| Some(cmd));
+        }
+    }
+}
+
+// Gets the command decided, through the batcher if proposal batching is on.
+// See Replica::submit_command.
+pub fn submit_command(proposer: &Proposer, batcher: &Option<SharedChan<Command>>,
+                      value: ~[u8]) -> Port<CommandResult> {
+    let (port, chan) = Chan::new();
+    let cmd = Command{ value: value, done: chan };
+    match *batcher {
+        Some(ref batcher) => batcher.send(cmd),
+        None => propose_batch(proposer, ~[cmd]),
+    }
+    port
+}
+
+// Starts an instance for the batch, and answers every command once the
+// instance is done
+pub fn propose_batch(proposer: &Proposer, batch: ~[Command]) {
+    let value = {
+        let values: ~[&[u8]] = batch.iter().map(|c| c.value.as_slice()).collect();
+        encode_commands(values)
+    };
+    debug!("Replica {} is proposing a batch of {} commands", proposer.id, batch.len());
+    match proposer.propose(value) {
+        Ok((iid, done)) => {
+            do spawn {
+                let committed = done.recv_opt().unwrap_or(false);
+                for (i, cmd) in batch.move_iter().enumerate() {
+                    cmd.done.try_send(if committed { Ok((iid, i)) } else { Err(Abandoned) });
+                }
+            }
+        },
+        Err(err) => {
+            for cmd in batch.move_iter() {
+                cmd.done.try_send(Err(err.clone()));
+            }
+        },
+    }
+}
+
+// The length of a value of so many commands of so many bytes in all
+pub fn encoded_len(commands: uint, bytes: uint) -> uint {
+    4 + 4 * commands + bytes
+}
+
+pub fn encode_commands(commands: &[&[u8]]) -> ~[u8] {
+    let mut w = MemWriter::new();
+    w.write_be_u32(commands.len() as u32);
+    for cmd in commands.iter() {
+        w.write_be_u32(cmd.len() as u32);
+        w.write(*cmd);
+    }
+    w.inner()
+}
+
+// Returns None if the value isn't a well-formed list of commands
+pub fn decode_commands(value: &[u8]) -> Option<~[~[u8]]> {
+    let mut r = BufReader::new(value);
+    let mut truncated = false;
+    let commands = io_error::cond.trap(|_| {
+        truncated = true;
+    }).inside(|| {
+        let count = r.read_be_u32() as uint;
+        // Every command takes at least four bytes, so a larger count can't
+        // be right
+        if count > value.len() / 4 {
+            return None;
+        }
+        let mut commands = ~[];
+        for _ in range(0, count) {
+            let len = r.read_be_u32() as uint;
+            if len > value.len() {
+                return None;
+            }
+            commands.push(r.read_bytes(len));
+        }
+        Some(commands)
+    });
+
+    // Trailing bytes are as suspicious as missing ones
+    match commands {
+        Some(commands) if !truncated && r.eof() => Some(commands),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::config::{ReplicaConfig, InvalidField};
+    use super::super::transport::Address;
+    use super::super::wire::{MAX_PAYLOAD_LEN, MAX_REQUEST_OVERHEAD};
+    use super::{BatchLimits, encode_commands, decode_commands, encoded_len};
+
+    fn config(limits: BatchLimits) -> ReplicaConfig {
+        let peers: ~[Address] = ["127.0.0.1:9000", "127.0.0.1:9001", "127.0.0.1:9002"].iter()
+            .map(|s| from_str::<Address>(*s).unwrap()).collect();
+        let mut config = ReplicaConfig::new(0, peers);
+        config.proposal_batching = Some(limits);
+        config
+    }
+
+    #[test]
+    fn test_commands_round_trip() {
+        let commands: &[&[u8]] = [&[1u8, 2, 3], &[], &[4u8]];
+        let value = encode_commands(commands);
+        assert_eq!(value.len(), encoded_len(3, 4));
+        assert_eq!(decode_commands(value.as_slice()), Some(~[~[1u8, 2, 3], ~[], ~[4u8]]));
+        assert_eq!(decode_commands(encode_commands([]).as_slice()), Some(~[]));
+    }
+
+    #[test]
+    fn test_malformed_values_are_refused() {
+        let value = encode_commands([&[1u8, 2, 3]]);
+        assert_eq!(decode_commands(value.slice_to(value.len() - 1)), None);
+        let mut trailing = value.clone();
+        trailing.push(0);
+        assert_eq!(decode_commands(trailing.as_slice()), None);
+        assert_eq!(decode_commands([0u8, 0, 0, 9]), None);
+    }
+
+    #[test]
+    fn test_batches_that_cant_fit_in_a_message_are_refused() {
+        let max_commands = 128;
+        let room = MAX_PAYLOAD_LEN - MAX_REQUEST_OVERHEAD;
+        let max_bytes = room - encoded_len(max_commands, 0);
+        let limits = BatchLimits{ max_commands: max_commands, max_bytes: max_bytes, window: 5 };
+        assert!(config(limits.clone()).validate().is_ok());
+        assert!(encoded_len(max_commands, max_bytes) + MAX_REQUEST_OVERHEAD <= MAX_PAYLOAD_LEN);
+
+        match config(BatchLimits{ max_bytes: max_bytes + 1, ..limits.clone() }).validate() {
+            Err(InvalidField(field, _)) => assert_eq!(field, ~"proposal_batching.max_bytes"),
+            _ => fail!("expected max_bytes to be refused"),
+        }
+        match config(BatchLimits{ max_commands: room, ..limits }).validate() {
+            Err(InvalidField(field, _)) => assert_eq!(field, ~"proposal_batching.max_commands"),
+            _ => fail!("expected max_commands to be refused"),
+        }
+    }
+}
@@ -0,0 +1,275 @@
+use std::hashmap::HashMap;
+
+use extra::arc::MutexArc;
+use extra::ringbuf::RingBuf;
+use extra::container::Deque;
+use extra::time::precise_time_ns;
+
+use super::replica::{ReplicaID, Proposer};
+use super::instance::InstanceID;
+use super::batcher::{Command, Decision, submit_command};
+use super::health::HealthMonitor;
+use super::membership::AddressBook;
+use super::transport::{Address, Transport, TransportStream, TransportListener, SocketTransport};
+use super::wire::FrameStream;
+use super::message::{ClientM, ClientMessage, SubmitRequest, GetRequest, StatusRequest, Submitted,
+    Decided, Undecided, StatusReply, Failure};
+
+// Clients talk to a replica through its client port, separate from the port
+// replicas talk to each other on.  A client sends one request at a time on
+// its connection and waits for the response (see ClientMessage):
+//
+//   - SubmitRequest gets a command decided, like Replica::submit_command,
+//     and is answered once the instance is done.
+//   - GetRequest asks what an instance decided.  Replicas don't keep a log
+//     yet, so they only remember the last MAX_REMEMBERED instances decided
+//     since they started.
+//   - StatusRequest asks how the cluster looks to the replica.
+//
+// The client port is neither encrypted nor authenticated.
+
+// How many decided instances a replica remembers for GetRequests
+static MAX_REMEMBERED: uint = 10000;
+
+// How the cluster looks to the replica that answered a StatusRequest
+#[deriving(Clone, ToStr)]
+pub struct ClusterStatus {
+    replica_id: ReplicaID,
+    cluster_id: ~str,
+    // How many of its instances the replica has in flight
+    in_flight: uint,
+    // Every replica of the cluster, indexed by ReplicaID
+    replicas: ~[ReplicaStatus],
+}
+
+#[deriving(Clone, ToStr)]
+pub struct ReplicaStatus {
+    id: ReplicaID,
+    stable_id: ~str,
+    // None if the replica's address isn't known yet
+    address: Option<Address>,
+    // Whether the answering replica's failure detector thinks the replica is
+    // up.  Always true for the answering replica itself.
+    alive: bool,
+    // Milliseconds since the answering replica last heard from it
+    last_heard: Option<u64>,
+}
+
+// The instances a replica saw decided most recently
+struct DecisionCache {
+    decided: HashMap<InstanceID, ~[~[u8]]>,
+    // Oldest first, to know what to forget
+    order: RingBuf<InstanceID>,
+}
+
+// Answers the clients that connect to a replica
+pub struct ClientServer {
+    id: ReplicaID,
+    cluster_id: ~str,
+    stable_ids: ~[~str],
+    peer_addrs: AddressBook,
+    proposer: Proposer,
+    // Where commands go to be batched, if proposal batching is on
+    batcher: Option<SharedChan<Command>>,
+    health: ~[HealthMonitor],
+    decisions: Port<Decision>,
+    listener: ~TransportListener,
+}
+
+impl ClientServer {
+    pub fn run(self) {
+        let ClientServer{
+            id, cluster_id, stable_ids, peer_addrs, proposer, batcher, health, decisions, mut listener
+        } = self;
+        debug!("Replica {} is serving clients at {}", id, listener.address().to_str());
+        let cache = MutexArc::new(DecisionCache{ decided: HashMap::new(), order: RingBuf::new() });
+
+        let recorder_cache = cache.clone();
+        do spawn {
+            loop {
+                let decision = match decisions.recv_opt() {
+                    Some(decision) => decision,
+                    None => return,
+                };
+                recorder_cache.access(|c| {
+                    if c.order.len() >= MAX_REMEMBERED {
+                        for old in c.order.pop_front().iter() {
+                            c.decided.remove(old);
+                        }
+                    }
+                    c.order.push_back(decision.instance_id);
+                    c.decided.insert(decision.instance_id, decision.commands.clone());
+                });
+            }
+        }
+
+        loop {
+            let stream = match listener.accept() {
+                Some(stream) => stream,
+                None => {
+                    error!("Replica {} can't accept clients anymore", id);
+                    return;
+                },
+            };
+            let session = ClientSession{
+                id: id,
+                cluster_id: cluster_id.clone(),
+                stable_ids: stable_ids.clone(),
+                peer_addrs: peer_addrs.clone(),
+                proposer: proposer.clone(),
+                batcher: batcher.clone(),
+                health: health.clone(),
+                cache: cache.clone(),
+            };
+            do spawn {
+                session.serve(FrameStream::new(stream));
+            }
+        }
+    }
+}
+
+// What a task serving one client connection needs
+struct ClientSession {
+    id: ReplicaID,
+    cluster_id: ~str,
+    stable_ids: ~[~str],
+    peer_addrs: AddressBook,
+    proposer: Proposer,
+    batcher: Option<SharedChan<Command>>,
+    health: ~[HealthMonitor],
+    cache: MutexArc<DecisionCache>,
+}
+
+impl ClientSession {
+    // Answers requests until the client disconnects
+    fn serve(&self, mut stream: FrameStream<~TransportStream>) {
+        loop {
+            let reply = match stream.recv() {
+                Ok(ClientM(request)) => self.handle(request),
+                Ok(msg) => Failure(format!("{} is not a client request", msg.to_str())),
+                Err(err) => {
+                    if err.is_fatal() {
+                        return;
+                    }
+                    Failure(err.to_str())
+                },
+            };
+            if !stream.try_send(&ClientM(reply)) {
+                return;
+            }
+        }
+    }
+
+    fn handle(&self, request: ClientMessage) -> ClientMessage {
+        match request {
+            SubmitRequest(value) => {
+                match submit_command(&self.proposer, &self.batcher, value).recv_opt() {
+                    Some(Ok((iid, position))) => Submitted(iid, position),
+                    Some(Err(err)) => Failure(err.to_str()),
+                    None => Failure(~"the replica is shutting down"),
+                }
+            },
+            GetRequest(iid) => {
+                self.cache.access(|c| match c.decided.find(&iid) {
+                    Some(commands) => Decided(iid, commands.clone()),
+                    None => Undecided(iid),
+                })
+            },
+            StatusRequest => StatusReply(self.status()),
+            other => Failure(format!("{} is not a client request", other.to_str())),
+        }
+    }
+
+    fn status(&self) -> ClusterStatus {
+        let now = precise_time_ns();
+        let mut replicas: ~[ReplicaStatus] = self.stable_ids.iter().enumerate().map(|(id, stable_id)| {
+            ReplicaStatus{
+                id: id,
+                stable_id: stable_id.clone(),
+                address: self.peer_addrs.get(id),
+                alive: id == self.id,
+                last_heard: if id == self.id { Some(0) } else { None },
+            }
+        }).collect();
+        for monitor in self.health.iter() {
+            let health = monitor.snapshot(now);
+            replicas[health.peer].alive = health.alive;
+            replicas[health.peer].last_heard = health.last_heard;
+        }
+        ClusterStatus{
+            replica_id: self.id,
+            cluster_id: self.cluster_id.clone(),
+            in_flight: self.proposer.window.in_flight(),
+            replicas: replicas,
+        }
+    }
+}
+
+#[deriving(ToStr)]
+pub enum ClientError {
+    // The connection to the replica failed
+    Disconnected,
+    // The replica couldn't carry out the request, and said why
+    Refused(~str),
+    // The replica answered with something other than what was asked for
+    UnexpectedReply,
+}
+
+// A connection to a replica's client port
+pub struct Client {
+    priv stream: FrameStream<~TransportStream>,
+}
+
+impl Client {
+    // Connects to the replica over TCP or a Unix domain socket, depending on
+    // the address
+    pub fn connect(addr: &Address) -> Option<Client> {
+        Client::with_transport(&SocketTransport, addr)
+    }
+
+    pub fn with_transport<T: Transport>(transport: &T, addr: &Address) -> Option<Client> {
+        transport.connect(addr).map(|stream| Client{ stream: FrameStream::new(stream) })
+    }
+
+    // Gets the value decided, and returns the instance that decided it along
+    // with its position among the commands of the instance
+    pub fn submit(&mut self, value: ~[u8]) -> Result<(InstanceID, uint), ClientError> {
+        match self.call(SubmitRequest(value)) {
+            Ok(Submitted(iid, position)) => Ok((iid, position)),
+            Ok(_) => Err(UnexpectedReply),
+            Err(err) => Err(err),
+        }
+    }
+
+    // The commands the instance decided, or None if the replica doesn't know
+    pub fn get(&mut self, iid: InstanceID) -> Result<Option<~[~[u8]]>, ClientError> {
+        match self.call(GetRequest(iid)) {
+            Ok(Decided(_, commands)) => Ok(Some(commands)),
+            Ok(Undecided(_)) => Ok(None),
+            Ok(_) => Err(UnexpectedReply),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn status(&mut self) -> Result<ClusterStatus, ClientError> {
+        match self.call(StatusRequest) {
+            Ok(StatusReply(status)) => Ok(status),
+            Ok(_) => Err(UnexpectedReply),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn call(&mut self, request: ClientMessage) -> Result<ClientMessage, ClientError> {
+        if !self.stream.try_send(&ClientM(request)) {
+            return Err(Disconnected);
+        }
+        match self.stream.recv() {
+            Ok(ClientM(Failure(reason))) => Err(Refused(reason)),
+            Ok(ClientM(reply)) => Ok(reply),
+            Ok(_) => Err(UnexpectedReply),
+            Err(err) => {
+                if err.is_fatal() { Err(Disconnected) } else { Err(UnexpectedReply) }
+            },
+        }
+    }
+}
@@ -1,147 +1,347 @@
-use std::vec;
-use std::io::net::tcp::TcpStream;
-use std::io::buffered::BufferedStream;
-use std::hashmap::HashMap;
-use std::comm::Chan;
+use std::comm::{Chan, Select};
+use std::io::timer::Timer;
 use std::sync::arc::UnsafeArc;
-use std::comm::{Empty, Data, Disconnected};
+use std::unstable::atomics::{AtomicBool, SeqCst};
 
-use extra::comm::DuplexStream;
+use extra::time::precise_time_ns;
 
-use object_stream::ObjectStream;
-
-use super::message::{Message, PaxosMessageContent, Propose, NetworkM, PaxosM, PaxosMessage, NetworkMessage};
+use super::message::{Message, PaxosM, PaxosMessage, NetworkM, NetworkMessage,
+    Heartbeat, HeartbeatAck};
 use super::replica::ReplicaID;
-use super::instance::{Instance, InstanceID};
-
-// Each communicator is responsible for communicating with a specific peer.
-// When an instance wants to send a message to a peer, it sends the message
-// to the corresponding communicator, which will takes care of all serialization.
-// Effectively, an instance views communicators as peers and exchanges messages
-// with them.
-// A communicator receives TcpStream from a channel.  Under the current scheme,
-// a replica is responsible for initiating TCP connections with replicas with
-// a lower ID.  Thus, if a communicator detects that it's talking to a lower-ID
-// replica and it doesn't have an active connection, it sends a signal through
-// stream_chan, thus telling the ConnectionHandler to initiate a new connection.
-// Otherwise, if the communicator is responsible for talking with higher-ID replica,
-// it simply waits for a connection because the replica will initiate it.
+use super::dispatcher::{DispatcherMessage, Received, Undeliverable};
+use super::connection_handler::Connection;
+use super::wire::Unauthenticated;
+use super::queue::{OutboundQueue, Outbound, QueueLimits, Batching};
+use super::metrics::BatchMetrics;
+use super::health::{HealthMonitor, DetectorConfig};
+
+// A communicator carries all traffic between this replica and one peer.
+// Instances hand it their messages for that peer over a shared channel, and
+// it forwards everything the peer sends to the Dispatcher, which knows which
+// instance it is for.  To an instance, the communicator is the peer.
+//
+// The communicator runs a single loop that blocks until something happens
+// (see Event): a new connection, a message from an instance or from the peer,
+// a timer, or new settings.  After each event it writes out what's queued.
+//
+// Messages from instances go through a bounded OutboundQueue, and are written
+// out in batches.  While the queue is full, the communicator stops taking
+// messages from instances and raises its congested flag, which tells the
+// replica not to start new instances.
+//
+// Connections come from the ConnectionHandler, with the handshake done.  When
+// one breaks, the communicator drops it, asks for a new one and keeps
+// queueing in the meantime; what couldn't be written goes out again over the
+// next connection (see flush).  Once the ConnectionHandler is gone, the
+// communicator serves the connection it has, and stops when that one breaks.
 pub struct Communicator {
     my_id: ReplicaID,
     peer_id: ReplicaID,
-    tcp_stream: DuplexStream<bool, ObjectStream<BufferedStream<TcpStream>>>,
-    message_stream_port: Port<(InstanceID, DuplexStream<PaxosMessageContent, PaxosMessageContent>)>,
-    message_stream_chans: ~[SharedChan<(InstanceID, DuplexStream<PaxosMessageContent, PaxosMessageContent>)>],
+    // New connections to the peer.  None once the ConnectionHandler is gone,
+    // after which the current connection is the last one.
+    connections: Option<Port<Connection>>,
+    // Asks the ConnectionHandler for a new connection.  Only the replica with
+    // the higher ID initiates connections, so on the other side of the link
+    // nobody listens and requests are ignored.
+    connection_requests: Chan<bool>,
+    // Messages from instances to the peer
+    outbound: Port<PaxosMessage>,
+    // Where messages from the peer go
+    dispatcher: SharedChan<DispatcherMessage>,
+    queue_limits: QueueLimits,
+    // Queued messages go out in batches of up to max_batch, with one flush per
+    // batch.  A batch that isn't full waits up to linger ms for more.
+    batching: Batching,
+    // Records the size of every batch written out
+    metrics: BatchMetrics,
+    // Every heartbeat_interval ms we send the peer a Heartbeat, which it
+    // answers with a HeartbeatAck.  Peers that only speak version 1 get none.
+    detector: DetectorConfig,
+    // Fed by everything heard from the peer; the acks give it round-trip
+    // times
+    health: HealthMonitor,
+    // New settings, when the config is reloaded.  None once the replica is
+    // gone.
+    settings: Option<Port<CommunicatorSettings>>,
+    // Set while the outbound queue is full
+    congested: UnsafeArc<AtomicBool>,
+}
+
+// The settings of a communicator that can change while it runs
+#[deriving(Clone)]
+pub struct CommunicatorSettings {
+    queue_limits: QueueLimits,
+    batching: Batching,
+    detector: DetectorConfig,
+}
+
+// How many connections we try to send a message over before dropping it
+static MAX_SEND_ATTEMPTS: uint = 5;
+
+// The things a communicator waits for
+enum Event {
+    // None if the ConnectionHandler is gone
+    NewConnection(Option<Connection>),
+    Outgoing(PaxosMessage),
+    // None if the connection broke
+    Incoming(Option<Message>),
+    // A partial batch has waited long enough
+    LingerExpired,
+    HeartbeatDue,
+    // None if the replica is gone
+    Reconfigure(Option<CommunicatorSettings>),
+}
+
+// The first protocol version with heartbeats
+static HEARTBEAT_VERSION: u8 = 2;
+
+// The connection the communicator is currently using: the sending half, and
+// the port the reader task forwards messages to
+struct Link {
+    sender: UnsafeArc<Connection>,
+    incoming: Port<Message>,
 }
 
 impl Communicator {
-    pub fn run(self) {
+    pub fn run(mut self) {
         debug!("Replica {} is running a communicator for {}",
             self.my_id, self.peer_id);
 
-        let mut stream_map = HashMap::new();
+        let mut queue = OutboundQueue::new(self.queue_limits.clone());
+        let mut link = None;
+        let mut timer = Timer::new().unwrap();
+        // Fires when the partial batch in the queue should go out
+        let mut linger = None;
+        let mut heartbeat_timer = Timer::new().unwrap();
+        let mut heartbeats = heartbeat_timer.periodic(self.detector.heartbeat_interval);
+        self.connection_requests.try_send(true);
 
         loop {
-            // Get a TCP stream
-            let mut tcp = match self.tcp_stream.try_recv() {
-                Data(tcp) => tcp,
-                Empty => {
-                    self.tcp_stream.send(true);
-                    self.tcp_stream.recv()
+            let mut lingered = false;
+            // A message to the communicator on the other side
+            let mut reply = None;
+            match self.next_event(&mut link, &mut linger, &mut heartbeats, !queue.is_full()) {
+                NewConnection(Some(tcp)) => {
+                    debug!("Replica {}'s communicator for {} received a connection",
+                        self.my_id, self.peer_id);
+                    link = Some(self.start_reader(tcp));
                 },
-                Disconnected => fail!()
-            };
-
-            debug!("Replica {}'s communicator for {} received a TCP stream",
-                self.my_id, self.peer_id);
-
-            // Send my ID
-            // TODO: according to the current design, it's only necessary
-            // for replicas with higher IDs to identify themselves to
-            // replicas with lower IDs.  However, it our communication is
-            // such that extra noise bytes are overlooked, then this should
-            // not be a problem.
-            let init_msg = NetworkM(NetworkMessage{
-                replica_id: self.my_id 
-            });
-            tcp.send(init_msg);
-            
-            let (tcp_send_arc, tcp_recv_arc) = UnsafeArc::new2(tcp);
-            let (msg_port, msg_chan) = Chan::new();
-
-            do spawn {
-                unsafe {
-                    let tcp_recv_ptr = tcp_recv_arc.get();
-                    loop {
-                        match (*tcp_recv_ptr).recv::<Message>() {
-                            Ok(msg) => msg_chan.send(msg),
-                            Err(_) => {},
-                        };
+                NewConnection(None) => {
+                    warn!("Replica {}'s communicator for {} won't get new connections anymore",
+                        self.my_id, self.peer_id);
+                    self.connections = None;
+                },
+                Outgoing(msg) => queue.push(msg),
+                Incoming(Some(msg)) => {
+                    self.health.heartbeat(precise_time_ns());
+                    match msg {
+                        PaxosM(msg) => self.dispatcher.send(Received(self.peer_id, msg)),
+                        NetworkM(Heartbeat(clock)) => reply = Some(HeartbeatAck(clock)),
+                        NetworkM(HeartbeatAck(clock)) => {
+                            let now = precise_time_ns();
+                            if now > clock {
+                                self.health.round_trip(now - clock);
+                            }
+                        },
+                        _ => (),
                     }
-                }
+                },
+                Incoming(None) => {
+                    debug!("Replica {}'s connection to {} broke, reconnecting",
+                        self.my_id, self.peer_id);
+                    link = None;
+                    self.connection_requests.try_send(true);
+                },
+                LingerExpired => {
+                    linger = None;
+                    lingered = true;
+                },
+                HeartbeatDue => reply = Some(Heartbeat(precise_time_ns())),
+                Reconfigure(Some(settings)) => {
+                    debug!("Replica {}'s communicator for {} has new settings",
+                        self.my_id, self.peer_id);
+                    queue.set_limits(settings.queue_limits.clone());
+                    if settings.detector.heartbeat_interval != self.detector.heartbeat_interval {
+                        heartbeats = heartbeat_timer.periodic(settings.detector.heartbeat_interval);
+                    }
+                    self.health.set_config(settings.detector.clone());
+                    self.queue_limits = settings.queue_limits;
+                    self.batching = settings.batching;
+                    self.detector = settings.detector;
+                },
+                Reconfigure(None) => self.settings = None,
             }
 
-            let tcp_send_ptr = tcp_send_arc.get();
-            loop {
-                // Accept new instances
-                match self.message_stream_port.try_recv() {
-                    Data((iid, stream)) => {
-                        stream_map.insert(iid, stream);
-                    },
-                    _ => (),
-                }
+            // Write out whatever is queued, unless we're waiting for a
+            // partial batch to fill up.  This also retransmits what we
+            // failed to send over the last connection.
+            let ready = lingered || self.batching.linger == 0 ||
+                queue.len() >= self.batching.max_batch;
+            if !ready && linger.is_none() && !queue.is_empty() {
+                linger = Some(timer.oneshot(self.batching.linger));
+            }
+            let mut broken = match link {
+                Some(ref l) if ready => unsafe { !self.flush(&mut *l.sender.get(), &mut queue) },
+                _ => false,
+            };
+            for msg in reply.move_iter() {
+                broken = broken || !self.send_network(&link, msg);
+            }
+            if ready {
+                linger = None;
+            }
+            if broken {
+                debug!("Replica {}'s connection to {} broke, reconnecting",
+                    self.my_id, self.peer_id);
+                link = None;
+                self.connection_requests.try_send(true);
+            }
+            self.set_congested(queue.is_full());
+            if link.is_none() && self.connections.is_none() {
+                warn!("Replica {}'s communicator for {} is stopping, with no connection left",
+                    self.my_id, self.peer_id);
+                return;
+            }
+        }
+    }
 
-                // Send new messages
-                for (iid, stream) in stream_map.iter() {
-                    match stream.try_recv() {
-                        Data(content) => {
-                            match content {
-                                _ => {
-                                    let msg = PaxosMessage{
-                                        content: content,
-                                        instance_id: *iid,
-                                    };
-                                    unsafe {
-                                        (*tcp_send_ptr).send(PaxosM(msg));
-                                    }
-                                }
-                            };
+    // Blocks until something happens.  Messages from instances are only
+    // taken if there's room for them in the queue.
+    fn next_event(&mut self, link: &mut Option<Link>, linger: &mut Option<Port<()>>,
+                  heartbeats: &mut Port<()>, take_outgoing: bool) -> Event {
+        let sel = Select::new();
+        let mut connections = self.connections.as_mut().map(|p| sel.add(p));
+        let mut outbound = if take_outgoing {
+            Some(sel.add(&mut self.outbound))
+        } else {
+            None
+        };
+        let mut incoming = link.as_mut().map(|l| sel.add(&mut l.incoming));
+        let mut lingering = linger.as_mut().map(|p| sel.add(p));
+        let mut heartbeat = sel.add(heartbeats);
+        let mut settings = self.settings.as_mut().map(|p| sel.add(p));
+
+        let ret = sel.wait();
+        for h in connections.mut_iter() {
+            if ret == h.id {
+                return NewConnection(h.recv_opt());
+            }
+        }
+        for h in outbound.mut_iter() {
+            if ret == h.id {
+                return Outgoing(h.recv());
+            }
+        }
+        for h in lingering.mut_iter() {
+            if ret == h.id {
+                h.recv_opt();
+                return LingerExpired;
+            }
+        }
+        if ret == heartbeat.id {
+            heartbeat.recv_opt();
+            return HeartbeatDue;
+        }
+        for h in settings.mut_iter() {
+            if ret == h.id {
+                return Reconfigure(h.recv_opt());
+            }
+        }
+        match incoming {
+            Some(ref mut h) if ret == h.id => Incoming(h.recv_opt()),
+            _ => unreachable!(),
+        }
+    }
+
+    // Spawns a task that reads from the connection.  It exits as soon as the
+    // connection fails, which disconnects the incoming port and tells us to
+    // tear the connection down.  Frames that merely fail to decode are dropped.
+    fn start_reader(&self, tcp: Connection) -> Link {
+        let (tcp_send_arc, tcp_recv_arc) = UnsafeArc::new2(tcp);
+        let (msg_port, msg_chan) = Chan::new();
+        let my_id = self.my_id;
+        let peer_id = self.peer_id;
+        do spawn {
+            unsafe {
+                let tcp_recv_ptr = tcp_recv_arc.get();
+                loop {
+                    match (*tcp_recv_ptr).recv() {
+                        Ok(msg) => msg_chan.send(msg),
+                        Err(Unauthenticated(reason)) => {
+                            error!("Replica {} dropped a message from replica {} that failed authentication: {}",
+                                my_id, peer_id, reason);
+                        },
+                        Err(err) => {
+                            if err.is_fatal() {
+                                break;
+                            }
+                            warn!("Replica {} dropped a frame from replica {}: {}",
+                                my_id, peer_id, err.to_str());
                         },
-                        _ => (),
                     };
                 }
+            }
+        }
+        Link{
+            sender: tcp_send_arc,
+            incoming: msg_port,
+        }
+    }
+
+    // Writes out everything in the queue, a batch at a time.  Returns false
+    // if the connection broke, in which case the batch that failed is put
+    // back to go out over the next connection.  A write that fails may still
+    // have reached the peer, so a message can arrive twice; that's harmless,
+    // as acceptors answer a repeated message the way they answered it the
+    // first time and proposers count each acceptor's reply once.  A message
+    // that failed MAX_SEND_ATTEMPTS times is dropped, and reported to the
+    // Dispatcher so that its instance knows.
+    fn flush(&self, tcp: &mut Connection, queue: &mut OutboundQueue) -> bool {
+        while !queue.is_empty() {
+            let mut batch = ~[];
+            while batch.len() < self.batching.max_batch {
+                match queue.pop() {
+                    Some(entry) => batch.push(entry),
+                    None => break,
+                }
+            }
+            let msgs: ~[Message] = batch.iter().map(|e| PaxosM(e.msg.clone())).collect();
+            if !tcp.try_send_all(msgs) {
+                // Put the batch back in its original order
+                for entry in batch.move_rev_iter() {
+                    if entry.attempts + 1 >= MAX_SEND_ATTEMPTS {
+                        error!("Replica {} gave up sending {} to replica {}",
+                            self.my_id, entry.msg.to_str(), self.peer_id);
+                        self.dispatcher.try_send(Undeliverable(self.peer_id, entry.msg));
+                    } else {
+                        queue.push_front(Outbound{
+                            msg: entry.msg,
+                            attempts: entry.attempts + 1,
+                        });
+                    }
+                }
+                return false;
+            }
+            self.metrics.record_batch(batch.len());
+        }
+        true
+    }
 
-                // Receive new messages
-                match msg_port.try_recv() {
-                    Data(PaxosM(msg)) => {
-                        let stream = stream_map.find(&msg.instance_id);
-                        if stream.is_none() {
-                            match msg.content {
-                                // Only Propose is legal
-                                Propose(seq) => {
-                                    let mut msg_streams = vec::with_capacity(self.message_stream_chans.len());
-                                    let (_, rid) = seq;
-                                    for (idx, chan) in self.message_stream_chans.iter().enumerate() {
-                                        let (from, to) = DuplexStream::new();
-                                        if (idx == rid) {
-                                            to.send(msg.content.clone());
-                                            chan.send((msg.instance_id.clone(), to));
-                                        }
-                                        msg_streams.push(from);
-                                    }
-                                    let instance = Instance::new_as_acceptor(self.my_id, msg.instance_id);
-                                    let msg_streams = msg_streams;
-                                    do spawn { instance.run(msg_streams); }
-                                },
-                                _ => {}, // overlook the wrong message
-                            }
-                        } else {
-                            stream.unwrap().try_send(msg.content);
-                        }
-                    },
-                    _ => (),
-                };
-            };
+    // Sends a message of our own to the peer, if we're connected and the peer
+    // understands it.  Returns false if the connection broke.
+    fn send_network(&self, link: &Option<Link>, msg: NetworkMessage) -> bool {
+        match *link {
+            Some(ref l) => unsafe {
+                let tcp = &mut *l.sender.get();
+                tcp.version() < HEARTBEAT_VERSION || tcp.try_send(&NetworkM(msg))
+            },
+            None => true,
         }
     }
-}
\ No newline at end of file
+
+    fn set_congested(&self, congested: bool) {
+        unsafe {
+            (*self.congested.get()).store(congested, SeqCst);
+        }
+    }
+}
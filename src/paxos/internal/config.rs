@@ -0,0 +1,922 @@
+use std::char;
+use std::cmp::{Ordering, Less, Greater};
+use std::hashmap::HashMap;
+use std::os;
+use std::str;
+use std::uint;
+use std::vec;
+use std::io::io_error;
+use std::io::fs::File;
+
+use extra::json;
+use extra::json::{Json, Object, List, Number, String, Null};
+use extra::treemap::TreeMap;
+use extra::hex::FromHex;
+
+use super::replica::ReplicaID;
+use super::tls::TlsConfig;
+use super::transport::{Address, UnixAddress};
+use super::queue::{QueueLimits, DEFAULT_QUEUE_LIMITS, Batching, DEFAULT_BATCHING};
+use super::batcher::{BatchLimits, DEFAULT_BATCH_LIMITS, encoded_len};
+use super::wire::{MAX_PAYLOAD_LEN, MAX_REQUEST_OVERHEAD};
+use super::window::DEFAULT_MAX_IN_FLIGHT;
+use super::health::{DetectorConfig, DEFAULT_DETECTOR_CONFIG};
+use super::gossip::{GossipConfig, DEFAULT_PROBE_INTERVAL, DEFAULT_INDIRECT_PROBES,
+    DEFAULT_SUSPECT_PERIODS};
+use super::toml;
+use super::log_level;
+
+// The shortest cluster key we accept, in bytes
+static MIN_KEY_LEN: uint = 16;
+
+// The longest stable ID we accept, in bytes
+static MAX_STABLE_ID_LEN: uint = 255;
+
+// Environment variables starting with this override fields of the config
+static ENV_PREFIX: &'static str = "PAXOS_";
+
+// Everything a replica needs to know to start.  Usually read from a JSON or
+// TOML file with load; see the README for the fields.
+#[deriving(Clone)]
+pub struct ReplicaConfig {
+    id: ReplicaID,
+    // Replicas refuse to talk to replicas from other clusters
+    cluster_id: ~str,
+    // The address of every replica, indexed by ID.  With gossip on, the
+    // addresses of other replicas may be None, to be discovered.
+    peers: ~[Option<Address>],
+    // The ID every replica is declared with in the config, indexed like
+    // peers.  ReplicaIDs are positions in this list, which is sorted, so they
+    // don't depend on the order of the config file.
+    stable_ids: ~[~str],
+    // Where to listen, if not at this replica's address in peers, e.g.
+    // 0.0.0.0:4000 behind a container's port mapping
+    bind: Option<Address>,
+    // Where to listen for clients, if anywhere; see client.rs
+    client_address: Option<Address>,
+    // Optional mutual TLS between replicas
+    tls: Option<TlsConfig>,
+    // Optional key for authenticating Paxos messages
+    cluster_key: Option<~[u8]>,
+    // Limits on the messages buffered for each peer
+    send_queue: QueueLimits,
+    // How messages to each peer are coalesced into writes
+    batching: Batching,
+    // Optional grouping of submitted commands into shared instances
+    proposal_batching: Option<BatchLimits>,
+    // How many instances we propose at a time
+    max_in_flight: uint,
+    // How often to send heartbeats, and when to suspect a peer is down
+    failure_detector: DetectorConfig,
+    // Optional gossip, to discover peers and follow their addresses
+    gossip: Option<GossipConfig>,
+    // Where the replica records its last incarnation, so that its instance
+    // numbers keep going up across restarts even if the clock goes back, and
+    // the stable IDs it last started with (see check_members in replica.rs)
+    data_dir: Option<Path>,
+    // How much the replica logs (see log_level.rs); RUST_LOG decides if unset
+    log_level: Option<u32>,
+}
+
+pub enum ConfigError {
+    // The config can't be read or parsed
+    Unreadable(~str),
+    // (#field)
+    MissingField(~str),
+    // (#field, #what's wrong with it)
+    InvalidField(~str, ~str),
+    // (#address) The replica couldn't listen where the config says
+    ListenFailed(~str),
+}
+
+impl ToStr for ConfigError {
+    fn to_str(&self) -> ~str {
+        match *self {
+            Unreadable(ref reason) => format!("unreadable config: {}", *reason),
+            MissingField(ref field) => format!("missing field `{}`", *field),
+            InvalidField(ref field, ref reason) => format!("invalid field `{}`: {}", *field, *reason),
+            ListenFailed(ref addr) => format!("can't listen at {}", *addr),
+        }
+    }
+}
+
+// What came of reloading the config of a running replica
+#[deriving(Clone, ToStr)]
+pub struct ReloadReport {
+    // The fields whose new values took effect
+    applied: ~[~str],
+    // (#field, #why not) The fields that changed but were left as they were
+    refused: ~[(~str, ~str)],
+}
+
+// Returns early with the error, if there is one
+macro_rules! check(($e:expr) => {
+    match $e {
+        Ok(v) => v,
+        Err(err) => return Err(err)
+    }
+})
+
+impl ReplicaConfig {
+    // A config with every optional setting at its default.  The stable ID of
+    // every replica is its position in peers.
+    pub fn new(id: ReplicaID, peers: ~[Address]) -> ReplicaConfig {
+        ReplicaConfig{
+            id: id,
+            cluster_id: ~"default",
+            stable_ids: range(0, peers.len()).map(|i| i.to_str()).collect(),
+            peers: peers.move_iter().map(|p| Some(p)).collect(),
+            bind: None,
+            client_address: None,
+            tls: None,
+            cluster_key: None,
+            send_queue: DEFAULT_QUEUE_LIMITS,
+            batching: DEFAULT_BATCHING,
+            proposal_batching: None,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            failure_detector: DEFAULT_DETECTOR_CONFIG,
+            gossip: None,
+            data_dir: None,
+            log_level: None,
+        }
+    }
+
+    // Reads a config file, TOML if its name ends in .toml and JSON
+    // otherwise, then applies the PAXOS_* environment variables on top.
+    // Settings in the environment take precedence over the file, which takes
+    // precedence over the defaults.
+    pub fn load(path: &Path) -> Result<ReplicaConfig, ConfigError> {
+        let mut failure = None;
+        let contents = io_error::cond.trap(|err| {
+            failure = Some(err.desc.to_owned());
+        }).inside(|| {
+            File::open(path).map(|mut f| f.read_to_end())
+        });
+        let contents = match (contents, failure) {
+            (Some(c), None) => c,
+            (_, err) => return Err(Unreadable(format!("can't read {}: {}", path.display(),
+                err.unwrap_or(~"no such file")))),
+        };
+        let text = match str::from_utf8_owned_opt(contents) {
+            Some(text) => text,
+            None => return Err(Unreadable(format!("{} is not UTF-8", path.display()))),
+        };
+        let mut content = if path.extension_str() == Some("toml") {
+            match toml::parse(text) {
+                Ok(c) => c,
+                Err(err) => return Err(Unreadable(err)),
+            }
+        } else {
+            match json::from_str(text) {
+                Ok(c) => c,
+                Err(err) => return Err(Unreadable(err.to_str())),
+            }
+        };
+        check!(apply_overrides(&mut content, os::env()));
+        ReplicaConfig::from_value(content)
+    }
+
+    // Reads and validates a JSON config
+    pub fn from_json<R: Reader>(reader: &mut R) -> Result<ReplicaConfig, ConfigError> {
+        match json::from_reader(reader as &mut Reader) {
+            Ok(c) => ReplicaConfig::from_value(c),
+            Err(err) => Err(Unreadable(err.to_str())),
+        }
+    }
+
+    // Reads and validates a TOML config
+    pub fn from_toml<R: Reader>(reader: &mut R) -> Result<ReplicaConfig, ConfigError> {
+        let text = match str::from_utf8_owned_opt(reader.read_to_end()) {
+            Some(text) => text,
+            None => return Err(Unreadable(~"the config is not UTF-8")),
+        };
+        match toml::parse(text) {
+            Ok(c) => ReplicaConfig::from_value(c),
+            Err(err) => Err(Unreadable(err)),
+        }
+    }
+
+    // Validates a config that's already been parsed
+    pub fn from_value(content: Json) -> Result<ReplicaConfig, ConfigError> {
+        let mut fields = check!(Fields::from(~"", content));
+
+        let stable_id = check!(fields.require("id", |f, v| f.stable_id(v)));
+        let (stable_ids, peers) = check!(fields.require("peers", |f, v| {
+            match v {
+                // A map from stable IDs to addresses
+                json::Object(obj) => {
+                    let mut entries = ~[];
+                    for (id, item) in obj.iter() {
+                        let field = format!("{}.{}", f.name, *id);
+                        entries.push((id.clone(), check!(peer_address(field, item.clone()))));
+                    }
+                    entries.sort_by(|a, b| {
+                        let (ref a, _) = *a;
+                        let (ref b, _) = *b;
+                        compare_stable_ids(*a, *b)
+                    });
+                    Ok(vec::unzip(entries.move_iter()))
+                },
+                // A list, where the stable ID of every replica is its position
+                v => {
+                    let items = check!(f.list(v));
+                    let mut peers = ~[];
+                    for (i, item) in items.move_iter().enumerate() {
+                        let field = format!("{}[{}]", f.name, i);
+                        peers.push(check!(peer_address(field, item)));
+                    }
+                    Ok((range(0, peers.len()).map(|i| i.to_str()).collect(), peers))
+                },
+            }
+        }));
+        let id = match stable_ids.iter().position(|s| *s == stable_id) {
+            Some(i) => i,
+            None => return Err(InvalidField(~"id",
+                format!("{} is not one of the replicas in `peers`", stable_id))),
+        };
+        let mut config = ReplicaConfig::new(id, ~[]);
+        config.peers = peers;
+        config.stable_ids = stable_ids;
+
+        for s in check!(fields.optional("cluster_id", |f, v| f.string(v))).move_iter() {
+            config.cluster_id = s;
+        }
+        config.bind = check!(fields.optional("bind", |f, v| f.address(v)));
+        config.client_address = check!(fields.optional("client_address", |f, v| f.address(v)));
+        config.tls = check!(fields.optional("tls", |f, v| {
+            let mut tls = check!(f.object(v));
+            let certificate = check!(tls.require("certificate", |f, v| f.path(v)));
+            let private_key = check!(tls.require("private_key", |f, v| f.path(v)));
+            let ca = check!(tls.require("ca", |f, v| f.path(v)));
+            check!(tls.finish());
+            Ok(TlsConfig{
+                certificate: certificate,
+                private_key: private_key,
+                ca: ca,
+            })
+        }));
+        config.cluster_key = check!(fields.optional("cluster_key", |f, v| {
+            let hex = check!(f.string(v));
+            match hex.from_hex() {
+                Ok(key) => Ok(key),
+                Err(_) => Err(f.invalid("expected a hex string")),
+            }
+        }));
+        for limits in check!(fields.optional("send_queue", |f, v| {
+            let mut obj = check!(f.object(v));
+            let mut limits = DEFAULT_QUEUE_LIMITS;
+            for n in check!(obj.optional("max_messages", |f, v| f.uint(v))).move_iter() {
+                limits.max_messages = n;
+            }
+            for n in check!(obj.optional("max_bytes", |f, v| f.uint(v))).move_iter() {
+                limits.max_bytes = n;
+            }
+            check!(obj.finish());
+            Ok(limits)
+        })).move_iter() {
+            config.send_queue = limits;
+        }
+        for batching in check!(fields.optional("batching", |f, v| {
+            let mut obj = check!(f.object(v));
+            let mut batching = DEFAULT_BATCHING;
+            for n in check!(obj.optional("max_batch", |f, v| f.uint(v))).move_iter() {
+                batching.max_batch = n;
+            }
+            for n in check!(obj.optional("linger_ms", |f, v| f.uint(v))).move_iter() {
+                batching.linger = n as u64;
+            }
+            check!(obj.finish());
+            Ok(batching)
+        })).move_iter() {
+            config.batching = batching;
+        }
+        config.proposal_batching = check!(fields.optional("proposal_batching", |f, v| {
+            let mut obj = check!(f.object(v));
+            let mut limits = DEFAULT_BATCH_LIMITS;
+            for n in check!(obj.optional("max_commands", |f, v| f.uint(v))).move_iter() {
+                limits.max_commands = n;
+            }
+            for n in check!(obj.optional("max_bytes", |f, v| f.uint(v))).move_iter() {
+                limits.max_bytes = n;
+            }
+            for n in check!(obj.optional("window_ms", |f, v| f.uint(v))).move_iter() {
+                limits.window = n as u64;
+            }
+            check!(obj.finish());
+            Ok(limits)
+        }));
+        for n in check!(fields.optional("max_in_flight", |f, v| f.uint(v))).move_iter() {
+            config.max_in_flight = n;
+        }
+        for detector in check!(fields.optional("failure_detector", |f, v| {
+            let mut obj = check!(f.object(v));
+            let mut detector = DEFAULT_DETECTOR_CONFIG;
+            for n in check!(obj.optional("heartbeat_ms", |f, v| f.uint(v))).move_iter() {
+                detector.heartbeat_interval = n as u64;
+            }
+            for n in check!(obj.optional("phi_threshold", |f, v| f.number(v))).move_iter() {
+                detector.phi_threshold = n;
+            }
+            check!(obj.finish());
+            Ok(detector)
+        })).move_iter() {
+            config.failure_detector = detector;
+        }
+        config.gossip = check!(fields.optional("gossip", |f, v| {
+            let mut obj = check!(f.object(v));
+            let address = check!(obj.require("address", |f, v| f.address(v)));
+            let mut gossip = GossipConfig{
+                address: address,
+                bind: check!(obj.optional("bind", |f, v| f.address(v))),
+                seeds: ~[],
+                probe_interval: DEFAULT_PROBE_INTERVAL,
+                indirect_probes: DEFAULT_INDIRECT_PROBES,
+                suspect_periods: DEFAULT_SUSPECT_PERIODS,
+            };
+            for seeds in check!(obj.optional("seeds", |f, v| {
+                let items = check!(f.list(v));
+                let mut seeds = ~[];
+                for (i, item) in items.move_iter().enumerate() {
+                    let field = format!("{}[{}]", f.name, i);
+                    match item {
+                        String(s) => seeds.push(check!(parse_address(field, s))),
+                        _ => return Err(InvalidField(field, ~"expected an address")),
+                    }
+                }
+                Ok(seeds)
+            })).move_iter() {
+                gossip.seeds = seeds;
+            }
+            for n in check!(obj.optional("probe_interval_ms", |f, v| f.uint(v))).move_iter() {
+                gossip.probe_interval = n as u64;
+            }
+            for n in check!(obj.optional("indirect_probes", |f, v| f.uint(v))).move_iter() {
+                gossip.indirect_probes = n;
+            }
+            for n in check!(obj.optional("suspect_periods", |f, v| f.uint(v))).move_iter() {
+                gossip.suspect_periods = n;
+            }
+            check!(obj.finish());
+            Ok(gossip)
+        }));
+        config.data_dir = check!(fields.optional("data_dir", |f, v| f.path(v)));
+        config.log_level = check!(fields.optional("log_level", |f, v| {
+            let name = check!(f.string(v));
+            match log_level::parse(name.as_slice()) {
+                Some(level) => Ok(level),
+                None => Err(f.invalid("expected error, warn, info or debug")),
+            }
+        }));
+        check!(fields.finish());
+
+        check!(config.validate());
+        Ok(config)
+    }
+
+    // The top-level fields whose values differ between the configs
+    pub fn changed_fields(&self, other: &ReplicaConfig) -> ~[&'static str] {
+        let mut changed = ~[];
+        if self.id != other.id { changed.push("id"); }
+        if self.cluster_id != other.cluster_id { changed.push("cluster_id"); }
+        if self.peers != other.peers || self.stable_ids != other.stable_ids { changed.push("peers"); }
+        if self.bind != other.bind { changed.push("bind"); }
+        if self.client_address != other.client_address { changed.push("client_address"); }
+        if self.tls != other.tls { changed.push("tls"); }
+        if self.cluster_key != other.cluster_key { changed.push("cluster_key"); }
+        if self.send_queue != other.send_queue { changed.push("send_queue"); }
+        if self.batching != other.batching { changed.push("batching"); }
+        if self.proposal_batching != other.proposal_batching { changed.push("proposal_batching"); }
+        if self.max_in_flight != other.max_in_flight { changed.push("max_in_flight"); }
+        if self.failure_detector != other.failure_detector { changed.push("failure_detector"); }
+        if self.gossip != other.gossip { changed.push("gossip"); }
+        if self.data_dir != other.data_dir { changed.push("data_dir"); }
+        if self.log_level != other.log_level { changed.push("log_level"); }
+        changed
+    }
+
+    // Checks that the settings make sense together.  Returns the first
+    // problem found.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        // Instances need a majority of the other replicas
+        if self.peers.len() < 2 {
+            return Err(InvalidField(~"peers",
+                ~"a cluster needs at least 2 replicas for an instance to reach a quorum"));
+        }
+        if self.id >= self.peers.len() {
+            return Err(InvalidField(~"id",
+                format!("{} is out of range for a cluster of {} replicas", self.id, self.peers.len())));
+        }
+        if self.cluster_id.is_empty() || self.cluster_id.len() > 0xffff {
+            return Err(InvalidField(~"cluster_id", ~"must be between 1 and 65535 bytes long"));
+        }
+
+        if self.stable_ids.len() != self.peers.len() {
+            return Err(InvalidField(~"peers", ~"every replica needs exactly one stable ID"));
+        }
+        let mut ids_seen = HashMap::new();
+        for stable_id in self.stable_ids.iter() {
+            let valid = stable_id.chars().all(|c| {
+                char::is_alphanumeric(c) || c == '-' || c == '_' || c == '.'
+            });
+            if stable_id.is_empty() || stable_id.len() > MAX_STABLE_ID_LEN || !valid {
+                return Err(InvalidField(format!("peers[{}]", *stable_id),
+                    format!("stable IDs must be 1 to {} letters, digits, `-`, `_` or `.`", MAX_STABLE_ID_LEN)));
+            }
+            if !ids_seen.insert(stable_id.clone(), ()) {
+                return Err(InvalidField(format!("peers[{}]", *stable_id), ~"is declared twice"));
+            }
+        }
+
+        let mut seen: HashMap<Address, ~str> = HashMap::new();
+        for (i, peer) in self.peers.iter().enumerate() {
+            let field = format!("peers[{}]", self.stable_ids[i]);
+            match *peer {
+                Some(ref addr) => {
+                    match seen.find(addr) {
+                        Some(other) => return Err(InvalidField(field,
+                            format!("{} is also the address of `{}`", addr.to_str(), *other))),
+                        None => (),
+                    }
+                    seen.insert(addr.clone(), field);
+                },
+                None if i == self.id => {
+                    return Err(InvalidField(field, ~"the address of this replica is required"));
+                },
+                None if self.gossip.is_none() => {
+                    return Err(InvalidField(field, ~"addresses may only be left out with gossip on"));
+                },
+                None => (),
+            }
+        }
+
+        // peers[id] is where others dial us, so it must reach the same kind
+        // of socket that we listen on
+        let advertised = self.peers[self.id].clone().unwrap();
+        let bind = self.bind.clone().unwrap_or(advertised.clone());
+        for addr in self.bind.iter() {
+            if is_unix(addr) != is_unix(&advertised) {
+                return Err(InvalidField(~"bind",
+                    format!("{} can't be reached at {}", addr.to_str(), advertised.to_str())));
+            }
+        }
+
+        for addr in self.client_address.iter() {
+            if *addr == bind {
+                return Err(InvalidField(~"client_address", ~"must differ from where Paxos listens"));
+            }
+            // The client port has no TLS or authentication, so it mustn't be a
+            // way around them
+            if (self.tls.is_some() || self.cluster_key.is_some()) && !addr.is_local() {
+                return Err(InvalidField(~"client_address",
+                    ~"must be a loopback address or a Unix socket when tls or cluster_key is set, \
+                      since clients are neither encrypted nor authenticated"));
+            }
+        }
+
+        for tls in self.tls.iter() {
+            check!(check_file("tls.certificate", &tls.certificate));
+            check!(check_file("tls.private_key", &tls.private_key));
+            check!(check_file("tls.ca", &tls.ca));
+        }
+        for key in self.cluster_key.iter() {
+            if key.len() < MIN_KEY_LEN {
+                return Err(InvalidField(~"cluster_key",
+                    format!("must be at least {} bytes long", MIN_KEY_LEN)));
+            }
+        }
+
+        check!(positive("send_queue.max_messages", self.send_queue.max_messages));
+        check!(positive("send_queue.max_bytes", self.send_queue.max_bytes));
+        check!(positive("batching.max_batch", self.batching.max_batch));
+        for limits in self.proposal_batching.iter() {
+            check!(positive("proposal_batching.max_commands", limits.max_commands));
+            check!(positive("proposal_batching.max_bytes", limits.max_bytes));
+            // A full batch has to fit in a single Request
+            let room = MAX_PAYLOAD_LEN - MAX_REQUEST_OVERHEAD - encoded_len(0, 0);
+            if limits.max_commands > room / 4 {
+                return Err(InvalidField(~"proposal_batching.max_commands",
+                    format!("must be at most {}", room / 4)));
+            }
+            let max_bytes = room - (encoded_len(limits.max_commands, 0) - encoded_len(0, 0));
+            if limits.max_bytes > max_bytes {
+                return Err(InvalidField(~"proposal_batching.max_bytes",
+                    format!("must be at most {} with max_commands {}", max_bytes, limits.max_commands)));
+            }
+        }
+        check!(positive("max_in_flight", self.max_in_flight));
+
+        check!(positive("failure_detector.heartbeat_ms", self.failure_detector.heartbeat_interval as uint));
+        if !(self.failure_detector.phi_threshold > 0.0) {
+            return Err(InvalidField(~"failure_detector.phi_threshold", ~"must be positive"));
+        }
+        // A batch that lingers longer than a heartbeat period would hold up
+        // heartbeat replies behind it
+        if self.batching.linger >= self.failure_detector.heartbeat_interval {
+            return Err(InvalidField(~"batching.linger_ms",
+                ~"must be shorter than failure_detector.heartbeat_ms"));
+        }
+
+        for gossip in self.gossip.iter() {
+            match seen.find(&gossip.address) {
+                Some(other) => return Err(InvalidField(~"gossip.address",
+                    format!("{} is also the address of `{}`", gossip.address.to_str(), *other))),
+                None => (),
+            }
+            for addr in gossip.bind.iter() {
+                if is_unix(addr) != is_unix(&gossip.address) {
+                    return Err(InvalidField(~"gossip.bind",
+                        format!("{} can't be reached at {}", addr.to_str(), gossip.address.to_str())));
+                }
+            }
+            if gossip.bind_address() == bind {
+                return Err(InvalidField(~"gossip.bind", ~"must differ from where Paxos listens"));
+            }
+            check!(positive("gossip.probe_interval_ms", gossip.probe_interval as uint));
+            check!(positive("gossip.suspect_periods", gossip.suspect_periods));
+            let everyone_known = self.peers.iter().all(|p| p.is_some());
+            if gossip.seeds.is_empty() && !everyone_known {
+                return Err(InvalidField(~"gossip.seeds",
+                    ~"at least one seed is needed to discover the replicas left out of `peers`"));
+            }
+        }
+
+        for dir in self.data_dir.iter() {
+            if !dir.is_dir() {
+                return Err(InvalidField(~"data_dir",
+                    format!("{} is not a directory", dir.display())));
+            }
+        }
+        Ok(())
+    }
+}
+
+// The address of a replica in peers, or None if gossip is to find it
+fn peer_address(field: ~str, item: Json) -> Result<Option<Address>, ConfigError> {
+    match item {
+        // TOML has no null, so an empty string will do as well
+        Null => Ok(None),
+        String(s) => if s.is_empty() {
+            Ok(None)
+        } else {
+            parse_address(field, s).map(|addr| Some(addr))
+        },
+        _ => Err(InvalidField(field, ~"expected an address or null")),
+    }
+}
+
+// The order of stable IDs, which decides every replica's ReplicaID: numeric
+// IDs first, by value, then the others, alphabetically
+fn compare_stable_ids(a: &str, b: &str) -> Ordering {
+    match (from_str::<u64>(a), from_str::<u64>(b)) {
+        (Some(x), Some(y)) => x.cmp(&y),
+        (Some(_), None) => Less,
+        (None, Some(_)) => Greater,
+        (None, None) => a.cmp(&b),
+    }
+}
+
+// How the value of a PAXOS_* variable is read, depending on the field it sets
+enum EnvValue {
+    // Taken as it is, even if it looks like a number (e.g. a hex key)
+    EnvString,
+    EnvNumber,
+    // Fields holding lists or tables are given as JSON
+    EnvJson,
+}
+
+// The fields that PAXOS_* variables can set
+static ENV_FIELDS: &'static [(&'static str, EnvValue)] = &[
+    ("id", EnvString),
+    ("cluster_id", EnvString),
+    ("peers", EnvJson),
+    ("bind", EnvString),
+    ("client_address", EnvString),
+    ("tls.certificate", EnvString),
+    ("tls.private_key", EnvString),
+    ("tls.ca", EnvString),
+    ("cluster_key", EnvString),
+    ("send_queue.max_messages", EnvNumber),
+    ("send_queue.max_bytes", EnvNumber),
+    ("batching.max_batch", EnvNumber),
+    ("batching.linger_ms", EnvNumber),
+    ("proposal_batching.max_commands", EnvNumber),
+    ("proposal_batching.max_bytes", EnvNumber),
+    ("proposal_batching.window_ms", EnvNumber),
+    ("max_in_flight", EnvNumber),
+    ("failure_detector.heartbeat_ms", EnvNumber),
+    ("failure_detector.phi_threshold", EnvNumber),
+    ("gossip.address", EnvString),
+    ("gossip.bind", EnvString),
+    ("gossip.seeds", EnvJson),
+    ("gossip.probe_interval_ms", EnvNumber),
+    ("gossip.indirect_probes", EnvNumber),
+    ("gossip.suspect_periods", EnvNumber),
+    ("data_dir", EnvString),
+    ("log_level", EnvString),
+];
+
+// Sets every field named by a PAXOS_* variable.  The rest of the variable's
+// name is the field's, lowercased, with __ between nested names: e.g.
+// PAXOS_SEND_QUEUE__MAX_BYTES sets send_queue.max_bytes.  The field decides
+// how the value is read (see ENV_FIELDS).  Variables that don't name a field
+// may belong to something else, so they're only warned about.
+fn apply_overrides(content: &mut Json, vars: &[(~str, ~str)]) -> Result<(), ConfigError> {
+    for &(ref name, ref value) in vars.iter() {
+        if !name.starts_with(ENV_PREFIX) {
+            continue;
+        }
+        let path: ~[~str] = name.slice_from(ENV_PREFIX.len()).to_ascii_lower()
+            .split_str("__").map(|s| s.to_owned()).collect();
+        let field = path.connect(".");
+        let kind = match ENV_FIELDS.iter().find(|&&(f, _)| f == field.as_slice()) {
+            Some(&(_, kind)) => kind,
+            None => {
+                warn!("Ignored {}, which doesn't name a config field", *name);
+                continue;
+            },
+        };
+        let value = match kind {
+            EnvString => String(value.clone()),
+            EnvNumber => match from_str::<f64>(value.trim()) {
+                Some(n) => Number(n),
+                None => return Err(InvalidField(field,
+                    format!("{} is not a number: {}", *name, *value))),
+            },
+            EnvJson => match json::from_str(*value) {
+                Ok(v) => v,
+                Err(err) => return Err(InvalidField(field,
+                    format!("{} is not valid JSON: {}", *name, err.to_str()))),
+            },
+        };
+        debug!("Config field {} set from {}", field, *name);
+        check!(set_field(content, path, field.clone(), value));
+    }
+    Ok(())
+}
+
+fn set_field(content: &mut Json, path: &[~str], field: ~str, value: Json) -> Result<(), ConfigError> {
+    let obj = match *content {
+        json::Object(ref mut obj) => obj,
+        _ => return Err(InvalidField(field, ~"is inside a field that isn't a table")),
+    };
+    if path.len() == 1 {
+        obj.insert(path[0].clone(), value);
+        return Ok(());
+    }
+    if !obj.contains_key(&path[0]) {
+        obj.insert(path[0].clone(), json::Object(~TreeMap::new()));
+    }
+    set_field(obj.find_mut(&path[0]).unwrap(), path.slice_from(1), field, value)
+}
+
+fn is_unix(addr: &Address) -> bool {
+    match *addr {
+        UnixAddress(..) => true,
+        _ => false,
+    }
+}
+
+fn positive(field: &str, n: uint) -> Result<(), ConfigError> {
+    if n == 0 {
+        Err(InvalidField(field.to_owned(), ~"must be positive"))
+    } else {
+        Ok(())
+    }
+}
+
+fn check_file(field: &str, path: &Path) -> Result<(), ConfigError> {
+    if path.is_file() {
+        Ok(())
+    } else {
+        Err(InvalidField(field.to_owned(), format!("{} is not a file", path.display())))
+    }
+}
+
+fn parse_address(field: ~str, s: ~str) -> Result<Address, ConfigError> {
+    match from_str::<Address>(s) {
+        Some(addr) => Ok(addr),
+        None => Err(InvalidField(field, format!("{} is not an address", s))),
+    }
+}
+
+// The fields of a JSON object, taken out one by one, so that whatever is left
+// at the end is unknown
+struct Fields {
+    // Where the object is in the config, e.g. "tls"
+    prefix: ~str,
+    obj: ~Object,
+}
+
+// A single field being converted
+struct Field {
+    name: ~str,
+}
+
+impl Fields {
+    fn from(prefix: ~str, json: Json) -> Result<Fields, ConfigError> {
+        match json {
+            json::Object(obj) => Ok(Fields{ prefix: prefix, obj: obj }),
+            _ => Err(if prefix.is_empty() {
+                Unreadable(~"expected an object")
+            } else {
+                InvalidField(prefix, ~"expected an object")
+            }),
+        }
+    }
+
+    fn name(&self, key: &str) -> ~str {
+        if self.prefix.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{}.{}", self.prefix, key)
+        }
+    }
+
+    fn optional<T>(&mut self, key: &str, convert: |&Field, Json| -> Result<T, ConfigError>)
+                   -> Result<Option<T>, ConfigError> {
+        let field = Field{ name: self.name(key) };
+        match self.obj.pop(&key.to_owned()) {
+            Some(v) => convert(&field, v).map(|v| Some(v)),
+            None => Ok(None),
+        }
+    }
+
+    fn require<T>(&mut self, key: &str, convert: |&Field, Json| -> Result<T, ConfigError>)
+                  -> Result<T, ConfigError> {
+        let name = self.name(key);
+        match check!(self.optional(key, convert)) {
+            Some(v) => Ok(v),
+            None => Err(MissingField(name)),
+        }
+    }
+
+    // Fails on the first field that nobody took
+    fn finish(self) -> Result<(), ConfigError> {
+        match self.obj.iter().next() {
+            Some((key, _)) => Err(InvalidField(self.name(*key), ~"unknown field")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Field {
+    fn invalid(&self, reason: &str) -> ConfigError {
+        InvalidField(self.name.clone(), reason.to_owned())
+    }
+
+    fn number(&self, v: Json) -> Result<f64, ConfigError> {
+        match v {
+            Number(n) => Ok(n),
+            _ => Err(self.invalid("expected a number")),
+        }
+    }
+
+    fn uint(&self, v: Json) -> Result<uint, ConfigError> {
+        let n = check!(self.number(v));
+        if n < 0.0 || n != n.floor() {
+            return Err(self.invalid("expected a non-negative integer"));
+        }
+        // Converting a float that's out of range is undefined.  The bound is
+        // the power of 2 just past the largest uint, which a float can hold.
+        if n >= uint::max_value as f64 + 1.0 {
+            return Err(InvalidField(self.name.clone(), format!("must be at most {}", uint::max_value)));
+        }
+        Ok(n as uint)
+    }
+
+    // A stable ID, which may be written as a number or as a string
+    fn stable_id(&self, v: Json) -> Result<~str, ConfigError> {
+        match v {
+            Number(..) => self.uint(v).map(|n| n.to_str()),
+            String(s) => Ok(s),
+            _ => Err(self.invalid("expected a number or a string")),
+        }
+    }
+
+    fn string(&self, v: Json) -> Result<~str, ConfigError> {
+        match v {
+            String(s) => Ok(s),
+            _ => Err(self.invalid("expected a string")),
+        }
+    }
+
+    fn path(&self, v: Json) -> Result<Path, ConfigError> {
+        self.string(v).map(|s| Path::new(s))
+    }
+
+    fn address(&self, v: Json) -> Result<Address, ConfigError> {
+        let s = check!(self.string(v));
+        parse_address(self.name.clone(), s)
+    }
+
+    fn list(&self, v: Json) -> Result<~[Json], ConfigError> {
+        match v {
+            List(items) => Ok(items),
+            _ => Err(self.invalid("expected a list")),
+        }
+    }
+
+    fn object(&self, v: Json) -> Result<Fields, ConfigError> {
+        Fields::from(self.name.clone(), v)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use extra::json;
+    use extra::json::{Json, String};
+
+    use super::{ReplicaConfig, ConfigError, InvalidField, apply_overrides};
+    use super::super::window::DEFAULT_MAX_IN_FLIGHT;
+
+    static FILE: &'static str =
+        "{\"id\": 0, \"peers\": [\"127.0.0.1:9000\", \"127.0.0.1:9001\", \"127.0.0.1:9002\"], \
+          \"max_in_flight\": 8}";
+
+    fn load(file: &str, vars: &[(&str, &str)]) -> Result<ReplicaConfig, ConfigError> {
+        let mut content = json::from_str(file).unwrap();
+        let vars: ~[(~str, ~str)] = vars.iter().map(|&(n, v)| (n.to_owned(), v.to_owned())).collect();
+        match apply_overrides(&mut content, vars) {
+            Ok(()) => ReplicaConfig::from_value(content),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn invalid_field(result: Result<ReplicaConfig, ConfigError>) -> ~str {
+        match result {
+            Err(InvalidField(field, _)) => field,
+            Err(_) => fail!("expected an invalid field"),
+            Ok(_) => fail!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn test_defaults_apply_without_file_or_env() {
+        let config = load("{\"id\": 0, \"peers\": [\"127.0.0.1:9000\", \"127.0.0.1:9001\"]}", []).unwrap();
+        assert_eq!(config.max_in_flight, DEFAULT_MAX_IN_FLIGHT);
+        assert_eq!(config.cluster_id, ~"default");
+    }
+
+    #[test]
+    fn test_file_overrides_defaults() {
+        let config = load(FILE, []).unwrap();
+        assert_eq!(config.max_in_flight, 8);
+    }
+
+    #[test]
+    fn test_env_overrides_file() {
+        let config = load(FILE, [("PAXOS_MAX_IN_FLIGHT", "3"), ("PAXOS_ID", "2")]).unwrap();
+        assert_eq!(config.max_in_flight, 3);
+        assert_eq!(config.id, 2);
+    }
+
+    #[test]
+    fn test_env_sets_nested_fields() {
+        let config = load(FILE, [("PAXOS_SEND_QUEUE__MAX_BYTES", "4096")]).unwrap();
+        assert_eq!(config.send_queue.max_bytes, 4096);
+    }
+
+    #[test]
+    fn test_env_strings_stay_strings() {
+        let config = load(FILE, [("PAXOS_CLUSTER_ID", "42"),
+                                 ("PAXOS_CLUSTER_KEY", "00112233445566778899aabbccddeeff")]).unwrap();
+        assert_eq!(config.cluster_id, ~"42");
+        assert_eq!(config.cluster_key, Some(~[0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77,
+                                                0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]));
+    }
+
+    #[test]
+    fn test_env_lists_are_json() {
+        let config = load(FILE, [("PAXOS_PEERS",
+            "[\"127.0.0.1:9000\", \"127.0.0.1:9001\", \"127.0.0.1:9002\", \"127.0.0.1:9003\"]")]).unwrap();
+        assert_eq!(config.peers.len(), 4);
+    }
+
+    #[test]
+    fn test_unknown_env_is_ignored() {
+        let config = load(FILE, [("PAXOS_HOME", "/opt/paxos"), ("PAXOS_", "x"),
+                                 ("HOME", "/root")]).unwrap();
+        assert_eq!(config.max_in_flight, 8);
+    }
+
+    #[test]
+    fn test_env_number_must_be_a_number() {
+        assert_eq!(invalid_field(load(FILE, [("PAXOS_MAX_IN_FLIGHT", "many")])), ~"max_in_flight");
+    }
+
+    #[test]
+    fn test_env_value_type_follows_field() {
+        let mut content: Json = json::from_str(FILE).unwrap();
+        apply_overrides(&mut content, [(~"PAXOS_CLUSTER_ID", ~"true")]).unwrap();
+        match content {
+            json::Object(obj) => assert_eq!(obj.find(&~"cluster_id"), Some(&String(~"true"))),
+            _ => fail!(),
+        }
+    }
+
+    #[test]
+    fn test_huge_integer_is_refused() {
+        let file = FILE.replace("\"max_in_flight\": 8", "\"max_in_flight\": 1e300");
+        assert_eq!(invalid_field(load(file.as_slice(), [])), ~"max_in_flight");
+    }
+}
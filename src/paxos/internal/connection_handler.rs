@@ -1,73 +1,217 @@
-use std::io::Listener;
-use std::io::Acceptor;
+use std::util;
+use std::comm::Select;
+use std::io::timer::{Timer, sleep};
 use std::io::buffered::BufferedStream;
-use std::io::net::ip::SocketAddr;
-use std::io::net::tcp::{TcpStream, TcpListener};
-
-use extra::comm::DuplexStream;
-use object_stream::ObjectStream;
 
 use super::replica::ReplicaID;
-use super::message::{Message, NetworkM};
+use super::wire::FrameStream;
+use super::handshake;
+use super::handshake::Session;
+use super::message::Handshake;
+use super::auth::Authenticator;
+use super::backoff::Backoff;
+use super::tls;
+use super::tls::{TlsContext, PeerStream};
+use super::transport::{Transport, TransportStream, TransportListener};
+use super::membership::AddressBook;
+
+pub type Connection = FrameStream<BufferedStream<PeerStream>>;
+
+// Applies what was agreed on in the handshake to the connection
+fn start_session(conn: &mut Connection, session: &Session, cluster_key: &Option<~[u8]>) {
+    conn.set_version(session.version);
+    match *cluster_key {
+        Some(ref key) => {
+            conn.set_authenticator(Authenticator::new(key.clone(),
+                session.our_nonce, session.their_nonce));
+        },
+        None => (),
+    }
+}
+
+// Delays (in milliseconds) between attempts to connect to an unreachable peer
+static INITIAL_BACKOFF: u64 = 50;
+static MAX_BACKOFF: u64 = 5000;
 
-pub struct ConnectionHandler {
+// How long a peer that connected to us has to complete the handshake, in
+// milliseconds
+static HANDSHAKE_TIMEOUT: u64 = 10000;
+
+pub struct ConnectionHandler<T> {
     id: ReplicaID,
-    address: SocketAddr,
-    tcp_request_streams: ~[DuplexStream<ObjectStream<BufferedStream<TcpStream>>, bool>],
-    peer_addrs: ~[SocketAddr],
+    transport: T,
+    cluster_id: ~str,
+    tls: Option<TlsContext>,
+    // Shared key used to authenticate Paxos messages
+    cluster_key: Option<~[u8]>,
+    // Where peers connect to us; bound before the replica starts, so that
+    // its address is known even if it asked for port 0
+    listener: ~TransportListener,
+    // For each peer, where to hand connections to its communicator and
+    // where the communicator asks for them.  None for this replica itself.
+    peer_links: ~[Option<(Chan<Connection>, Port<bool>)>],
+    peer_addrs: AddressBook,
+    // The stable ID of every replica, from the config
+    stable_ids: ~[~str],
 }
 
-impl ConnectionHandler {
+impl<T: Transport> ConnectionHandler<T> {
     pub fn run(mut self) {
         debug!("Running a connection handler for replica {}", self.id)
+        let hello = handshake::new_hello(self.cluster_id.clone(), self.id, self.cluster_key.is_some());
         loop {
             // Initiate connections
             for i in range(0, self.id) {
-                let stream = self.tcp_request_streams.remove(0);
+                let (conn_chan, request_port) = self.peer_links[i].take_unwrap();
                 let peer_addrs = self.peer_addrs.clone();
+                let my_id = self.id;
+                let hello = hello.clone();
+                let tls_ctx = self.tls.clone();
+                let cluster_key = self.cluster_key.clone();
+                let transport = self.transport.clone();
+                let stable_ids = self.stable_ids.clone();
                 do spawn {
+                    let mut backoff = Backoff::new(INITIAL_BACKOFF, MAX_BACKOFF);
                     loop {
-                        stream.recv();
+                        // Wait for the communicator to ask for a connection.
+                        // It does so at startup and whenever the previous
+                        // connection broke.
+                        match request_port.recv_opt() {
+                            Some(_) => (),
+                            None => return,
+                        };
                         loop {
-                            match TcpStream::connect(peer_addrs[i]) {
-                                None => continue,
-                                Some(tcp) => {
-                                    stream.send(ObjectStream::new(BufferedStream::new(tcp)));
-                                    return;
+                            // The address may have changed, or only just
+                            // become known
+                            let conn = match peer_addrs.get(i) {
+                                Some(addr) => transport.connect(&addr),
+                                None => None,
+                            };
+                            let peer = match conn {
+                                Some(conn) => match tls::connect(&tls_ctx, conn) {
+                                    Ok(peer) => Some(peer),
+                                    Err(err) => {
+                                        error!("Replica {} failed to set up TLS with replica {}: {}",
+                                            my_id, i, err);
+                                        None
+                                    },
+                                },
+                                None => None,
+                            };
+                            match peer {
+                                Some(peer) => {
+                                    let identity = peer.peer_identity();
+                                    let mut conn = FrameStream::new(BufferedStream::new(peer));
+                                    let res = tls::check_identity(&identity, stable_ids[i]).and_then(|_| {
+                                        handshake::initiate(&mut conn, &hello, i, stable_ids)
+                                            .map_err(|err| err.to_str())
+                                    });
+                                    match res {
+                                        Ok(session) => {
+                                            start_session(&mut conn, &session, &cluster_key);
+                                            backoff.reset();
+                                            conn_chan.send(conn);
+                                            break;
+                                        },
+                                        Err(err) => {
+                                            error!("Replica {}'s handshake with replica {} failed: {}",
+                                                my_id, i, err);
+                                        },
+                                    };
                                 },
+                                None => (),
                             };
+                            sleep(backoff.next_delay());
                         }
                     }
                 }
             }
 
-            // Accept connections
-            let mut acceptor = match TcpListener::bind(self.address).listen() {
-                Some(acceptor) => acceptor,
-                None => {
-                    error!("bind or listen failed :-(");
-                    return;
-                },
-            };
+            // Accept connections.  Each one is set up in a task of its own,
+            // so that a peer that connects and then stays silent can't hold
+            // up the others.  Connections that passed the handshake come back
+            // through accepted, to be handed to their communicator.
+            let (accepted, accepted_chan) = SharedChan::new();
+            let peer_links = util::replace(&mut self.peer_links, ~[]);
+            let my_id = self.id;
+            do spawn {
+                loop {
+                    match accepted.recv_opt() {
+                        Some((id, conn)) => match peer_links[id] {
+                            Some((ref conn_chan, _)) => conn_chan.send(conn),
+                            None => (),
+                        },
+                        None => return,
+                    }
+                }
+            }
 
             loop {
-                let stream = acceptor.accept().unwrap();
-                let mut stream = ObjectStream::new(BufferedStream::new(stream));
-                match stream.recv::<Message>() {
-                    Ok(NetworkM(msg)) => {
-                        // Read the replica's ID
-                        let id = msg.replica_id;
-                        debug!("Got id from replica {}", id);
-                        self.tcp_request_streams[id - self.id].send(stream);
-                    },
-                    Ok(msg) => {
-                        debug!("Got wrong initial message {}", msg.to_str());
+                let stream = match self.listener.accept() {
+                    Some(stream) => stream,
+                    None => {
+                        error!("Replica {} can't accept connections from peers anymore", my_id);
+                        return;
                     },
-                    Err(err) => {
-                        debug!("{}", err);
-                    }
                 };
+                let accepted_chan = accepted_chan.clone();
+                let hello = hello.clone();
+                let tls_ctx = self.tls.clone();
+                let cluster_key = self.cluster_key.clone();
+                let stable_ids = self.stable_ids.clone();
+                do spawn {
+                    let (mut done, done_chan) = Chan::new();
+                    do spawn {
+                        done_chan.try_send(accept_peer(my_id, stream, &hello, &tls_ctx,
+                                                       &cluster_key, stable_ids));
+                    }
+                    let mut timer = Timer::new().unwrap();
+                    let mut deadline = timer.oneshot(HANDSHAKE_TIMEOUT);
+                    let sel = Select::new();
+                    let mut d = sel.add(&mut done);
+                    let mut t = sel.add(&mut deadline);
+                    let ret = sel.wait();
+                    if ret == t.id {
+                        // The task doing the handshake goes on until the
+                        // connection fails, but its result is dropped
+                        t.recv_opt();
+                        warn!("Replica {} rejected a connection: no handshake within {} ms",
+                            my_id, HANDSHAKE_TIMEOUT);
+                    } else {
+                        match d.recv_opt() {
+                            Some(Ok((id, conn))) => accepted_chan.send((id, conn)),
+                            Some(Err(err)) => warn!("Replica {} rejected a connection: {}", my_id, err),
+                            None => (),
+                        }
+                    }
+                }
             }
         }
     }
+}
+
+// Sets up TLS and performs the handshake on a connection we accepted.
+// Returns the ID of the peer along with the connection, ready for use.
+fn accept_peer(my_id: ReplicaID, stream: ~TransportStream, hello: &Handshake,
+               tls_ctx: &Option<TlsContext>, cluster_key: &Option<~[u8]>,
+               stable_ids: &[~str]) -> Result<(ReplicaID, Connection), ~str> {
+    let peer = match tls::accept(tls_ctx, stream) {
+        Ok(peer) => peer,
+        Err(err) => return Err(err),
+    };
+    let identity = peer.peer_identity();
+    let mut conn = FrameStream::new(BufferedStream::new(peer));
+    let (id, session) = match handshake::respond(&mut conn, hello, stable_ids) {
+        Ok(res) => res,
+        Err(err) => return Err(err.to_str()),
+    };
+    // A replica may only claim the ID in its certificate
+    match tls::check_identity(&identity, stable_ids[id]) {
+        Ok(()) => {
+            debug!("Replica {} completed a handshake with replica {}", my_id, id);
+            start_session(&mut conn, &session, cluster_key);
+            Ok((id, conn))
+        },
+        Err(err) => Err(err),
+    }
 }
\ No newline at end of file
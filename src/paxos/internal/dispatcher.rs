@@ -0,0 +1,437 @@
+use std::hashmap::{HashMap, HashSet};
+
+use std::io::timer::Timer;
+
+use extra::treemap::TreeMap;
+use extra::time::precise_time_ns;
+
+use super::replica::ReplicaID;
+use super::message::{PaxosMessage, Propose};
+use super::instance::{Instance, InstanceID, Inbox, InstanceInput, Incoming, Lost, Peer, PROPOSER_TIMEOUT,
+    first_instance, incarnation_of};
+use super::batcher::{Decision, decode_commands};
+use super::window::InFlightWindow;
+
+pub enum DispatcherMessage {
+    // A message from a peer
+    Received(ReplicaID, PaxosMessage),
+    // A message to a peer that its communicator gave up on, after failing
+    // to send it over several connections
+    Undeliverable(ReplicaID, PaxosMessage),
+    // The replica started an instance as a proposer
+    Started(InstanceID, Inbox),
+    // An instance committed a value, or gave up with None
+    Decided(InstanceID, Option<~[u8]>),
+    // An instance is done and can be forgotten.  A proposer can still be
+    // waiting for acknowledgements for a while after it decided.
+    Finished(InstanceID),
+    // Asks for every value committed from now on
+    Subscribe(Chan<Decision>),
+    // Sent every GAP_CHECK_INTERVAL, to skip the gaps in the DecisionLog that
+    // won't be filled
+    Tick,
+}
+
+// How often the dispatcher looks for gaps in the DecisionLog, in
+// milliseconds
+static GAP_CHECK_INTERVAL: u64 = 1000;
+
+// The dispatcher routes the messages that communicators receive to the
+// instances they're for, and starts an acceptor whenever a peer proposes a
+// new instance.  It is the only place that knows about every running
+// instance; communicators just forward everything to it, so the cost of an
+// instance doesn't depend on the number of peers.  Communicators also report
+// the messages they gave up sending, which go back to the instance that sent
+// them, so that a proposer short of a majority gives up at once.  Instances
+// tell the dispatcher when they've decided, at which point it hands the value
+// they committed to its subscribers, and when they're done, at which point it
+// forgets about them.  Instances of the same proposer can decide out of
+// order; their values are held back in a DecisionLog until every earlier
+// instance of that proposer has decided, so that subscribers see each
+// proposer's commands in the order they were proposed.  Instance numbers go
+// up across restarts of their proposer, so once an instance of a later run
+// finishes, those of the earlier runs are taken as done.
+pub struct Dispatcher {
+    my_id: ReplicaID,
+    port: Port<DispatcherMessage>,
+    // For the instances we start to report that they're done
+    chan: SharedChan<DispatcherMessage>,
+    peers: ~[Peer],
+}
+
+impl Dispatcher {
+    pub fn run(self) {
+        debug!("Replica {} is running a dispatcher", self.my_id);
+
+        let mut instances: HashMap<InstanceID, Inbox> = HashMap::new();
+        let mut finished = FinishedInstances::new();
+        let mut log = DecisionLog::new();
+        let mut subscribers: ~[Chan<Decision>] = ~[];
+
+        let ticks = self.chan.clone();
+        do spawn {
+            let mut timer = Timer::new().unwrap();
+            let periodic = timer.periodic(GAP_CHECK_INTERVAL);
+            loop {
+                periodic.recv();
+                if !ticks.try_send(Tick) {
+                    return;
+                }
+            }
+        }
+
+        loop {
+            match self.port.recv_opt() {
+                Some(Received(from, msg)) => {
+                    match instances.find(&msg.instance_id) {
+                        Some(inbox) => {
+                            inbox.try_send(Incoming(from, msg.content));
+                            continue;
+                        },
+                        None => (),
+                    }
+                    if finished.contains(msg.instance_id) {
+                        // A late message for an instance that's done
+                        continue;
+                    }
+                    match msg.content {
+                        // Only Propose is legal
+                        Propose(..) => {
+                            let (port, inbox) = SharedChan::new();
+                            inbox.send(Incoming(from, msg.content.clone()));
+                            instances.insert(msg.instance_id, inbox);
+                            let instance = Instance::new_as_acceptor(self.my_id, msg.instance_id);
+                            spawn_instance(instance, port, self.peers.clone(), self.chan.clone(), None);
+                        },
+                        _ => {}, // overlook the wrong message
+                    }
+                },
+                Some(Undeliverable(to, msg)) => {
+                    // The instance learns it can't count on that peer
+                    for inbox in instances.find(&msg.instance_id).iter() {
+                        inbox.try_send(Lost(to, msg.content.clone()));
+                    }
+                },
+                Some(Started(iid, inbox)) => {
+                    instances.insert(iid, inbox);
+                },
+                Some(Decided(iid, value)) => {
+                    let commands = value.and_then(|value| {
+                        let commands = decode_commands(value.as_slice());
+                        if commands.is_none() {
+                            warn!("Replica {} committed a malformed value in instance {:?}",
+                                self.my_id, iid);
+                        }
+                        commands
+                    });
+                    for decision in log.insert(iid, commands).move_iter() {
+                        // Forget subscribers that have gone away
+                        subscribers.retain(|s| s.try_send(decision.clone()));
+                    }
+                },
+                Some(Finished(iid)) => {
+                    instances.remove(&iid);
+                    finished.insert(iid);
+                },
+                Some(Tick) => {
+                    let ready = log.skip_stalled(precise_time_ns(), |iid| instances.contains_key(&iid));
+                    for decision in ready.move_iter() {
+                        subscribers.retain(|s| s.try_send(decision.clone()));
+                    }
+                },
+                Some(Subscribe(chan)) => subscribers.push(chan),
+                None => return,
+            }
+        }
+    }
+}
+
+// Runs an instance in its own task, and tells the dispatcher once it has
+// decided and once it's done.  The returned port says whether the instance
+// committed.  If the instance holds a slot in a window, the slot is released
+// as soon as it has decided.
+pub fn spawn_instance(instance: Instance, inbox: Port<InstanceInput>,
+                      peers: ~[Peer], dispatcher: SharedChan<DispatcherMessage>,
+                      window: Option<InFlightWindow>) -> Port<bool> {
+    let iid = instance.id;
+    let (done_port, done_chan) = Chan::new();
+    do spawn {
+        instance.run(inbox, peers, |value| {
+            for w in window.iter() {
+                w.release();
+            }
+            done_chan.try_send(value.is_some());
+            dispatcher.try_send(Decided(iid, value));
+        });
+        dispatcher.try_send(Finished(iid));
+    }
+    done_port
+}
+
+// Remembers which instances are done, so that late messages don't bring them
+// back to life.  The instances of each proposer finish roughly in order, so
+// all we keep is, for every proposer, the number below which all of its
+// instances are done, plus the few above it that finished early.  Once a
+// proposer restarts, every instance of its earlier incarnations counts as
+// done.  An instance this replica never heard of would keep the floor where
+// it is forever, so past MAX_EARLY instances above it, the floor moves on
+// without it.
+struct FinishedInstances {
+    floors: HashMap<ReplicaID, u64>,
+    early: HashMap<ReplicaID, HashSet<u64>>,
+}
+
+impl FinishedInstances {
+    fn new() -> FinishedInstances {
+        FinishedInstances{
+            floors: HashMap::new(),
+            early: HashMap::new(),
+        }
+    }
+
+    fn contains(&self, iid: InstanceID) -> bool {
+        let (rid, n) = iid;
+        match self.floors.find(&rid) {
+            Some(&floor) if n < floor => true,
+            _ => match self.early.find(&rid) {
+                Some(early) => early.contains(&n),
+                None => false,
+            },
+        }
+    }
+
+    fn insert(&mut self, iid: InstanceID) {
+        let (rid, n) = iid;
+        let early = self.early.find_or_insert_with(rid, |_| HashSet::new());
+        let mut floor = match self.floors.find(&rid) {
+            Some(&floor) if n < floor => return,
+            Some(&floor) if incarnation_of((rid, floor)) == incarnation_of(iid) => floor,
+            _ => {
+                early.clear();
+                first_instance(incarnation_of(iid))
+            },
+        };
+        early.insert(n);
+        if early.len() > MAX_EARLY {
+            floor = *early.iter().min().unwrap();
+        }
+        while early.remove(&floor) {
+            floor += 1;
+        }
+        self.floors.insert(rid, floor);
+    }
+}
+
+// How many finished instances of a proposer FinishedInstances keeps above a
+// gap before it gives up on the gap
+static MAX_EARLY: uint = 4096;
+
+// Puts committed values back in the order their proposer started the
+// instances.  An instance that gave up takes up its place in the order but
+// yields no decision.  Nothing is handed out past a gap, so an instance this
+// replica never hears of would hold back every later instance of the same
+// proposer.  A gap that's neither filled nor running after GAP_TIMEOUT is
+// skipped, as is what's left of a proposer's earlier incarnation once it
+// restarted.
+struct DecisionLog {
+    // For every proposer, the number of the next instance to hand out
+    next: HashMap<ReplicaID, u64>,
+    // For every proposer, the instances that decided before some earlier
+    // instance of theirs
+    waiting: HashMap<ReplicaID, TreeMap<u64, Option<~[~[u8]]>>>,
+    // For every proposer with instances waiting, since when the next one
+    // has been missing, in precise_time_ns
+    stalled: HashMap<ReplicaID, u64>,
+}
+
+// How long a gap in the DecisionLog is waited for, in nanoseconds.  By then,
+// its proposer has either committed or given up.
+static GAP_TIMEOUT: u64 = 2 * PROPOSER_TIMEOUT * 1000000;
+
+impl DecisionLog {
+    fn new() -> DecisionLog {
+        DecisionLog{
+            next: HashMap::new(),
+            waiting: HashMap::new(),
+            stalled: HashMap::new(),
+        }
+    }
+
+    // Records that an instance decided, and returns the decisions that are
+    // now next in line
+    fn insert(&mut self, iid: InstanceID, commands: Option<~[~[u8]]>) -> ~[Decision] {
+        let (rid, n) = iid;
+        let mut ready = ~[];
+        {
+            let waiting = self.waiting.find_or_insert_with(rid, |_| TreeMap::new());
+            let next = match self.next.find(&rid) {
+                Some(&next) if n < next => {
+                    // Its place in the order has passed, but the value is
+                    // committed all the same
+                    warn!("Instance {:?} decided after the instances that follow it", iid);
+                    push_decision(&mut ready, iid, commands);
+                    return ready;
+                },
+                Some(&next) if incarnation_of((rid, next)) == incarnation_of(iid) => next,
+                Some(_) => {
+                    for (&m, commands) in waiting.iter() {
+                        push_decision(&mut ready, (rid, m), commands.clone());
+                    }
+                    waiting.clear();
+                    first_instance(incarnation_of(iid))
+                },
+                None => first_instance(incarnation_of(iid)),
+            };
+            waiting.insert(n, commands);
+            self.next.insert(rid, next);
+        }
+        self.hand_out(rid, &mut ready);
+        ready
+    }
+
+    // Skips the gaps that have been holding back their proposer's decisions
+    // for longer than GAP_TIMEOUT, unless the missing instance is still
+    // running, and returns the decisions that are then next in line
+    fn skip_stalled(&mut self, now: u64, running: |InstanceID| -> bool) -> ~[Decision] {
+        let mut ready = ~[];
+        let stalled: ~[(ReplicaID, u64)] = self.stalled.iter().map(|(&rid, &since)| (rid, since)).collect();
+        for &(rid, since) in stalled.iter() {
+            let next = *self.next.get(&rid);
+            if now - since < GAP_TIMEOUT || running((rid, next)) {
+                continue;
+            }
+            let (&first, _) = self.waiting.get(&rid).iter().next().unwrap();
+            warn!("Skipping instances {} to {} of replica {}, which never decided here",
+                next, first - 1, rid);
+            self.next.insert(rid, first);
+            self.hand_out(rid, &mut ready);
+        }
+        ready
+    }
+
+    // Hands out the decisions of a proposer that are next in line, and notes
+    // when it has to wait for a missing one
+    fn hand_out(&mut self, rid: ReplicaID, ready: &mut ~[Decision]) {
+        let waiting = self.waiting.get_mut(&rid);
+        let first = *self.next.get(&rid);
+        let mut next = first;
+        loop {
+            match waiting.pop(&next) {
+                Some(commands) => {
+                    push_decision(ready, (rid, next), commands);
+                    next += 1;
+                },
+                None => break,
+            }
+        }
+        self.next.insert(rid, next);
+        if waiting.is_empty() {
+            self.stalled.remove(&rid);
+        } else if next != first || !self.stalled.contains_key(&rid) {
+            self.stalled.insert(rid, precise_time_ns());
+        }
+    }
+}
+
+fn push_decision(ready: &mut ~[Decision], iid: InstanceID, commands: Option<~[~[u8]]>) {
+    for commands in commands.move_iter() {
+        ready.push(Decision{
+            instance_id: iid,
+            commands: commands,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use extra::time::precise_time_ns;
+
+    use super::super::instance::{InstanceID, first_instance};
+    use super::super::batcher::Decision;
+    use super::{FinishedInstances, MAX_EARLY, DecisionLog, GAP_TIMEOUT};
+
+    static INCARNATION: u64 = 7;
+
+    #[test]
+    fn test_late_messages_for_finished_instances_are_dropped() {
+        let first = first_instance(INCARNATION);
+        let mut finished = FinishedInstances::new();
+        finished.insert((1, first));
+        assert!(finished.contains((1, first)));
+        assert!(!finished.contains((1, first + 1)));
+        assert!(!finished.contains((2, first)));
+    }
+
+    #[test]
+    fn test_floor_advances_once_the_gap_is_filled() {
+        let first = first_instance(INCARNATION);
+        let mut finished = FinishedInstances::new();
+        finished.insert((1, first + 1));
+        finished.insert((1, first + 2));
+        assert!(!finished.contains((1, first)));
+        assert_eq!(finished.early.get(&1).len(), 2);
+
+        finished.insert((1, first));
+        assert_eq!(*finished.floors.get(&1), first + 3);
+        assert!(finished.early.get(&1).is_empty());
+        assert!(finished.contains((1, first + 2)));
+    }
+
+    #[test]
+    fn test_early_set_stays_within_bounds() {
+        let first = first_instance(INCARNATION);
+        let mut finished = FinishedInstances::new();
+        // first never finishes here
+        for n in range(1, MAX_EARLY as u64 + 2) {
+            finished.insert((1, first + n));
+            assert!(finished.early.get(&1).len() <= MAX_EARLY);
+        }
+        assert!(finished.contains((1, first)));
+        assert_eq!(*finished.floors.get(&1), first + MAX_EARLY as u64 + 2);
+    }
+
+    #[test]
+    fn test_earlier_incarnations_are_done_once_a_later_one_finishes() {
+        let mut finished = FinishedInstances::new();
+        finished.insert((1, first_instance(INCARNATION) + 5));
+        finished.insert((1, first_instance(INCARNATION + 1)));
+        assert!(finished.contains((1, first_instance(INCARNATION) + 9)));
+        assert!(!finished.contains((1, first_instance(INCARNATION + 1) + 1)));
+    }
+
+    fn instance_ids(decisions: ~[Decision]) -> ~[InstanceID] {
+        decisions.move_iter().map(|d| d.instance_id).collect()
+    }
+
+    #[test]
+    fn test_out_of_order_decisions_come_out_in_order() {
+        let first = first_instance(INCARNATION);
+        let mut log = DecisionLog::new();
+        assert!(log.insert((1, first + 1), Some(~[~[1u8]])).is_empty());
+        assert!(log.insert((1, first + 2), Some(~[~[2u8]])).is_empty());
+        let ready = log.insert((1, first), Some(~[~[0u8]]));
+        assert_eq!(ready.iter().map(|d| d.commands.clone()).collect::<~[~[~[u8]]]>(),
+            ~[~[~[0u8]], ~[~[1u8]], ~[~[2u8]]]);
+        assert_eq!(instance_ids(log.insert((1, first + 3), None)), ~[]);
+        assert_eq!(instance_ids(log.insert((1, first + 4), Some(~[]))), ~[(1, first + 4)]);
+    }
+
+    #[test]
+    fn test_gap_is_skipped_after_the_timeout() {
+        let first = first_instance(INCARNATION);
+        let mut log = DecisionLog::new();
+        log.insert((1, first), Some(~[]));
+        assert!(log.insert((1, first + 2), Some(~[])).is_empty());
+        let since = precise_time_ns();
+
+        assert!(log.skip_stalled(since, |_| false).is_empty());
+        // Still running, so it may yet decide
+        assert!(log.skip_stalled(since + GAP_TIMEOUT, |_| true).is_empty());
+        let ready = log.skip_stalled(since + GAP_TIMEOUT, |iid| {
+            assert_eq!(iid, (1, first + 1));
+            false
+        });
+        assert_eq!(instance_ids(ready), ~[(1, first + 2)]);
+        assert_eq!(instance_ids(log.insert((1, first + 3), Some(~[]))), ~[(1, first + 3)]);
+    }
+}
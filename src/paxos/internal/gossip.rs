@@ -0,0 +1,470 @@
+use std::cmp;
+use std::comm::Select;
+use std::hashmap::HashMap;
+use std::io::timer::Timer;
+use std::rand::{Rng, task_rng};
+
+use extra::arc::MutexArc;
+
+use super::replica::ReplicaID;
+use super::wire::FrameStream;
+use super::message::{GossipM, GossipMessage, GossipKind, Ping, PingReq, Ack};
+use super::membership::{AddressBook, Member, Alive, Suspect, Dead};
+use super::transport::{Address, Transport, TransportStream, TransportListener};
+
+// Optional SWIM-style membership (Das et al.), so that replicas can find each
+// other from a few seed addresses instead of having every address in every
+// config, and learn when one moves or fails.
+//
+// Every probe_interval, a replica pings one other replica, going round-robin
+// through the members it knows in random order.  If no Ack comes back within
+// the period, it asks up to indirect_probes other members to ping the target
+// for it (PingReq), and they relay the Ack if they get one.  If that fails as
+// well by the end of the next period, the target is suspected.  A suspect
+// that doesn't refute the suspicion within suspect_periods periods, by
+// gossiping a higher incarnation of itself, is declared dead.
+//
+// Membership news travels piggybacked on pings and acks: every change is
+// passed on a few times (more in larger clusters) and then dropped.  Every
+// message also carries the sender's own record, which is how a replica that
+// pings a seed gets to be known.  While a replica knows of no live member,
+// it keeps pinging its seeds.  A PingReq names the target by ID, and is only
+// followed for a replica we've heard of ourselves, so that nobody can make us
+// connect to an address of their choosing.
+//
+// Gossip uses connections of its own, one message each way, on a separate
+// address.  It doesn't use TLS, but when the cluster has a shared key, every
+// message is tagged with it, and messages without a valid tag are dropped.
+// The Paxos layer only takes addresses from it, through the AddressBook, and
+// only for the replicas whose address the config leaves out; the number of
+// replicas and their IDs are still fixed by the config.
+
+#[deriving(Clone, Eq)]
+pub struct GossipConfig {
+    // Where other replicas gossip with us
+    address: Address,
+    // Where we listen for gossip, if not at the address above
+    bind: Option<Address>,
+    // Where to look for the cluster when we know no one yet
+    seeds: ~[Address],
+    // Milliseconds between probes
+    probe_interval: u64,
+    indirect_probes: uint,
+    suspect_periods: uint,
+}
+
+pub static DEFAULT_PROBE_INTERVAL: u64 = 1000;
+pub static DEFAULT_INDIRECT_PROBES: uint = 3;
+pub static DEFAULT_SUSPECT_PERIODS: uint = 5;
+
+// The most updates piggybacked on a single message
+static MAX_PIGGYBACK: uint = 16;
+// Every update is passed on RETRANSMIT_MULT * log2(cluster size) times
+static RETRANSMIT_MULT: uint = 3;
+
+// What the listener and the probes tell the gossip task
+enum GossipEvent {
+    // A message from another replica, and where to send the answer to it
+    Request(GossipMessage, Chan<GossipMessage>),
+    // The answer to a message we sent
+    Answer(GossipMessage),
+}
+
+// The probe of the current period
+struct Probe {
+    target: ReplicaID,
+    seq: u64,
+    // Whether we've already asked others to ping the target for us
+    indirect: bool,
+    acked: bool,
+}
+
+pub struct Gossip<T> {
+    me: Member,
+    cluster_id: ~str,
+    // Tags our messages and checks others', if the cluster has a shared key
+    key: Option<~[u8]>,
+    config: GossipConfig,
+    transport: T,
+    book: AddressBook,
+    // Where every replica we've heard of gossips
+    gossip_book: AddressBook,
+    // What we know of every other replica, for the replica to read
+    view: MutexArc<~[Member]>,
+}
+
+impl GossipConfig {
+    // Where we listen for gossip
+    pub fn bind_address(&self) -> Address {
+        self.bind.clone().unwrap_or(self.address.clone())
+    }
+}
+
+impl<T: Transport> Gossip<T> {
+    // Gossips with the replicas that connect to the listener, which the
+    // replica binds before it starts, so that it can refuse to start if the
+    // address is taken
+    pub fn run(mut self, mut listener: ~TransportListener) {
+        debug!("Replica {} is gossiping on {}", self.me.id, listener.address().to_str());
+
+        let (mut events, events_chan) = SharedChan::new();
+        let listener_chan = events_chan.clone();
+        let cluster_id = self.cluster_id.clone();
+        let key = self.key.clone();
+        let my_id = self.me.id;
+        do spawn {
+            loop {
+                match listener.accept() {
+                    Some(stream) => serve(stream, cluster_id.clone(), key.clone(), listener_chan.clone()),
+                    None => {
+                        error!("Replica {} can't accept gossip anymore", my_id);
+                        break;
+                    },
+                }
+            }
+        }
+
+        let mut state = GossipState{
+            members: HashMap::new(),
+            suspects: HashMap::new(),
+            broadcasts: ~[],
+            probe: None,
+            order: ~[],
+            seq: 0,
+            period: 0,
+        };
+        let mut timer = Timer::new().unwrap();
+        let mut ticks = timer.periodic(self.config.probe_interval);
+
+        loop {
+            let event = {
+                let sel = Select::new();
+                let mut e = sel.add(&mut events);
+                let mut t = sel.add(&mut ticks);
+                let ret = sel.wait();
+                if ret == t.id {
+                    t.recv();
+                    None
+                } else {
+                    assert!(ret == e.id);
+                    Some(e.recv())
+                }
+            };
+            match event {
+                Some(Request(msg, reply)) => self.handle_request(&mut state, msg, reply, &events_chan),
+                Some(Answer(msg)) => {
+                    for probe in state.probe.mut_iter() {
+                        if msg.seq == probe.seq {
+                            probe.acked = true;
+                        }
+                    }
+                    self.merge(&mut state, msg.updates);
+                },
+                None => self.tick(&mut state, &events_chan),
+            }
+            self.publish(&state);
+        }
+    }
+
+    fn handle_request(&mut self, state: &mut GossipState, msg: GossipMessage,
+                      reply: Chan<GossipMessage>, events: &SharedChan<GossipEvent>) {
+        let GossipMessage{ seq, kind, updates, .. } = msg;
+        match kind {
+            Ping => {
+                self.merge(state, updates);
+                let ack = self.message(state, seq, Ack);
+                reply.try_send(ack);
+            },
+            PingReq(target) => {
+                self.merge(state, updates);
+                let target_addr = match self.gossip_book.get(target) {
+                    Some(addr) if target != self.me.id => addr,
+                    _ => {
+                        warn!("Replica {} refused to ping replica {}, which it doesn't know",
+                            self.me.id, target);
+                        return;
+                    },
+                };
+                // Only answer if the target answers us
+                let ack = self.message(state, seq, Ack);
+                state.seq += 1;
+                let ping_seq = state.seq;
+                let ping = self.message(state, ping_seq, Ping);
+                let transport = self.transport.clone();
+                let key = self.key.clone();
+                let events = events.clone();
+                do spawn {
+                    match exchange(&transport, &target_addr, &key, ping) {
+                        Some(answer) => {
+                            events.try_send(Answer(answer));
+                            reply.try_send(ack);
+                        },
+                        None => (),
+                    }
+                }
+            },
+            Ack => self.merge(state, updates),
+        }
+    }
+
+    // Runs once every probe period
+    fn tick(&mut self, state: &mut GossipState, events: &SharedChan<GossipEvent>) {
+        state.period += 1;
+
+        // Suspects that had their chance are dead
+        let mut dead = ~[];
+        for (&id, &since) in state.suspects.iter() {
+            if state.period - since >= self.config.suspect_periods {
+                dead.push(id);
+            }
+        }
+        for id in dead.move_iter() {
+            state.suspects.remove(&id);
+            let member = state.members.get(&id).clone();
+            warn!("Replica {} declared replica {} dead", self.me.id, id);
+            self.update(state, Member{ state: Dead, ..member });
+        }
+
+        // Follow up on the last probe
+        match state.probe.take() {
+            Some(probe) if !probe.acked && !probe.indirect => {
+                self.probe_indirectly(state, probe.target, probe.seq, events);
+                state.probe = Some(Probe{ indirect: true, ..probe });
+                return;
+            },
+            Some(probe) if !probe.acked => {
+                let suspect = match state.members.find(&probe.target) {
+                    Some(member) if member.state == Alive => Some(member.clone()),
+                    _ => None,
+                };
+                for member in suspect.move_iter() {
+                    info!("Replica {} suspects replica {}", self.me.id, probe.target);
+                    self.update(state, Member{ state: Suspect, ..member });
+                }
+            },
+            _ => (),
+        }
+
+        // Start the next one
+        match self.next_target(state) {
+            Some(target) => {
+                state.seq += 1;
+                let seq = state.seq;
+                let addr = state.members.get(&target).gossip_address.clone();
+                let ping = self.message(state, seq, Ping);
+                state.probe = Some(Probe{ target: target, seq: seq, indirect: false, acked: false });
+                spawn_exchange(self.transport.clone(), addr, self.key.clone(), ping, events.clone());
+            },
+            None => {
+                // We don't know anyone to probe; look for the cluster
+                for seed in self.config.seeds.clone().move_iter() {
+                    if seed != self.config.address {
+                        state.seq += 1;
+                        let seq = state.seq;
+                        let ping = self.message(state, seq, Ping);
+                        spawn_exchange(self.transport.clone(), seed, self.key.clone(), ping,
+                                       events.clone());
+                    }
+                }
+            },
+        }
+    }
+
+    fn probe_indirectly(&mut self, state: &mut GossipState, target: ReplicaID, seq: u64,
+                        events: &SharedChan<GossipEvent>) {
+        let mut helpers: ~[Address] = state.members.values().filter(|m| {
+            m.id != target && m.state != Dead
+        }).map(|m| m.gossip_address.clone()).collect();
+        task_rng().shuffle_mut(helpers);
+        helpers.truncate(self.config.indirect_probes);
+        for helper in helpers.move_iter() {
+            let req = self.message(state, seq, PingReq(target));
+            spawn_exchange(self.transport.clone(), helper, self.key.clone(), req, events.clone());
+        }
+    }
+
+    // Goes round-robin through the live members, in an order that's
+    // reshuffled every round
+    fn next_target(&self, state: &mut GossipState) -> Option<ReplicaID> {
+        loop {
+            if state.order.is_empty() {
+                state.order = state.members.values().filter(|m| m.state != Dead)
+                    .map(|m| m.id).collect();
+                if state.order.is_empty() {
+                    return None;
+                }
+                task_rng().shuffle_mut(state.order);
+            }
+            let id = state.order.pop();
+            match state.members.find(&id) {
+                Some(m) if m.state != Dead => return Some(id),
+                _ => (),
+            }
+        }
+    }
+
+    // Takes in membership news
+    fn merge(&mut self, state: &mut GossipState, updates: ~[Member]) {
+        for member in updates.move_iter() {
+            if member.id == self.me.id {
+                // Refute rumors of our death
+                if member.state != Alive && member.incarnation >= self.me.incarnation {
+                    self.me.incarnation = member.incarnation + 1;
+                    info!("Replica {} is refuting a suspicion at incarnation {}",
+                        self.me.id, self.me.incarnation);
+                    let me = self.me.clone();
+                    state.broadcast(me);
+                }
+                continue;
+            }
+            if member.id >= self.book.len() {
+                warn!("Replica {} heard of replica {}, which isn't in the cluster",
+                    self.me.id, member.id);
+                continue;
+            }
+            let news = match state.members.find(&member.id) {
+                Some(old) => member.supersedes(old),
+                None => true,
+            };
+            if news {
+                self.update(state, member);
+            }
+        }
+    }
+
+    // Records a change to a member, and passes it on
+    fn update(&mut self, state: &mut GossipState, member: Member) {
+        match member.state {
+            Suspect => { state.suspects.insert(member.id, state.period); },
+            _ => { state.suspects.remove(&member.id); },
+        }
+        if member.state != Dead {
+            if self.book.set(member.id, member.address.clone()) {
+                info!("Replica {} learned that replica {} is at {}",
+                    self.me.id, member.id, member.address.to_str());
+            }
+            self.gossip_book.set(member.id, member.gossip_address.clone());
+        }
+        state.members.insert(member.id, member.clone());
+        state.broadcast(member);
+    }
+
+    // A message with as much news piggybacked as fits
+    fn message(&self, state: &mut GossipState, seq: u64, kind: GossipKind) -> GossipMessage {
+        let limit = RETRANSMIT_MULT * log2(state.members.len() + 2);
+        let mut updates = ~[self.me.clone()];
+        let n = cmp::min(state.broadcasts.len(), MAX_PIGGYBACK - 1);
+        for entry in state.broadcasts.mut_slice_to(n).mut_iter() {
+            match *entry {
+                (ref member, ref mut sent) => {
+                    updates.push(member.clone());
+                    *sent += 1;
+                },
+            }
+        }
+        state.broadcasts.retain(|&(_, sent)| sent < limit);
+        GossipMessage{
+            cluster_id: self.cluster_id.clone(),
+            seq: seq,
+            kind: kind,
+            updates: updates,
+        }
+    }
+
+    fn publish(&self, state: &GossipState) {
+        let members: ~[Member] = state.members.values().map(|m| m.clone()).collect();
+        self.view.access(|v| *v = members.clone());
+    }
+}
+
+struct GossipState {
+    members: HashMap<ReplicaID, Member>,
+    // When (in probe periods) each suspect was suspected
+    suspects: HashMap<ReplicaID, uint>,
+    // News to pass on, and how many times it's been passed on so far.  The
+    // freshest news comes first.
+    broadcasts: ~[(Member, uint)],
+    probe: Option<Probe>,
+    // Who's left to probe this round
+    order: ~[ReplicaID],
+    seq: u64,
+    period: uint,
+}
+
+impl GossipState {
+    fn broadcast(&mut self, member: Member) {
+        // Newer news about a member replaces older news
+        self.broadcasts.retain(|&(ref m, _)| m.id != member.id);
+        self.broadcasts.unshift((member, 0));
+    }
+}
+
+// Answers a gossip connection
+fn serve(stream: ~TransportStream, cluster_id: ~str, key: Option<~[u8]>,
+         events: SharedChan<GossipEvent>) {
+    do spawn {
+        let mut stream = FrameStream::new(stream);
+        for key in key.move_iter() {
+            stream.set_gossip_key(key);
+        }
+        match stream.recv() {
+            Ok(GossipM(msg)) => {
+                if msg.cluster_id != cluster_id {
+                    warn!("Ignored gossip from cluster {}", msg.cluster_id);
+                    return;
+                }
+                let (reply_port, reply_chan) = Chan::new();
+                events.send(Request(msg, reply_chan));
+                match reply_port.recv_opt() {
+                    Some(reply) => { stream.try_send(&GossipM(reply)); },
+                    None => (),
+                }
+            },
+            Err(err) => warn!("Ignored gossip: {}", err.to_str()),
+            _ => (),
+        }
+    }
+}
+
+// Sends a message and reports the answer, if one comes
+fn spawn_exchange<T: Transport>(transport: T, addr: Address, key: Option<~[u8]>, msg: GossipMessage,
+                                events: SharedChan<GossipEvent>) {
+    do spawn {
+        match exchange(&transport, &addr, &key, msg) {
+            Some(answer) => { events.try_send(Answer(answer)); },
+            None => (),
+        }
+    }
+}
+
+fn exchange<T: Transport>(transport: &T, addr: &Address, key: &Option<~[u8]>,
+                          msg: GossipMessage) -> Option<GossipMessage> {
+    let cluster_id = msg.cluster_id.clone();
+    let stream = match transport.connect(addr) {
+        Some(stream) => stream,
+        None => return None,
+    };
+    let mut stream = FrameStream::new(stream);
+    for key in key.iter() {
+        stream.set_gossip_key(key.clone());
+    }
+    if !stream.try_send(&GossipM(msg)) {
+        return None;
+    }
+    match stream.recv() {
+        Ok(GossipM(answer)) => {
+            if answer.cluster_id == cluster_id { Some(answer) } else { None }
+        },
+        _ => None,
+    }
+}
+
+// The number of bits needed to write n
+fn log2(mut n: uint) -> uint {
+    let mut bits = 0;
+    while n > 0 {
+        bits += 1;
+        n >>= 1;
+    }
+    bits
+}
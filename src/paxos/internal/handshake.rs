@@ -0,0 +1,197 @@
+use std::cmp;
+use std::rand::{Rng, task_rng};
+
+use super::replica::ReplicaID;
+use super::message::{NetworkM, Hello, Reject, Identify, Nonce, Handshake};
+use super::wire::{FrameStream, FrameError, MIN_PROTOCOL_VERSION, MAX_PROTOCOL_VERSION};
+
+// When a connection is established, the replica that initiated it sends a
+// Hello describing itself.  The accepting replica checks that the Hello comes
+// from the same cluster, from a replica that is supposed to connect to it, and
+// that the two have a protocol version in common.  If so, it replies with its
+// own Hello, which the initiating replica validates in the same way; otherwise
+// it replies with a Reject explaining why, and drops the connection.  Both
+// sides then use the highest protocol version they have in common.
+//
+// From protocol version 3 on, the two sides then exchange Identify messages
+// carrying their stable IDs, and each checks that the other's is the one its
+// own config gives that ReplicaID.  This catches replicas whose configs
+// disagree about who is who.
+//
+// From protocol version 5 on, the two sides finally exchange Nonce messages,
+// each carrying a number picked at random for the connection, which
+// authenticated messages are bound to (see auth.rs).  A replica with a
+// cluster key won't speak an earlier version, since it couldn't authenticate
+// its messages.
+
+// The first protocol version with Identify messages
+static IDENTIFY_VERSION: u8 = 3;
+// The first protocol version with Nonce messages
+static NONCE_VERSION: u8 = 5;
+
+// What the two sides of a connection agreed on
+pub struct Session {
+    version: u8,
+    our_nonce: u64,
+    their_nonce: u64,
+}
+
+#[deriving(ToStr)]
+pub enum HandshakeError {
+    // (#ours, #theirs)
+    ClusterMismatch(~str, ~str),
+    // (#our range, #their range)
+    NoCommonVersion((u8, u8), (u8, u8)),
+    // The peer isn't a replica that's supposed to connect to us, or isn't
+    // the replica we tried to connect to
+    UnexpectedReplica(ReplicaID),
+    // (#expected, #claimed) The peer's stable ID isn't the one our config
+    // gives it
+    IdentityMismatch(~str, ~str),
+    // The peer rejected our Hello
+    Rejected(~str),
+    UnexpectedMessage,
+    BrokenConnection(FrameError),
+}
+
+// The Hello of a replica.  One that authenticates its messages needs a
+// version with nonces.
+pub fn new_hello(cluster_id: ~str, replica_id: ReplicaID, authenticated: bool) -> Handshake {
+    Handshake{
+        cluster_id: cluster_id,
+        min_version: if authenticated { NONCE_VERSION } else { MIN_PROTOCOL_VERSION },
+        max_version: MAX_PROTOCOL_VERSION,
+        replica_id: replica_id,
+    }
+}
+
+// Performs the handshake on a connection we initiated to peer_id.  stable_ids
+// holds the stable ID of every replica, indexed by ReplicaID.
+pub fn initiate<S: Reader + Writer>(stream: &mut FrameStream<S>, hello: &Handshake,
+                                    peer_id: ReplicaID, stable_ids: &[~str])
+                                    -> Result<Session, HandshakeError> {
+    stream.try_send(&NetworkM(Hello(hello.clone())));
+    let theirs = match stream.recv() {
+        Ok(NetworkM(Hello(theirs))) => theirs,
+        Ok(NetworkM(Reject(reason))) => return Err(Rejected(reason)),
+        Ok(_) => return Err(UnexpectedMessage),
+        Err(err) => return Err(BrokenConnection(err)),
+    };
+    if theirs.replica_id != peer_id {
+        return Err(UnexpectedReplica(theirs.replica_id));
+    }
+    let session = match check(&hello, &theirs) {
+        Ok(session) => session,
+        Err(err) => return Err(err),
+    };
+    if session.version < IDENTIFY_VERSION {
+        return Ok(session);
+    }
+
+    stream.try_send(&NetworkM(Identify(stable_ids[hello.replica_id].clone())));
+    match stream.recv() {
+        Ok(NetworkM(Identify(claimed))) => {
+            if claimed != stable_ids[peer_id] {
+                return Err(IdentityMismatch(stable_ids[peer_id].clone(), claimed));
+            }
+        },
+        Ok(NetworkM(Reject(reason))) => return Err(Rejected(reason)),
+        Ok(_) => return Err(UnexpectedMessage),
+        Err(err) => return Err(BrokenConnection(err)),
+    }
+    if session.version < NONCE_VERSION {
+        return Ok(session);
+    }
+
+    let mut session = session;
+    stream.try_send(&NetworkM(Nonce(session.our_nonce)));
+    match stream.recv() {
+        Ok(NetworkM(Nonce(theirs))) => {
+            session.their_nonce = theirs;
+            Ok(session)
+        },
+        Ok(NetworkM(Reject(reason))) => Err(Rejected(reason)),
+        Ok(_) => Err(UnexpectedMessage),
+        Err(err) => Err(BrokenConnection(err)),
+    }
+}
+
+// Performs the handshake on a connection we accepted.  stable_ids holds the
+// stable ID of every replica, indexed by ReplicaID.  Returns the ID of the
+// peer along with the session.
+pub fn respond<S: Reader + Writer>(stream: &mut FrameStream<S>, hello: &Handshake,
+                                   stable_ids: &[~str]) -> Result<(ReplicaID, Session), HandshakeError> {
+    let num_replicas = stable_ids.len();
+    let theirs = match stream.recv() {
+        Ok(NetworkM(Hello(theirs))) => theirs,
+        Ok(_) => return Err(UnexpectedMessage),
+        Err(err) => return Err(BrokenConnection(err)),
+    };
+
+    // Only replicas with a higher ID initiate connections to us
+    let res = if theirs.replica_id <= hello.replica_id || theirs.replica_id >= num_replicas {
+        Err(UnexpectedReplica(theirs.replica_id))
+    } else {
+        check(&hello, &theirs)
+    };
+
+    let session = match res {
+        Ok(session) => {
+            stream.try_send(&NetworkM(Hello(hello.clone())));
+            session
+        },
+        Err(err) => {
+            stream.try_send(&NetworkM(Reject(err.to_str())));
+            return Err(err);
+        },
+    };
+    if session.version < IDENTIFY_VERSION {
+        return Ok((theirs.replica_id, session));
+    }
+
+    let expected = stable_ids[theirs.replica_id].clone();
+    match stream.recv() {
+        Ok(NetworkM(Identify(claimed))) => {
+            if claimed == expected {
+                stream.try_send(&NetworkM(Identify(stable_ids[hello.replica_id].clone())));
+            } else {
+                let err = IdentityMismatch(expected, claimed);
+                stream.try_send(&NetworkM(Reject(err.to_str())));
+                return Err(err);
+            }
+        },
+        Ok(_) => return Err(UnexpectedMessage),
+        Err(err) => return Err(BrokenConnection(err)),
+    }
+    if session.version < NONCE_VERSION {
+        return Ok((theirs.replica_id, session));
+    }
+
+    let mut session = session;
+    match stream.recv() {
+        Ok(NetworkM(Nonce(nonce))) => {
+            session.their_nonce = nonce;
+            stream.try_send(&NetworkM(Nonce(session.our_nonce)));
+            Ok((theirs.replica_id, session))
+        },
+        Ok(_) => Err(UnexpectedMessage),
+        Err(err) => Err(BrokenConnection(err)),
+    }
+}
+
+fn check(ours: &Handshake, theirs: &Handshake) -> Result<Session, HandshakeError> {
+    if ours.cluster_id != theirs.cluster_id {
+        return Err(ClusterMismatch(ours.cluster_id.clone(), theirs.cluster_id.clone()));
+    }
+    let version = cmp::min(ours.max_version, theirs.max_version);
+    if version < cmp::max(ours.min_version, theirs.min_version) {
+        return Err(NoCommonVersion((ours.min_version, ours.max_version),
+                                   (theirs.min_version, theirs.max_version)));
+    }
+    // Our nonce is only sent, and theirs only known, from NONCE_VERSION on
+    Ok(Session{
+        version: version,
+        our_nonce: task_rng().gen(),
+        their_nonce: 0,
+    })
+}
@@ -0,0 +1,220 @@
+use extra::arc::MutexArc;
+use extra::ringbuf::RingBuf;
+use extra::container::Deque;
+
+use super::replica::ReplicaID;
+
+// How often communicators send heartbeats, and how suspicious the failure
+// detector has to be before a peer counts as down
+#[deriving(Clone, Eq)]
+pub struct DetectorConfig {
+    // Milliseconds between heartbeats
+    heartbeat_interval: u64,
+    phi_threshold: f64,
+}
+
+pub static DEFAULT_DETECTOR_CONFIG: DetectorConfig = DetectorConfig{
+    heartbeat_interval: 500,
+    phi_threshold: 8.0,
+};
+
+// How many inter-arrival times the detector remembers
+static MAX_SAMPLES: uint = 1000;
+
+// What we know about a peer's liveness
+#[deriving(Clone, ToStr)]
+pub struct PeerHealth {
+    peer: ReplicaID,
+    // False once phi crosses the threshold, or if we never heard from the peer
+    alive: bool,
+    phi: f64,
+    // Milliseconds since we last heard from the peer
+    last_heard: Option<u64>,
+    // Smoothed round-trip time of heartbeats, in milliseconds
+    rtt: Option<f64>,
+}
+
+// A phi-accrual failure detector (Hayashibara et al.).  Rather than declaring
+// a peer down after a fixed timeout, it learns the distribution of the time
+// between messages from the peer, and reports phi: how unlikely it is, on a
+// log10 scale, that the peer is still up given how long it has been silent.
+// Every message from the peer counts as a heartbeat.  The communicator feeds
+// it and the replica reads it, so it lives behind a MutexArc.
+#[deriving(Clone)]
+pub struct HealthMonitor {
+    priv state: MutexArc<Detector>,
+}
+
+struct Detector {
+    peer: ReplicaID,
+    config: DetectorConfig,
+    // Times between consecutive heartbeats, in milliseconds
+    intervals: RingBuf<f64>,
+    sum: f64,
+    sum_of_squares: f64,
+    // When we last heard from the peer, in nanoseconds
+    last: Option<u64>,
+    rtt: Option<f64>,
+}
+
+impl HealthMonitor {
+    pub fn new(peer: ReplicaID, config: DetectorConfig) -> HealthMonitor {
+        HealthMonitor{
+            state: MutexArc::new(Detector{
+                peer: peer,
+                config: config,
+                intervals: RingBuf::new(),
+                sum: 0.0,
+                sum_of_squares: 0.0,
+                last: None,
+                rtt: None,
+            }),
+        }
+    }
+
+    // Records that we heard from the peer at now (in nanoseconds)
+    pub fn heartbeat(&self, now: u64) {
+        self.state.access(|d| {
+            match d.last {
+                Some(last) if now > last => d.add_interval(ns_to_ms(now - last)),
+                Some(_) => (),
+                None => {
+                    // Nothing to go by yet but the heartbeat interval
+                    let interval = d.config.heartbeat_interval as f64;
+                    d.add_interval(interval);
+                },
+            }
+            d.last = Some(now);
+        })
+    }
+
+    // Changes the heartbeat interval and threshold of a running detector.
+    // What it learned about the peer so far is kept.
+    pub fn set_config(&self, config: DetectorConfig) {
+        self.state.access(|d| d.config = config.clone())
+    }
+
+    // Records the round trip of a heartbeat, in nanoseconds
+    pub fn round_trip(&self, rtt: u64) {
+        self.state.access(|d| {
+            let sample = ns_to_ms(rtt);
+            d.rtt = Some(match d.rtt {
+                Some(old) => 0.875 * old + 0.125 * sample,
+                None => sample,
+            });
+        })
+    }
+
+    pub fn snapshot(&self, now: u64) -> PeerHealth {
+        self.state.access(|d| {
+            let phi = d.phi(now);
+            PeerHealth{
+                peer: d.peer,
+                alive: d.last.is_some() && phi < d.config.phi_threshold,
+                phi: phi,
+                last_heard: d.last.map(|last| if now > last { (now - last) / 1000000 } else { 0 }),
+                rtt: d.rtt,
+            }
+        })
+    }
+}
+
+impl Detector {
+    fn add_interval(&mut self, interval: f64) {
+        if self.intervals.len() == MAX_SAMPLES {
+            let old = self.intervals.pop_front().unwrap();
+            self.sum -= old;
+            self.sum_of_squares -= old * old;
+        }
+        self.intervals.push_back(interval);
+        self.sum += interval;
+        self.sum_of_squares += interval * interval;
+    }
+
+    fn phi(&self, now: u64) -> f64 {
+        let last = match self.last {
+            Some(last) => last,
+            None => return 0.0,
+        };
+        let elapsed = if now > last { ns_to_ms(now - last) } else { 0.0 };
+        let n = self.intervals.len() as f64;
+        let mean = self.sum / n;
+        let variance = self.sum_of_squares / n - mean * mean;
+        // Keep a peer with very regular heartbeats from being suspected the
+        // moment one is a little late
+        let min_std_dev = self.config.heartbeat_interval as f64 / 4.0;
+        let std_dev = if variance > min_std_dev * min_std_dev {
+            variance.sqrt()
+        } else {
+            min_std_dev
+        };
+
+        // A logistic approximation of the normal CDF
+        let y = (elapsed - mean) / std_dev;
+        let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+        if elapsed > mean {
+            -(e / (1.0 + e)).log10()
+        } else {
+            -(1.0 - 1.0 / (1.0 + e)).log10()
+        }
+    }
+}
+
+fn ns_to_ms(ns: u64) -> f64 {
+    ns as f64 / 1000000.0
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HealthMonitor, DEFAULT_DETECTOR_CONFIG};
+
+    static MS: u64 = 1000000;
+
+    // A monitor that heard from its peer every heartbeat interval, the last
+    // time at the returned time
+    fn regular_monitor() -> (HealthMonitor, u64) {
+        let monitor = HealthMonitor::new(1, DEFAULT_DETECTOR_CONFIG);
+        let interval = DEFAULT_DETECTOR_CONFIG.heartbeat_interval * MS;
+        let mut now = 1000 * MS;
+        for _ in range(0, 20) {
+            monitor.heartbeat(now);
+            now += interval;
+        }
+        (monitor, now - interval)
+    }
+
+    #[test]
+    fn test_phi_stays_low_on_regular_heartbeats() {
+        let (monitor, last) = regular_monitor();
+        let interval = DEFAULT_DETECTOR_CONFIG.heartbeat_interval * MS;
+        for &elapsed in [0, interval / 2, interval, interval + interval / 4].iter() {
+            let health = monitor.snapshot(last + elapsed);
+            assert!(health.alive);
+            assert!(health.phi < 1.0);
+        }
+    }
+
+    #[test]
+    fn test_phi_rises_past_the_threshold_after_a_silence() {
+        let (monitor, last) = regular_monitor();
+        let interval = DEFAULT_DETECTOR_CONFIG.heartbeat_interval * MS;
+        let mut previous = monitor.snapshot(last + interval).phi;
+        for n in range(2u64, 6) {
+            let phi = monitor.snapshot(last + n * interval).phi;
+            assert!(phi > previous);
+            previous = phi;
+        }
+        let health = monitor.snapshot(last + 5 * interval);
+        assert!(health.phi > DEFAULT_DETECTOR_CONFIG.phi_threshold);
+        assert!(!health.alive);
+        assert_eq!(health.last_heard, Some(5 * DEFAULT_DETECTOR_CONFIG.heartbeat_interval));
+    }
+
+    #[test]
+    fn test_a_peer_never_heard_from_is_down() {
+        let monitor = HealthMonitor::new(1, DEFAULT_DETECTOR_CONFIG);
+        let health = monitor.snapshot(1000 * MS);
+        assert!(!health.alive);
+        assert_eq!(health.last_heard, None);
+    }
+}
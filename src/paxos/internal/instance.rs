@@ -1,19 +1,62 @@
-use std::comm::Data;
+use std::comm::Select;
+use std::io::timer::Timer;
 
-use extra::comm::DuplexStream;
+use extra::time::precise_time_ns;
 
 use super::replica::ReplicaID;
 use super::message::{Propose, Promise, RejectPropose, Request, Accept,
-    RejectRequest, Commit, Acknowledge, PaxosMessageContent};
+    RejectRequest, Commit, Acknowledge, PaxosMessageContent, PaxosMessage};
 
 #[deriving(Clone, TotalOrd, Encodable, Decodable)]
 pub type SequenceID = (uint, ReplicaID);
 
 #[deriving(Clone, TotalOrd, Encodable, Decodable)]
-pub type InstanceID = (ReplicaID, uint);
+pub type InstanceID = (ReplicaID, u64);
 
-type Peers = ~[DuplexStream<PaxosMessageContent, PaxosMessageContent>];
-type BorrowedPeers<'a> = &'a [DuplexStream<PaxosMessageContent, PaxosMessageContent>];
+// How often a proposer sends the messages of its current phase again to the
+// peers that haven't answered them, in milliseconds
+static RETRY_INTERVAL: u64 = 1000;
+// How long a proposer keeps trying before it abandons its instance, in
+// milliseconds
+pub static PROPOSER_TIMEOUT: u64 = 30000;
+// How long a proposer that committed keeps sending Commit to the peers that
+// haven't acknowledged it, in milliseconds
+static COMMIT_LINGER: u64 = 10000;
+// How long an acceptor waits for the next message before it gives up on its
+// instance, in milliseconds
+static ACCEPTOR_TIMEOUT: u64 = 60000;
+
+// Messages for an instance, along with the replica they came from.  All of an
+// instance's messages arrive on a single port, so it can simply block on it.
+pub type Inbox = SharedChan<InstanceInput>;
+
+pub enum InstanceInput {
+    // A message from a peer
+    Incoming(ReplicaID, PaxosMessageContent),
+    // A message to a peer that its communicator gave up on
+    Lost(ReplicaID, PaxosMessageContent),
+}
+
+// An instance reaches a peer through the communicator for that peer
+#[deriving(Clone)]
+pub struct Peer {
+    id: ReplicaID,
+    outbox: SharedChan<PaxosMessage>,
+}
+
+impl Peer {
+    // The communicator stops once it has no connection left, after which
+    // the message goes nowhere, as it would to an unreachable peer
+    fn send(&self, iid: InstanceID, content: PaxosMessageContent) {
+        self.outbox.try_send(PaxosMessage{
+            instance_id: iid,
+            content: content,
+        });
+    }
+}
+
+type Peers = ~[Peer];
+type BorrowedPeers<'a> = &'a [Peer];
 
 pub fn increment_seq(sid: SequenceID) -> SequenceID {
     match sid {
@@ -27,6 +70,25 @@ pub fn increment_iid(iid: InstanceID) -> InstanceID {
     }
 }
 
+// Instance numbers carry the incarnation of their proposer in their upper 32
+// bits, and count up from there.  They're u64s so that this holds on 32-bit
+// targets as well.  A replica picks a higher incarnation every
+// time it starts (see replica.rs), so the instances of a replica that
+// restarted can't be mistaken for those of its previous run, which its peers
+// consider done.
+static INCARNATION_SHIFT: u64 = 32;
+
+// The number of the first instance a replica proposes in an incarnation
+pub fn first_instance(incarnation: u64) -> u64 {
+    incarnation << INCARNATION_SHIFT
+}
+
+// The incarnation of the replica that proposed an instance
+pub fn incarnation_of(iid: InstanceID) -> u64 {
+    let (_, n) = iid;
+    n >> INCARNATION_SHIFT
+}
+
 pub enum InstanceIdentity {
     Proposer,
     Acceptor,
@@ -37,18 +99,41 @@ pub enum InstanceState {
     // Initial state
     Null,
 
-    // (#sequence, value, #promised)
-    Proposed(SequenceID, uint),
+    // (#sequence, #the acceptors that promised)
+    Proposed(SequenceID, ~[ReplicaID]),
     // (#sequence)
     Promised(SequenceID),
 
-    // (#sequence, value, #accepted)
-    Requested(SequenceID, ~[u8], uint),
+    // (#sequence, value, #the acceptors that accepted)
+    Requested(SequenceID, ~[u8], ~[ReplicaID]),
     // (#sequence, value)
     Accepted(SequenceID, ~[u8]),
 
-    // (#sequence, value)
-    Committed(SequenceID, ~[u8], uint),
+    // (#sequence, value, #the acceptors that acknowledged)
+    Committed(SequenceID, ~[u8], ~[ReplicaID]),
+}
+
+// Blocks until a message arrives or the timer fires.  Returns Some(None) for
+// the latter, and None once the inbox is closed.
+fn next_message(inbox: &mut Port<InstanceInput>, timer: &mut Port<()>)
+                -> Option<Option<InstanceInput>> {
+    let sel = Select::new();
+    let mut messages = sel.add(inbox);
+    let mut fired = sel.add(timer);
+    if sel.wait() == fired.id {
+        fired.recv_opt();
+        return Some(None);
+    }
+    messages.recv_opt().map(|m| Some(m))
+}
+
+// Counts an acceptor's vote, unless it was already counted.  Communicators
+// resend messages that might not have gone through, so the same reply can
+// arrive twice, and must not count as two acceptors towards a majority.
+fn add_vote(votes: &mut ~[ReplicaID], from: ReplicaID) {
+    if !votes.contains(&from) {
+        votes.push(from);
+    }
 }
 
 pub struct Instance {
@@ -82,65 +167,145 @@ impl Instance {
         }
     }
 
-    pub fn run(self, peers: Peers) {
+    // Runs the instance until it has committed a value or given up, and
+    // calls decided with the value, or None if the instance was abandoned.
+    // A proposer goes on running for a while after that, until every peer
+    // has acknowledged its Commit.
+    pub fn run(self, inbox: Port<InstanceInput>, peers: Peers,
+               decided: |Option<~[u8]>|) {
         match self.identity {
-            Proposer => self.run_as_proposer(peers),
-            Acceptor => self.run_as_acceptor(peers),
+            Proposer => self.run_as_proposer(inbox, peers, decided),
+            Acceptor => self.run_as_acceptor(inbox, peers, decided),
         }
     }
 
-    fn run_as_proposer(mut self, peers: Peers) {
+    // Proposes the value, and sends the messages of the current phase again
+    // every RETRY_INTERVAL to the peers that haven't answered, since a
+    // message can be lost with its connection.  Abandons the instance after
+    // PROPOSER_TIMEOUT, or as soon as too many peers are unreachable for a
+    // majority to answer.
+    fn run_as_proposer(mut self, mut inbox: Port<InstanceInput>, peers: Peers,
+                       decided: |Option<~[u8]>|) {
+        let mut timer = Timer::new().unwrap();
+        let mut retry = timer.periodic(RETRY_INTERVAL);
+        let deadline = precise_time_ns() + PROPOSER_TIMEOUT * 1000000;
+        let majority = peers.len() / 2 + 1;
+        // The peers a message was lost to, since they last answered
+        let mut unreachable = ~[];
         let seq = (0, self.replica_id);
         self.propose(seq, peers);
 
-        let get_messages = |peers: BorrowedPeers| {
-            peers.iter().fold(~[], |mut messages, peer| {
-                match peer.try_recv() {
-                    Data(msg) => messages.push(msg),
-                    _ => (),
-                }
-                messages
-            })
-        };
+        while !self.is_committed() {
+            match next_message(&mut inbox, &mut retry) {
+                Some(Some(Incoming(from, msg))) => {
+                    unreachable.retain(|&id| id != from);
+                    match msg {
+                        Promise(seq) => self.handle_promise(from, seq, peers),
+                        RejectPropose(s1, s2) => self.handle_reject_propose(s1, s2, peers),
+                        Accept(seq) => self.handle_accept(from, seq, peers),
+                        RejectRequest(s1, s2) => self.handle_reject_request(s1, s2, peers),
+                        _ => (),
+                    }
+                },
+                Some(Some(Lost(to, _))) => {
+                    add_vote(&mut unreachable, to);
+                    if peers.len() - unreachable.len() < majority {
+                        warn!("Instance {:?} on replica {} gave up, with {} of its {} peers unreachable",
+                            self.id, self.replica_id, unreachable.len(), peers.len());
+                        return decided(None);
+                    }
+                },
+                Some(None) if precise_time_ns() < deadline => self.retry(peers),
+                Some(None) => {
+                    warn!("Instance {:?} on replica {} gave up after {} ms",
+                        self.id, self.replica_id, PROPOSER_TIMEOUT);
+                    return decided(None);
+                },
+                None => return decided(None),
+            }
+        }
+        decided(self.committed_value());
 
-        loop {
-            let messages = get_messages(peers);
-            for msg in messages.move_iter() {
-                match msg {
-                    Promise(seq) => self.handle_promise(seq, peers),
-                    RejectPropose(s1, s2) => self.handle_reject_propose(s1, s2, peers),
-                    Accept(seq) => self.handle_accept(seq, peers),
-                    RejectRequest(s1, s2) => self.handle_reject_request(s1, s2, peers),
-                    Acknowledge(seq) => self.handle_acknowledge(seq, peers),
-                    _ => (),
-                };
+        // An acceptor that missed the Commit would never finish otherwise
+        let deadline = precise_time_ns() + COMMIT_LINGER * 1000000;
+        while !self.is_acknowledged(peers) {
+            match next_message(&mut inbox, &mut retry) {
+                Some(Some(Incoming(from, Acknowledge(seq)))) => self.handle_acknowledge(from, seq, peers),
+                Some(Some(_)) => (),
+                Some(None) if precise_time_ns() < deadline => self.retry(peers),
+                Some(None) | None => return,
             }
         }
     }
 
+    // Sends the messages of the current phase again, to the peers that
+    // haven't answered them yet
+    fn retry(&self, peers: BorrowedPeers) {
+        match self.state {
+            Proposed(seq, ref promised) => {
+                for peer in peers.iter().filter(|p| !promised.contains(&p.id)) {
+                    peer.send(self.id, Propose(seq));
+                }
+            },
+            Requested(seq, ref value, ref accepted) => {
+                for peer in peers.iter().filter(|p| !accepted.contains(&p.id)) {
+                    peer.send(self.id, Request(seq, value.clone()));
+                }
+            },
+            Committed(seq, _, ref acknowledged) => {
+                for peer in peers.iter().filter(|p| !acknowledged.contains(&p.id)) {
+                    peer.send(self.id, Commit(seq));
+                }
+            },
+            _ => (),
+        }
+    }
+
+    fn is_committed(&self) -> bool {
+        match self.state {
+            Committed(..) => true,
+            _ => false,
+        }
+    }
+
+    fn is_acknowledged(&self, peers: BorrowedPeers) -> bool {
+        match self.state {
+            Committed(_, _, ref acknowledged) => acknowledged.len() >= peers.len(),
+            _ => false,
+        }
+    }
+
+    fn committed_value(&self) -> Option<~[u8]> {
+        match self.state {
+            Committed(_, ref value, _) => Some(value.clone()),
+            _ => None,
+        }
+    }
+
     fn propose(&mut self, seq: SequenceID, peers: BorrowedPeers) {
         debug!("Instance {:?} on replica {} is proposing", self.id, self.replica_id);
-        self.state = Proposed(seq, 0);
+        self.state = Proposed(seq, ~[]);
         for peer in peers.iter() {
-            peer.send(Propose(seq));
+            peer.send(self.id, Propose(seq));
         }
     }
 
-    fn handle_promise(&mut self, seq: SequenceID, peers: BorrowedPeers) {
+    fn handle_promise(&mut self, from: ReplicaID, seq: SequenceID, peers: BorrowedPeers) {
         debug!("Instance {:?} on replica {} is handling a Promise message", self.id, self.replica_id);
         let majority: uint = peers.len() / 2 + 1;
-        match self.state {
-            Proposed(old_seq, count) => {
+        match self.state.clone() {
+            Proposed(old_seq, promised) => {
                 if seq == old_seq {
-                    count += 1;
-                    if count >= majority {
+                    let mut promised = promised;
+                    add_vote(&mut promised, from);
+                    if promised.len() >= majority {
                         for peer in peers.iter() {
-                            peer.send(Request(seq, self.value.clone()));
+                            peer.send(self.id, Request(seq, self.value.clone()));
                         }
-                        self.state = Requested(seq, self.value.clone(), 0);
+                        self.state = Requested(seq, self.value.clone(), ~[]);
                         return;
                     } else {
-                        self.state = Proposed(seq, count);
+                        self.state = Proposed(seq, promised);
                     }
                 } else if seq > old_seq {
                     self.propose(increment_seq(seq), peers);
@@ -162,22 +327,23 @@ impl Instance {
         }
     }
 
-    fn handle_accept(&mut self, seq: SequenceID, peers: BorrowedPeers) {
+    fn handle_accept(&mut self, from: ReplicaID, seq: SequenceID, peers: BorrowedPeers) {
         debug!("Instance {:?} on replica {} is handling an Accept message", self.id, self.replica_id);
         let majority: uint = peers.len() / 2 + 1;
         match self.state.clone() {
-            Requested(old_seq, value, count) => {
+            Requested(old_seq, value, accepted) => {
                 if seq == old_seq {
-                    count += 1;
-                    if count >= majority {
+                    let mut accepted = accepted;
+                    add_vote(&mut accepted, from);
+                    if accepted.len() >= majority {
                         for peer in peers.iter() {
-                            peer.send(Commit(seq));
+                            peer.send(self.id, Commit(seq));
                         }
                         self.commit(seq, self.value.clone());
-                        self.state = Committed(seq, self.value.clone(), 0);
+                        self.state = Committed(seq, self.value.clone(), ~[]);
                         return;
                     } else {
-                        self.state = Requested(old_seq, value, count + 1);
+                        self.state = Requested(old_seq, value, accepted);
                     }
                 } else if seq > old_seq {
                     self.propose(increment_seq(seq), peers);
@@ -199,31 +365,52 @@ impl Instance {
         }
     }
 
-    fn handle_acknowledge(&mut self, seq: SequenceID, _: BorrowedPeers) {
+    fn handle_acknowledge(&mut self, from: ReplicaID, seq: SequenceID, _: BorrowedPeers) {
         debug!("Instance {:?} on replica {} is handling an Acknowledge message", self.id, self.replica_id);
         match self.state.clone() {
-            Committed(old_seq, value, count) => {
+            Committed(old_seq, value, acknowledged) => {
                 if seq == old_seq {
-                    self.state = Committed(seq, value, count + 1);
+                    let mut acknowledged = acknowledged;
+                    add_vote(&mut acknowledged, from);
+                    self.state = Committed(seq, value, acknowledged);
                 }
             },
             _ => (),
         }
     }
 
-    fn run_as_acceptor(mut self, mut peers: Peers) {
+    // Answers the proposer until the value is committed.  Gives up on the
+    // instance if the proposer goes quiet for ACCEPTOR_TIMEOUT, which it only
+    // does once it's done or gone.
+    fn run_as_acceptor(mut self, mut inbox: Port<InstanceInput>, peers: Peers,
+                       decided: |Option<~[u8]>|) {
+        let mut timer = Timer::new().unwrap();
         loop {
-            for peer in peers.mut_iter() {
-                let reply = match peer.try_recv() {
-                    Data(Propose(seq)) => self.handle_propose(seq),
-                    Data(Request(seq, value)) => self.handle_request(seq, value),
-                    Data(Commit(seq)) => self.handle_commit(seq),
-                    _ => None,
-                };
-                match reply {
-                    Some(msg) => peer.send(msg),
-                    None => (),
-                };
+            let mut timeout = timer.oneshot(ACCEPTOR_TIMEOUT);
+            let (from, msg) = match next_message(&mut inbox, &mut timeout) {
+                Some(Some(Incoming(from, msg))) => (from, msg),
+                // The proposer sends its message again if it still needs
+                // the answer
+                Some(Some(Lost(..))) => continue,
+                Some(None) => {
+                    warn!("Instance {:?} on replica {} heard nothing from its proposer for {} ms",
+                        self.id, self.replica_id, ACCEPTOR_TIMEOUT);
+                    return decided(None);
+                },
+                None => return decided(None),
+            };
+            let reply = match msg {
+                Propose(seq) => self.handle_propose(seq),
+                Request(seq, value) => self.handle_request(seq, value),
+                Commit(seq) => self.handle_commit(seq),
+                _ => None,
+            };
+            match (reply, peers.iter().find(|p| p.id == from)) {
+                (Some(msg), Some(peer)) => peer.send(self.id, msg),
+                _ => (),
+            };
+            if self.is_committed() {
+                return decided(self.committed_value());
             }
         }
     }
@@ -243,7 +430,10 @@ impl Instance {
                 }
             },
             Accepted(old_seq, _) => {
-                if seq >= old_seq {
+                if seq == old_seq {
+                    // A resent Propose; the value we accepted stays
+                    Some(Promise(seq))
+                } else if seq > old_seq {
                     self.state = Promised(seq);
                     Some(Promise(seq))
                 } else {
@@ -268,7 +458,8 @@ impl Instance {
             },
             Accepted(old_seq, _) => {
                 if seq == old_seq {
-                    None
+                    // A resent Request, whose Accept may have been lost
+                    Some(Accept(seq))
                 } else {
                     Some(RejectRequest(seq, old_seq))
                 }
@@ -284,13 +475,15 @@ impl Instance {
             Promised(..) => None,
             Accepted(old_seq, value) => {
                 if old_seq == seq {
-                    self.state = Committed(seq, value.clone(), 0);
+                    self.state = Committed(seq, value.clone(), ~[]);
                     self.commit(seq, value);
                     Some(Acknowledge(seq))
                 } else {
                     None
                 }
             },
+            // A resent Commit, whose Acknowledge may have been lost
+            Committed(old_seq, _, _) if old_seq == seq => Some(Acknowledge(seq)),
             Committed(..) => None,
             _ => fail!("Illegal state"),
         }
@@ -0,0 +1,49 @@
+use std::logging::{ERROR, WARN, INFO, DEBUG};
+use std::rt::crate_map::{get_crate_map, iter_crate_map};
+
+// The log level of the paxos crate, set from the config's log_level.  The
+// logging macros compare against a per-module level that the runtime reads
+// from RUST_LOG when the process starts, and keeps in the crate map; setting
+// a level rewrites that of every module of this crate, and leaves other
+// crates' as RUST_LOG set them.
+
+static LEVELS: &'static [(&'static str, u32)] = &[
+    ("error", ERROR),
+    ("warn", WARN),
+    ("info", INFO),
+    ("debug", DEBUG),
+];
+
+static CRATE: &'static str = "paxos";
+
+// The level with that name, if there's one
+pub fn parse(name: &str) -> Option<u32> {
+    LEVELS.iter().find(|&&(n, _)| n == name).map(|&(_, level)| level)
+}
+
+pub fn name(level: u32) -> &'static str {
+    match LEVELS.iter().find(|&&(_, l)| l == level) {
+        Some(&(n, _)) => n,
+        None => "unknown",
+    }
+}
+
+// Makes the crate log messages up to the level from now on
+pub fn set(level: u32) {
+    let crate_map = match get_crate_map() {
+        Some(crate_map) => crate_map,
+        None => {
+            warn!("Can't set the log level to {}: there's no crate map", name(level));
+            return;
+        },
+    };
+    iter_crate_map(crate_map, |entry| {
+        let ours = entry.name.starts_with(CRATE) &&
+            (entry.name.len() == CRATE.len() || entry.name.slice_from(CRATE.len()).starts_with("::"));
+        if ours {
+            // Other tasks may be reading the level as it changes; a u32 is
+            // written in one go, so they see either the old or the new one
+            unsafe { *entry.log_level = level; }
+        }
+    });
+}
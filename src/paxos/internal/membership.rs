@@ -0,0 +1,93 @@
+use extra::arc::{Arc, MutexArc};
+
+use super::replica::ReplicaID;
+use super::transport::Address;
+
+// Where every replica of the cluster can be reached, indexed by ID.  Filled
+// in from the config, and completed by gossip (see gossip.rs) if it's on.
+// Connectors look a peer up before every attempt, so a peer that moves or is
+// only discovered later is picked up on the next try.  Addresses the config
+// gives are fixed; gossip only fills in and updates those it left out.
+#[deriving(Clone)]
+pub struct AddressBook {
+    priv addrs: MutexArc<~[Option<Address>]>,
+    // Whether the config gives each replica's address
+    priv fixed: Arc<~[bool]>,
+}
+
+impl AddressBook {
+    pub fn new(addrs: ~[Option<Address>]) -> AddressBook {
+        let fixed = addrs.iter().map(|a| a.is_some()).collect();
+        AddressBook{
+            addrs: MutexArc::new(addrs),
+            fixed: Arc::new(fixed),
+        }
+    }
+
+    // The number of replicas in the cluster, whether or not we know where
+    // they are
+    pub fn len(&self) -> uint {
+        self.addrs.access(|a| a.len())
+    }
+
+    // None if we don't know where the replica is, or it isn't in the cluster
+    pub fn get(&self, id: ReplicaID) -> Option<Address> {
+        self.addrs.access(|a| if id < a.len() { a[id].clone() } else { None })
+    }
+
+    // Records where a replica is, unless the config says where it is.
+    // Returns whether that's news.
+    pub fn set(&self, id: ReplicaID, addr: Address) -> bool {
+        let fixed = self.fixed.get();
+        self.addrs.access(|a| {
+            if id >= a.len() || fixed[id] || a[id] == Some(addr.clone()) {
+                false
+            } else {
+                a[id] = Some(addr.clone());
+                true
+            }
+        })
+    }
+}
+
+#[deriving(Clone, Eq, ToStr)]
+pub enum MemberState {
+    Alive,
+    // Failed to answer a probe; dead unless it refutes the suspicion in time
+    Suspect,
+    Dead,
+}
+
+// What gossip knows about a replica.  The incarnation starts at that of the
+// replica's run (see next_incarnation in replica.rs), and is bumped by the
+// replica itself to refute suspicions about it.
+#[deriving(Clone, Eq, ToStr)]
+pub struct Member {
+    id: ReplicaID,
+    // Where the replica talks Paxos
+    address: Address,
+    // Where the replica gossips
+    gossip_address: Address,
+    incarnation: u64,
+    state: MemberState,
+}
+
+impl Member {
+    // Whether news about a member should replace what we knew.  Higher
+    // incarnations always win; at the same incarnation, Suspect beats Alive
+    // and Dead beats both.
+    pub fn supersedes(&self, old: &Member) -> bool {
+        if self.incarnation != old.incarnation {
+            return self.incarnation > old.incarnation;
+        }
+        rank(&self.state) > rank(&old.state)
+    }
+}
+
+fn rank(state: &MemberState) -> uint {
+    match *state {
+        Alive => 0,
+        Suspect => 1,
+        Dead => 2,
+    }
+}
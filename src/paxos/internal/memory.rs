@@ -0,0 +1,199 @@
+use std::cmp;
+use std::hashmap::HashMap;
+use std::io::{io_error, standard_error, EndOfFile, BrokenPipe};
+use std::io::net::ip::SocketAddr;
+
+use super::transport::{Address, InetAddress, HostAddress, UnixAddress, Transport, TransportStream,
+    TransportListener};
+
+// A transport that connects replicas running in the same process through
+// channels, so that a whole cluster can run inside one test binary without
+// using any ports.  Addresses are only used as names; replicas that should
+// see each other must be given clones of the same MemoryTransport.  Listening
+// at port 0 picks an unused port, as it would with TCP.
+#[deriving(Clone)]
+pub struct MemoryTransport {
+    priv switchboard: SharedChan<SwitchboardRequest>,
+}
+
+enum SwitchboardRequest {
+    // Replies with where the listener ended up, if the address was free
+    Listen(Address, SharedChan<MemoryStream>, Chan<Option<Address>>),
+    // Replies with our end of the connection, if anyone is listening
+    Connect(Address, Chan<Option<MemoryStream>>),
+}
+
+impl MemoryTransport {
+    pub fn new() -> MemoryTransport {
+        let (port, chan) = SharedChan::new();
+        do spawn { run_switchboard(port) };
+        MemoryTransport{ switchboard: chan }
+    }
+}
+
+// Where ports picked for listeners at port 0 start
+static FIRST_PICKED_PORT: u16 = 49152;
+
+// Keeps track of who is listening where
+fn run_switchboard(requests: Port<SwitchboardRequest>) {
+    let mut listeners: HashMap<Address, SharedChan<MemoryStream>> = HashMap::new();
+    let mut next_port = FIRST_PICKED_PORT;
+    loop {
+        match requests.recv_opt() {
+            Some(Listen(addr, chan, reply)) => {
+                let mut addr = addr;
+                if port_of(&addr) == Some(0) {
+                    loop {
+                        let picked = with_port(&addr, next_port);
+                        next_port = if next_port == 0xffff { FIRST_PICKED_PORT } else { next_port + 1 };
+                        if !listeners.contains_key(&picked) {
+                            addr = picked;
+                            break;
+                        }
+                    }
+                }
+                if listeners.contains_key(&addr) {
+                    reply.send(None);
+                } else {
+                    listeners.insert(addr.clone(), chan);
+                    reply.send(Some(addr));
+                }
+            },
+            Some(Connect(addr, reply)) => {
+                let (ours, theirs) = MemoryStream::pair();
+                let connected = match listeners.find(&addr) {
+                    Some(chan) => chan.try_send(theirs),
+                    None => false,
+                };
+                if connected {
+                    reply.send(Some(ours));
+                } else {
+                    // The listener may have gone away
+                    listeners.remove(&addr);
+                    reply.send(None);
+                }
+            },
+            // Every MemoryTransport has been dropped
+            None => return,
+        }
+    }
+}
+
+fn port_of(addr: &Address) -> Option<u16> {
+    match *addr {
+        InetAddress(inet) => Some(inet.port),
+        HostAddress(_, port) => Some(port),
+        UnixAddress(..) => None,
+    }
+}
+
+fn with_port(addr: &Address, port: u16) -> Address {
+    match *addr {
+        InetAddress(inet) => InetAddress(SocketAddr{ ip: inet.ip, port: port }),
+        HostAddress(ref host, _) => HostAddress(host.clone(), port),
+        UnixAddress(ref path) => UnixAddress(path.clone()),
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn connect(&self, addr: &Address) -> Option<~TransportStream> {
+        let (port, chan) = Chan::new();
+        self.switchboard.send(Connect(addr.clone(), chan));
+        port.recv().map(|s| ~s as ~TransportStream)
+    }
+
+    fn listen(&self, addr: &Address) -> Option<~TransportListener> {
+        let (port, chan) = SharedChan::new();
+        let (reply_port, reply_chan) = Chan::new();
+        self.switchboard.send(Listen(addr.clone(), chan, reply_chan));
+        match reply_port.recv() {
+            Some(bound) => Some(~MemoryListener{ port: port, address: bound } as ~TransportListener),
+            None => {
+                error!("Failed to listen on {}: address in use", addr.to_str());
+                None
+            },
+        }
+    }
+}
+
+struct MemoryListener {
+    port: Port<MemoryStream>,
+    address: Address,
+}
+
+impl TransportListener for MemoryListener {
+    fn accept(&mut self) -> Option<~TransportStream> {
+        self.port.recv_opt().map(|s| ~s as ~TransportStream)
+    }
+
+    fn address(&self) -> Address {
+        self.address.clone()
+    }
+}
+
+// One end of an in-memory connection.  Every write is sent as one chunk
+// through a channel; reads hand out the chunks piece by piece.
+pub struct MemoryStream {
+    priv port: Port<~[u8]>,
+    priv chan: Chan<~[u8]>,
+    // The chunk being read and how far into it we are
+    priv buf: ~[u8],
+    priv pos: uint,
+    priv eof: bool,
+}
+
+impl MemoryStream {
+    pub fn pair() -> (MemoryStream, MemoryStream) {
+        let (port1, chan1) = Chan::new();
+        let (port2, chan2) = Chan::new();
+        (MemoryStream::new(port1, chan2), MemoryStream::new(port2, chan1))
+    }
+
+    fn new(port: Port<~[u8]>, chan: Chan<~[u8]>) -> MemoryStream {
+        MemoryStream{
+            port: port,
+            chan: chan,
+            buf: ~[],
+            pos: 0,
+            eof: false,
+        }
+    }
+}
+
+impl TransportStream for MemoryStream {}
+
+impl Reader for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> Option<uint> {
+        while self.pos == self.buf.len() {
+            match self.port.recv_opt() {
+                Some(chunk) => {
+                    self.buf = chunk;
+                    self.pos = 0;
+                },
+                None => {
+                    self.eof = true;
+                    io_error::cond.raise(standard_error(EndOfFile));
+                    return None;
+                },
+            }
+        }
+        let end = cmp::min(self.buf.len(), self.pos + buf.len());
+        let n = buf.copy_from(self.buf.slice(self.pos, end));
+        self.pos += n;
+        Some(n)
+    }
+
+    fn eof(&mut self) -> bool {
+        self.eof
+    }
+}
+
+impl Writer for MemoryStream {
+    fn write(&mut self, buf: &[u8]) {
+        if !self.chan.try_send(buf.to_owned()) {
+            io_error::cond.raise(standard_error(BrokenPipe));
+        }
+    }
+
+    fn flush(&mut self) {}
+}
@@ -1,15 +1,44 @@
 use super::replica::ReplicaID;
 use super::instance::{InstanceID, SequenceID};
+use super::membership::Member;
+use super::client::ClusterStatus;
 
-#[deriving(Clone, Encodable, Decodable, ToStr)]
+#[deriving(Clone, ToStr)]
 pub enum Message {
     NetworkM(NetworkMessage),
     PaxosM(PaxosMessage),
+    GossipM(GossipMessage),
+    ClientM(ClientMessage),
+}
+
+#[deriving(Clone, Encodable, Decodable, ToStr)]
+pub enum NetworkMessage {
+    // Sent by both sides when a connection is established.  The side that
+    // initiated the connection speaks first.
+    Hello(Handshake),
+    // Sent instead of a Hello when the other side's Hello is unacceptable
+    Reject(~str),
+    // Sent periodically by both sides from protocol version 2 on, carrying
+    // the sender's clock in nanoseconds
+    Heartbeat(u64),
+    // Echoes the clock of a Heartbeat, so that the sender can tell the
+    // round-trip time
+    HeartbeatAck(u64),
+    // Sent by both sides after the Hellos from protocol version 3 on,
+    // carrying the sender's stable ID from the config
+    Identify(~str),
+    // Sent by both sides after the Identify messages from protocol version 5
+    // on, carrying the nonce the sender picked for the connection
+    Nonce(u64),
 }
 
 #[deriving(Clone, Encodable, Decodable, ToStr)]
-pub struct NetworkMessage {
-    replica_id: ReplicaID
+pub struct Handshake {
+    cluster_id: ~str,
+    // The range of protocol versions the replica can speak
+    min_version: u8,
+    max_version: u8,
+    replica_id: ReplicaID,
 }
 
 #[deriving(Clone, Encodable, Decodable, ToStr)]
@@ -33,4 +62,45 @@ pub enum PaxosMessageContent {
 pub struct PaxosMessage {
     instance_id: InstanceID,
     content: PaxosMessageContent,
-}
\ No newline at end of file
+}
+
+// Gossip travels on connections of its own, one message each way; see
+// gossip.rs
+#[deriving(Clone, ToStr)]
+pub struct GossipMessage {
+    cluster_id: ~str,
+    // Matches an Ack to the Ping or PingReq it answers
+    seq: u64,
+    kind: GossipKind,
+    // Membership news piggybacked on the message
+    updates: ~[Member],
+}
+
+#[deriving(Clone, ToStr)]
+pub enum GossipKind {
+    Ping,
+    // Asks the receiver to ping a replica for us, at the gossip address the
+    // receiver knows for it
+    PingReq(ReplicaID),
+    Ack,
+}
+
+// Spoken between clients and a replica's client port, one request and one
+// response at a time; see client.rs
+#[deriving(Clone, ToStr)]
+pub enum ClientMessage {
+    // Requests
+    SubmitRequest(~[u8]),
+    GetRequest(InstanceID),
+    StatusRequest,
+    // The instance that decided a submitted command, and the command's
+    // position in it
+    Submitted(InstanceID, uint),
+    // The commands an instance decided
+    Decided(InstanceID, ~[~[u8]]),
+    // The replica doesn't know what the instance decided, if anything
+    Undecided(InstanceID),
+    StatusReply(ClusterStatus),
+    // The request couldn't be carried out, and why
+    Failure(~str),
+}
@@ -0,0 +1,78 @@
+use std::sync::arc::UnsafeArc;
+use std::unstable::atomics::{AtomicUint, SeqCst};
+
+// Upper bounds of the batch size buckets; the last bucket holds everything
+// larger
+pub static BATCH_SIZE_BUCKETS: [uint, ..5] = [1, 4, 16, 64, 256];
+
+// Counters a communicator updates as it writes batches to its peer, and that
+// the replica reads to report on them.  Clones share the same counters, so
+// the communicator and the replica each hold one.
+#[deriving(Clone)]
+pub struct BatchMetrics {
+    priv counters: UnsafeArc<Counters>,
+}
+
+struct Counters {
+    batches: AtomicUint,
+    messages: AtomicUint,
+    histogram: [AtomicUint, ..6],
+}
+
+// A snapshot of a BatchMetrics
+#[deriving(Clone, ToStr)]
+pub struct BatchStats {
+    batches: uint,
+    messages: uint,
+    // Number of batches in each bucket of BATCH_SIZE_BUCKETS, plus one for
+    // batches larger than the last bucket
+    histogram: ~[uint],
+}
+
+impl BatchMetrics {
+    pub fn new() -> BatchMetrics {
+        BatchMetrics{
+            counters: UnsafeArc::new(Counters{
+                batches: AtomicUint::new(0),
+                messages: AtomicUint::new(0),
+                histogram: [AtomicUint::new(0), AtomicUint::new(0), AtomicUint::new(0),
+                            AtomicUint::new(0), AtomicUint::new(0), AtomicUint::new(0)],
+            }),
+        }
+    }
+
+    pub fn record_batch(&self, size: uint) {
+        // Every counter is an atomic, so concurrent updates and snapshots
+        // are safe
+        let counters = unsafe { &mut *self.counters.get() };
+        counters.batches.fetch_add(1, SeqCst);
+        counters.messages.fetch_add(size, SeqCst);
+        let mut bucket = BATCH_SIZE_BUCKETS.len();
+        for (i, bound) in BATCH_SIZE_BUCKETS.iter().enumerate() {
+            if size <= *bound {
+                bucket = i;
+                break;
+            }
+        }
+        counters.histogram[bucket].fetch_add(1, SeqCst);
+    }
+
+    pub fn snapshot(&self) -> BatchStats {
+        let counters = unsafe { &*self.counters.get_immut() };
+        BatchStats{
+            batches: counters.batches.load(SeqCst),
+            messages: counters.messages.load(SeqCst),
+            histogram: counters.histogram.iter().map(|n| n.load(SeqCst)).collect(),
+        }
+    }
+}
+
+impl BatchStats {
+    pub fn mean_batch_size(&self) -> f64 {
+        if self.batches == 0 {
+            0.0
+        } else {
+            self.messages as f64 / self.batches as f64
+        }
+    }
+}
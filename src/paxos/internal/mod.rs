@@ -2,5 +2,24 @@ mod message;
 mod communicator;
 mod instance;
 mod connection_handler;
+mod backoff;
+mod wire;
+mod handshake;
+mod tls;
+mod auth;
+mod queue;
+mod dispatcher;
+mod window;
+mod gossip;
+mod toml;
+mod log_level;
 
 pub mod replica;
+pub mod transport;
+pub mod memory;
+pub mod metrics;
+pub mod batcher;
+pub mod health;
+pub mod membership;
+pub mod config;
+pub mod client;
@@ -0,0 +1,217 @@
+use extra::ringbuf::RingBuf;
+use extra::container::Deque;
+
+use super::message::{PaxosMessage, Propose, Request};
+
+// Bounds on how much a communicator buffers for its peer
+#[deriving(Clone, Eq)]
+pub struct QueueLimits {
+    max_messages: uint,
+    max_bytes: uint,
+}
+
+pub static DEFAULT_QUEUE_LIMITS: QueueLimits = QueueLimits{
+    max_messages: 4096,
+    max_bytes: 64 * 1024 * 1024,
+};
+
+// How a communicator coalesces queued messages into a single write
+#[deriving(Clone, Eq)]
+pub struct Batching {
+    // The most messages written out with one flush
+    max_batch: uint,
+    // How long (in milliseconds) to wait for more messages before writing out
+    // a batch that isn't full.  0 writes out whatever is queued right away.
+    linger: u64,
+}
+
+pub static DEFAULT_BATCHING: Batching = Batching{
+    max_batch: 64,
+    linger: 0,
+};
+
+// A message waiting to be sent, along with the number of connections it has
+// already failed to go out on
+pub struct Outbound {
+    msg: PaxosMessage,
+    attempts: uint,
+}
+
+// The messages a communicator has yet to send to its peer.  Messages that
+// move existing instances forward (promises, accepts, commits...) go out
+// before Proposes, which only start new ones.  The queue itself never refuses
+// a message; instead, the communicator stops taking messages from instances
+// while it is full, and the replica stops starting instances while a quorum
+// of queues is full.
+pub struct OutboundQueue {
+    priv limits: QueueLimits,
+    priv critical: RingBuf<Outbound>,
+    priv normal: RingBuf<Outbound>,
+    priv bytes: uint,
+}
+
+impl OutboundQueue {
+    pub fn new(limits: QueueLimits) -> OutboundQueue {
+        OutboundQueue{
+            limits: limits,
+            critical: RingBuf::new(),
+            normal: RingBuf::new(),
+            bytes: 0,
+        }
+    }
+
+    // Changes the limits.  A queue that's over the new ones just counts as
+    // full until it drains; nothing is dropped.
+    pub fn set_limits(&mut self, limits: QueueLimits) {
+        self.limits = limits;
+    }
+
+    pub fn len(&self) -> uint {
+        self.critical.len() + self.normal.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.limits.max_messages || self.bytes >= self.limits.max_bytes
+    }
+
+    pub fn push(&mut self, msg: PaxosMessage) {
+        self.push_back(Outbound{ msg: msg, attempts: 0 });
+    }
+
+    pub fn push_back(&mut self, entry: Outbound) {
+        self.bytes += size_of(&entry.msg);
+        if is_critical(&entry.msg) {
+            self.critical.push_back(entry);
+        } else {
+            self.normal.push_back(entry);
+        }
+    }
+
+    // Puts back a message that failed to go out, so that it's the next one
+    // to be retried
+    pub fn push_front(&mut self, entry: Outbound) {
+        self.bytes += size_of(&entry.msg);
+        if is_critical(&entry.msg) {
+            self.critical.push_front(entry);
+        } else {
+            self.normal.push_front(entry);
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<Outbound> {
+        let entry = match self.critical.pop_front() {
+            Some(entry) => Some(entry),
+            None => self.normal.pop_front(),
+        };
+        for entry in entry.iter() {
+            self.bytes -= size_of(&entry.msg);
+        }
+        entry
+    }
+}
+
+fn is_critical(msg: &PaxosMessage) -> bool {
+    match msg.content {
+        Propose(..) => false,
+        _ => true,
+    }
+}
+
+// Roughly how much memory a queued message takes
+fn size_of(msg: &PaxosMessage) -> uint {
+    static OVERHEAD: uint = 64;
+    match msg.content {
+        Request(_, ref value) => OVERHEAD + value.len(),
+        _ => OVERHEAD,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::vec;
+
+    use super::super::message::{PaxosMessage, PaxosMessageContent, Propose, Request, Commit, Acknowledge};
+    use super::{OutboundQueue, QueueLimits, Outbound};
+
+    static LIMITS: QueueLimits = QueueLimits{
+        max_messages: 4,
+        max_bytes: 1024,
+    };
+
+    fn message(n: u64, content: PaxosMessageContent) -> PaxosMessage {
+        PaxosMessage{
+            instance_id: (0, n),
+            content: content,
+        }
+    }
+
+    fn instance_ids(queue: &mut OutboundQueue) -> ~[u64] {
+        let mut ids = ~[];
+        loop {
+            match queue.pop() {
+                Some(entry) => {
+                    let (_, n) = entry.msg.instance_id;
+                    ids.push(n);
+                },
+                None => return ids,
+            }
+        }
+    }
+
+    #[test]
+    fn test_commits_and_acknowledgements_go_before_proposes() {
+        let mut queue = OutboundQueue::new(LIMITS);
+        queue.push(message(0, Propose((1, 0))));
+        queue.push(message(1, Commit((1, 0))));
+        queue.push(message(2, Propose((1, 0))));
+        queue.push(message(3, Acknowledge((1, 0))));
+        assert_eq!(instance_ids(&mut queue), ~[1, 3, 0, 2]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_full_at_the_message_limit() {
+        let mut queue = OutboundQueue::new(LIMITS);
+        for n in range(0u64, 3) {
+            queue.push(message(n, Propose((1, 0))));
+            assert!(!queue.is_full());
+        }
+        queue.push(message(3, Propose((1, 0))));
+        assert!(queue.is_full());
+        queue.pop();
+        assert!(!queue.is_full());
+    }
+
+    #[test]
+    fn test_full_at_the_byte_limit() {
+        let mut queue = OutboundQueue::new(LIMITS);
+        queue.push(message(0, Request((1, 0), vec::from_elem(512, 0u8))));
+        assert!(!queue.is_full());
+        queue.push(message(1, Request((1, 0), vec::from_elem(512, 0u8))));
+        assert!(queue.is_full());
+        assert_eq!(queue.len(), 2);
+        queue.pop();
+        assert!(!queue.is_full());
+    }
+
+    #[test]
+    fn test_retransmissions_keep_their_order() {
+        let mut queue = OutboundQueue::new(LIMITS);
+        for n in range(0u64, 4) {
+            queue.push(message(n, Commit((1, 0))));
+        }
+        let mut batch = ~[];
+        for _ in range(0, 3) {
+            batch.push(queue.pop().unwrap());
+        }
+        // As the communicator puts back a batch that failed to go out
+        for entry in batch.move_rev_iter() {
+            queue.push_front(Outbound{ msg: entry.msg, attempts: entry.attempts + 1 });
+        }
+        assert_eq!(instance_ids(&mut queue), ~[0, 1, 2, 3]);
+    }
+}
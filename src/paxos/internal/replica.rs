@@ -1,118 +1,939 @@
-use std::io::net::ip::SocketAddr;
+use std::vec;
+use std::sync::arc::UnsafeArc;
+use std::unstable::atomics::{AtomicBool, AtomicUint, SeqCst};
+use std::io::io_error;
+use std::io::fs::File;
+use std::io::timer::sleep;
 
-use extra::json;
-use extra::json::{Object, List, Number, String};
-use extra::comm::DuplexStream;
+use extra::time;
+use extra::time::precise_time_ns;
+use extra::arc::MutexArc;
 
 use super::connection_handler::ConnectionHandler;
-use super::communicator::Communicator;
-use super::instance::{Instance, InstanceID, increment_iid};
-use super::message::PaxosMessageContent;
+use super::communicator::{Communicator, CommunicatorSettings};
+use super::instance::{Instance, InstanceID, Peer, first_instance};
+use super::dispatcher::{Dispatcher, DispatcherMessage, Started, Subscribe, spawn_instance};
+use super::batcher::{Batcher, BatchLimits, Command, CommandResult, Decision, submit_command,
+    encode_commands};
+use super::transport::{Address, Transport, TransportListener, SocketTransport};
+use super::metrics::{BatchMetrics, BatchStats};
+use super::window::InFlightWindow;
+use super::health::{HealthMonitor, PeerHealth};
+use super::membership::{AddressBook, Member, Alive};
+use super::gossip::Gossip;
+use super::client::ClientServer;
+use super::tls::TlsContext;
+use super::log_level;
+use super::config::{ReplicaConfig, ConfigError, ReloadReport, Unreadable, InvalidField, ListenFailed};
 
 pub type ReplicaID = uint;
 
+// How often drain checks whether the instances are done, in milliseconds
+static DRAIN_POLL_INTERVAL: u64 = 10;
+
+// Where in its data_dir a replica records its last incarnation
+static INCARNATION_FILE: &'static str = "incarnation";
+// Where in its data_dir a replica records the stable IDs of the cluster
+static MEMBERS_FILE: &'static str = "members";
+
+#[deriving(Clone, ToStr)]
+pub enum SubmitError {
+    // Too many peers can't keep up with the messages we send them
+    Congested,
+    // The instance stopped before it committed
+    Abandoned,
+    // As many instances as the window allows are already in flight
+    Busy,
+}
+
 pub struct Replica {
     N: uint,
     id: ReplicaID,
-    instance_id: InstanceID,
-    address: SocketAddr,
-    peer_addrs: ~[SocketAddr],
-    peer_chans: ~[SharedChan<(InstanceID, DuplexStream<PaxosMessageContent, PaxosMessageContent>)>]
+    address: Address,
+    // Where the replica actually listens for peers
+    local_address: Address,
+    // The stable ID of every replica, from the config
+    stable_ids: ~[~str],
+    // Where the replica listens for clients, if it does
+    client_address: Option<Address>,
+    peer_addrs: AddressBook,
+    dispatcher: SharedChan<DispatcherMessage>,
+    proposer: Proposer,
+    // Where commands go to be batched, if proposal batching is on
+    batcher: Option<SharedChan<Command>>,
+    // How the communicator for each peer has been batching its writes
+    batch_metrics: ~[(ReplicaID, BatchMetrics)],
+    // The failure detector for each peer
+    health: ~[HealthMonitor],
+    // What gossip knows of the other replicas, if gossip is on
+    members: Option<MutexArc<~[Member]>>,
+    // The config in effect, and the file it came from, if any
+    config: ReplicaConfig,
+    config_path: Option<Path>,
+    // Where each communicator and the batcher take new settings
+    communicator_settings: ~[Chan<CommunicatorSettings>],
+    batcher_settings: Option<Chan<BatchLimits>>,
 }
 
+// The settings that a reload changes on a running replica.  Changes to any
+// other field are refused until the replica is restarted.
+static LIVE_FIELDS: &'static [&'static str] = &["send_queue", "batching", "proposal_batching",
+    "max_in_flight", "failure_detector", "log_level"];
+
 impl Replica {
-    pub fn new<T: Reader>(config: &mut T) -> Replica {
-        debug!("Creating replica");
+    // Creates a replica that talks to its peers over TCP or Unix domain
+    // sockets, depending on their addresses
+    pub fn new<R: Reader>(config: &mut R) -> Result<Replica, ConfigError> {
+        Replica::with_transport(config, SocketTransport)
+    }
 
-        macro_rules! take_or_fail(($val:expr, $ok:pat => $out:expr) => {
-            match $val {
-                $ok => $out,
-                _ => fail!(~"malformed config")
-            }
-        })
-
-        // Parse config
-        let content = take_or_fail!(json::from_reader(config as &mut Reader), Ok(c) => c);
-        let mut obj = take_or_fail!(content, Object(obj) => obj);
-        let id = take_or_fail!(take_or_fail!(obj.pop(&~"id"), Some(t) => t), Number(n) => n as uint);
-
-        let addresses = take_or_fail!(take_or_fail!(obj.pop(&~"peers"), Some(t) => t), List(lst) => {
-            assert!(id < lst.len());
-            lst.move_iter().map(|p| {
-                let s = take_or_fail!(p, String(s) => s);
-                match from_str::<SocketAddr>(s) {
-                    Some(a) => a,
-                    None => fail!(~"malformed peer address")
-                }
-            })
-        });
+    // Reads the config at the path, JSON or TOML, with any PAXOS_* overrides
+    // from the environment; see ReplicaConfig::load
+    pub fn open(path: &Path) -> Result<Replica, ConfigError> {
+        match ReplicaConfig::load(path) {
+            Ok(config) => Replica::from_config(config, SocketTransport).map(|mut replica| {
+                replica.config_path = Some(path.clone());
+                replica
+            }),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn with_transport<R: Reader, T: Transport>(config: &mut R, transport: T)
+                                                   -> Result<Replica, ConfigError> {
+        match ReplicaConfig::from_json(config) {
+            Ok(config) => Replica::from_config(config, transport),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn from_config<T: Transport>(config: ReplicaConfig, transport: T) -> Result<Replica, ConfigError> {
+        match config.validate() {
+            Ok(()) => (),
+            Err(err) => return Err(err),
+        }
+        let tls = match load_tls(&config) {
+            Ok(tls) => tls,
+            Err(err) => return Err(err),
+        };
+        match check_members(&config) {
+            Ok(()) => (),
+            Err(err) => return Err(err),
+        }
+        let incarnation = match next_incarnation(&config) {
+            Ok(incarnation) => incarnation,
+            Err(err) => return Err(err),
+        };
+        // validate made sure we know our own address
+        let bind = config.bind.clone().unwrap_or(config.peers[config.id].clone().unwrap());
+        let listener = match transport.listen(&bind) {
+            Some(listener) => listener,
+            None => return Err(ListenFailed(bind.to_str())),
+        };
+        let clients = match listen_for_clients(&transport, &config) {
+            Ok(clients) => clients,
+            Err(err) => return Err(err),
+        };
+        match listen_for_gossip(&transport, &config) {
+            Ok(gossip) => Ok(Replica::start(config, incarnation, tls, transport, listener, clients, gossip)),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Starts a replica whose config has been validated, accepting peers'
+    // connections on the listener, clients' on the next one if there is one,
+    // and gossip on the last one if the config has a gossip section
+    fn start<T: Transport>(config: ReplicaConfig, incarnation: u64, tls: Option<TlsContext>,
+                           transport: T, listener: ~TransportListener,
+                           client_listener: Option<~TransportListener>,
+                           gossip_listener: Option<~TransportListener>) -> Replica {
+        debug!("Creating replica");
+        let running_config = config.clone();
+        let ReplicaConfig{
+            id, cluster_id, peers: addresses, cluster_key, send_queue: queue_limits,
+            batching, proposal_batching: batch_limits, max_in_flight, failure_detector: detector,
+            gossip, stable_ids, log_level: level, ..
+        } = config;
+        for &level in level.iter() {
+            log_level::set(level);
+        }
+        let local_address = listener.address();
+        let num_replicas = addresses.len();
 
-        let mut peers = ~[];
-        let mut chans = ~[];
         let mut my_address = None;
-        let mut tcp_request_streams = ~[];
+        let mut peer_links = ~[];
         let mut communicators = ~[];
-        for (i, address) in addresses.enumerate() {
-            let (port, chan) = SharedChan::new();
-            let (from_child, to_child) = DuplexStream::new();
-            tcp_request_streams.push(from_child);
-            chans.push(chan);
-            peers.push(address);
+        let mut outboxes = ~[];
+        let (dispatcher_port, dispatcher_chan) = SharedChan::new();
+        let mut congested = ~[];
+        let mut batch_metrics = ~[];
+        let mut health = ~[];
+        let mut communicator_settings = ~[];
+        for (i, address) in addresses.iter().enumerate() {
             if (i != id) {
+                let (conn_port, conn_chan) = Chan::new();
+                let (request_port, request_chan) = Chan::new();
+                let (outbound_port, outbound_chan) = SharedChan::new();
+                let flag = UnsafeArc::new(AtomicBool::new(false));
+                peer_links.push(Some((conn_chan, request_port)));
+                outboxes.push(Peer{ id: i, outbox: outbound_chan });
+                congested.push(flag.clone());
+                let metrics = BatchMetrics::new();
+                batch_metrics.push((i, metrics.clone()));
+                let monitor = HealthMonitor::new(i, detector.clone());
+                health.push(monitor.clone());
+                let (settings_port, settings_chan) = Chan::new();
+                communicator_settings.push(settings_chan);
                 let communicator = Communicator {
                     my_id: id,
                     peer_id: i,
-                    tcp_stream: to_child,
-                    message_stream_port: port,
-                    message_stream_chans: ~[],
+                    connections: Some(conn_port),
+                    connection_requests: request_chan,
+                    outbound: outbound_port,
+                    dispatcher: dispatcher_chan.clone(),
+                    queue_limits: queue_limits.clone(),
+                    batching: batching.clone(),
+                    metrics: metrics,
+                    detector: detector.clone(),
+                    health: monitor,
+                    settings: Some(settings_port),
+                    congested: flag,
                 };
                 communicators.push(communicator);
             } else {
-                my_address = Some(address);
+                peer_links.push(None);
+                my_address = address.clone();
             }
         }
 
-        for mut communicator in communicators.move_iter() {
-            communicator.message_stream_chans = chans.clone();
+        for communicator in communicators.move_iter() {
             do spawn { communicator.run() };
         }
 
+        let dispatcher = Dispatcher{
+            my_id: id,
+            port: dispatcher_port,
+            chan: dispatcher_chan.clone(),
+            peers: outboxes.clone(),
+        };
+        do spawn { dispatcher.run() };
+
+        // validate made sure we know our own address
         let my_address = my_address.unwrap();
+        let peer_addrs = AddressBook::new(addresses);
+
+        let members = match (gossip, gossip_listener) {
+            (Some(config), Some(gossip_listener)) => {
+                let view = MutexArc::new(~[]);
+                let gossip = Gossip{
+                    me: Member{
+                        id: id,
+                        address: my_address.clone(),
+                        gossip_address: config.address.clone(),
+                        // Higher than in any earlier run, so that news about a
+                        // restarted replica isn't taken for stale gossip
+                        incarnation: incarnation,
+                        state: Alive,
+                    },
+                    cluster_id: cluster_id.clone(),
+                    key: cluster_key.clone(),
+                    config: config,
+                    transport: transport.clone(),
+                    book: peer_addrs.clone(),
+                    gossip_book: AddressBook::new(vec::from_elem(num_replicas, None)),
+                    view: view.clone(),
+                };
+                do spawn { gossip.run(gossip_listener) };
+                Some(view)
+            },
+            _ => None,
+        };
+
+        let proposer = Proposer{
+            id: id,
+            first_instance: first_instance(incarnation),
+            started: UnsafeArc::new(AtomicUint::new(0)),
+            dispatcher: dispatcher_chan.clone(),
+            peers: outboxes,
+            congested: congested,
+            window: InFlightWindow::new(max_in_flight),
+        };
+
+        let mut batcher_settings = None;
+        let batcher = batch_limits.map(|limits| {
+            let (port, chan) = SharedChan::new();
+            let (settings_port, settings_chan) = Chan::new();
+            batcher_settings = Some(settings_chan);
+            let batcher = Batcher{
+                limits: limits,
+                commands: port,
+                settings: settings_port,
+                proposer: proposer.clone(),
+            };
+            do spawn { batcher.run() };
+            chan
+        });
+
+        let client_address = client_listener.map(|listener| {
+            let (decisions_port, decisions_chan) = Chan::new();
+            dispatcher_chan.send(Subscribe(decisions_chan));
+            let address = listener.address();
+            let server = ClientServer{
+                id: id,
+                cluster_id: cluster_id.clone(),
+                stable_ids: stable_ids.clone(),
+                peer_addrs: peer_addrs.clone(),
+                proposer: proposer.clone(),
+                batcher: batcher.clone(),
+                health: health.clone(),
+                decisions: decisions_port,
+                listener: listener,
+            };
+            do spawn { server.run() };
+            address
+        });
 
         let conn_handler = ConnectionHandler{ 
             id: id,
-            address: my_address.clone(),
-            tcp_request_streams: tcp_request_streams,
-            peer_addrs: peers.clone()
+            transport: transport,
+            cluster_id: cluster_id,
+            tls: tls,
+            cluster_key: cluster_key,
+            listener: listener,
+            peer_links: peer_links,
+            peer_addrs: peer_addrs.clone(),
+            stable_ids: stable_ids.clone(),
         };
         do spawn {
             conn_handler.run()
         };
 
         Replica{
-            N: peers.len(),
+            N: num_replicas,
             id: id,
-            instance_id: (id, 0),
             address: my_address,
-            peer_addrs: peers,
-            peer_chans: chans,
+            local_address: local_address,
+            stable_ids: stable_ids,
+            client_address: client_address,
+            peer_addrs: peer_addrs,
+            dispatcher: dispatcher_chan,
+            proposer: proposer,
+            batcher: batcher,
+            batch_metrics: batch_metrics,
+            health: health,
+            members: members,
+            config: running_config,
+            config_path: None,
+            communicator_settings: communicator_settings,
+            batcher_settings: batcher_settings,
+        }
+    }
+
+    // Reads the config file the replica was opened from again, along with
+    // the environment, and applies what changed; see reconfigure.  Meant to
+    // be called on SIGHUP or by an admin command.
+    pub fn reload(&mut self) -> Result<ReloadReport, ConfigError> {
+        let config = match self.config_path {
+            Some(ref path) => ReplicaConfig::load(path),
+            None => return Err(Unreadable(~"the replica wasn't opened from a config file")),
+        };
+        match config {
+            Ok(config) => self.reconfigure(config),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Applies the tunables of a new config (queue limits, batching, the
+    // window, the failure detector and the log level) to the running
+    // replica, without restarting anything.  Changes to membership,
+    // addresses, security settings and the like are refused and reported.
+    // If the new config, or the running one with the changes applied, is
+    // invalid, nothing changes.
+    pub fn reconfigure(&mut self, new: ReplicaConfig) -> Result<ReloadReport, ConfigError> {
+        match new.validate() {
+            Ok(()) => (),
+            Err(err) => return Err(err),
+        }
+        let mut report = ReloadReport{ applied: ~[], refused: ~[] };
+        let mut config = self.config.clone();
+        for &field in self.config.changed_fields(&new).iter() {
+            if !LIVE_FIELDS.contains(&field) {
+                report.refused.push((field.to_owned(), ~"needs a restart"));
+                continue;
+            }
+            match field {
+                "send_queue" => config.send_queue = new.send_queue.clone(),
+                "batching" => config.batching = new.batching.clone(),
+                "max_in_flight" => config.max_in_flight = new.max_in_flight,
+                "failure_detector" => config.failure_detector = new.failure_detector.clone(),
+                "log_level" => {
+                    // What RUST_LOG said is gone once the level has been set
+                    if new.log_level.is_none() {
+                        report.refused.push((field.to_owned(),
+                            ~"needs a restart to go back to RUST_LOG"));
+                        continue;
+                    }
+                    config.log_level = new.log_level;
+                },
+                _ => {
+                    // The batcher is only started with the replica
+                    if config.proposal_batching.is_none() || new.proposal_batching.is_none() {
+                        report.refused.push((field.to_owned(),
+                            ~"needs a restart to be turned on or off"));
+                        continue;
+                    }
+                    config.proposal_batching = new.proposal_batching.clone();
+                },
+            }
+            report.applied.push(field.to_owned());
+        }
+        match config.validate() {
+            Ok(()) => (),
+            Err(err) => return Err(err),
+        }
+
+        let settings = CommunicatorSettings{
+            queue_limits: config.send_queue.clone(),
+            batching: config.batching.clone(),
+            detector: config.failure_detector.clone(),
+        };
+        for chan in self.communicator_settings.iter() {
+            chan.try_send(settings.clone());
+        }
+        for chan in self.batcher_settings.iter() {
+            for limits in config.proposal_batching.iter() {
+                chan.try_send(limits.clone());
+            }
         }
+        self.proposer.window.set_limit(config.max_in_flight);
+        for &level in config.log_level.iter() {
+            log_level::set(level);
+        }
+        info!("Replica {} reloaded its config: applied {:?}, refused {:?}",
+            self.id, report.applied, report.refused);
+        self.config = config;
+        Ok(report)
+    }
+
+    // The stable ID of every replica as declared in the config, indexed by
+    // ReplicaID
+    pub fn stable_ids(&self) -> ~[~str] {
+        self.stable_ids.clone()
     }
 
-    pub fn submit(&mut self, value: ~[u8]) {
-        let mut peers = ~[];
-        for (idx, chan) in self.peer_chans.iter().enumerate() {
-            if (idx != self.id) {
-                let (from, to) = DuplexStream::new();
-                chan.send((self.instance_id, to));
-                peers.push(from);
+    // Where the replica listens for peers.  Tells which port was picked if
+    // the config asked for port 0.
+    pub fn local_address(&self) -> Address {
+        self.local_address.clone()
+    }
+
+    // Where the replica listens for clients, if the config gives it a
+    // client_address
+    pub fn client_address(&self) -> Option<Address> {
+        self.client_address.clone()
+    }
+
+    // Starts an instance to get the value decided on its own.  Fails if too
+    // many peers are backed up for the instance to reach a majority, or if
+    // the in-flight window is full.
+    pub fn submit(&mut self, value: ~[u8]) -> Result<InstanceID, SubmitError> {
+        self.proposer.try_propose(encode_commands([value.as_slice()])).map(|(iid, _)| iid)
+    }
+
+    // Like submit, but waits for room in the in-flight window instead of
+    // failing with Busy
+    pub fn submit_wait(&mut self, value: ~[u8]) -> Result<InstanceID, SubmitError> {
+        self.proposer.propose(encode_commands([value.as_slice()])).map(|(iid, _)| iid)
+    }
+
+    // How many of the instances we proposed are still running
+    pub fn in_flight(&self) -> uint {
+        self.proposer.window.in_flight()
+    }
+
+    // Waits up to timeout milliseconds for the instances this replica
+    // proposed to finish, e.g. before shutting down.  Returns whether they
+    // all did.
+    pub fn drain(&self, timeout: u64) -> bool {
+        let deadline = precise_time_ns() + timeout * 1000000;
+        while self.in_flight() > 0 {
+            if precise_time_ns() >= deadline {
+                return false;
             }
+            sleep(DRAIN_POLL_INTERVAL);
         }
+        true
+    }
+
+    // Gets the command decided, batched with others if proposal batching is
+    // on.  The port says which instance decided the command and where in the
+    // instance's batch it is, once the instance is done.  Without batching,
+    // this waits for room in the in-flight window.
+    pub fn submit_command(&mut self, value: ~[u8]) -> Port<CommandResult> {
+        submit_command(&self.proposer, &self.batcher, value)
+    }
 
-        let instance = Instance::new_as_proposer(self.id, self.instance_id, value);
-        let peers = peers;
-        do spawn { instance.run(peers); }
+    // Returns a port that receives the commands of every instance this
+    // replica sees committed from now on
+    pub fn decisions(&self) -> Port<Decision> {
+        let (port, chan) = Chan::new();
+        self.dispatcher.send(Subscribe(chan));
+        port
+    }
 
-        self.instance_id = increment_iid(self.instance_id);
+    // How every peer looks to the failure detector
+    pub fn peer_health(&self) -> ~[PeerHealth] {
+        let now = precise_time_ns();
+        self.health.iter().map(|h| h.snapshot(now)).collect()
     }
-}
\ No newline at end of file
+
+    // What gossip knows of the other replicas, or None if gossip is off
+    pub fn members(&self) -> Option<~[Member]> {
+        self.members.as_ref().map(|view| view.access(|v| v.clone()))
+    }
+
+    // How many messages each peer's communicator has written per flush
+    pub fn batch_stats(&self) -> ~[(ReplicaID, BatchStats)] {
+        self.batch_metrics.iter().map(|&(id, ref metrics)| {
+            (id, metrics.snapshot())
+        }).collect()
+    }
+}
+
+// Starts a replica in two steps, for when its address isn't known until it
+// listens, as in tests that listen at port 0: bind every replica of the
+// cluster, collect their addresses into the list of peers, then start each
+// one with a config listing them.
+pub struct ReplicaBuilder<T> {
+    priv transport: T,
+    priv listener: ~TransportListener,
+}
+
+impl<T: Transport> ReplicaBuilder<T> {
+    pub fn bind(transport: T, addr: &Address) -> Result<ReplicaBuilder<T>, ConfigError> {
+        match transport.listen(addr) {
+            Some(listener) => Ok(ReplicaBuilder{ transport: transport, listener: listener }),
+            None => Err(ListenFailed(addr.to_str())),
+        }
+    }
+
+    // Where the replica will be listening
+    pub fn address(&self) -> Address {
+        self.listener.address()
+    }
+
+    // Starts the replica.  Its own entry in the config's peers should be
+    // address(), or a name that reaches it; the config's bind is ignored,
+    // since the replica is already listening.
+    pub fn start(self, config: ReplicaConfig) -> Result<Replica, ConfigError> {
+        match config.validate() {
+            Ok(()) => (),
+            Err(err) => return Err(err),
+        }
+        let tls = match load_tls(&config) {
+            Ok(tls) => tls,
+            Err(err) => return Err(err),
+        };
+        match check_members(&config) {
+            Ok(()) => (),
+            Err(err) => return Err(err),
+        }
+        let incarnation = match next_incarnation(&config) {
+            Ok(incarnation) => incarnation,
+            Err(err) => return Err(err),
+        };
+        let ReplicaBuilder{ transport, listener } = self;
+        let clients = match listen_for_clients(&transport, &config) {
+            Ok(clients) => clients,
+            Err(err) => return Err(err),
+        };
+        match listen_for_gossip(&transport, &config) {
+            Ok(gossip) => Ok(Replica::start(config, incarnation, tls, transport, listener, clients, gossip)),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+// A ReplicaID is a replica's place in the sorted stable IDs, and it's in
+// every instance number and ballot, so all replicas must agree on it.  A new
+// ID that sorts before existing ones, or a removed one, would shift the
+// others', so if the replica has a data_dir, the IDs of its last start are
+// recorded there and a config that gives any of them another ReplicaID is
+// refused.  Adding IDs that sort after the existing ones, or dropping the
+// last ones, is fine.
+fn check_members(config: &ReplicaConfig) -> Result<(), ConfigError> {
+    let path = match config.data_dir {
+        Some(ref dir) => dir.join(MEMBERS_FILE),
+        None => return Ok(()),
+    };
+    let mut failure = None;
+    let moved = io_error::cond.trap(|err| {
+        failure = Some(err.to_str());
+    }).inside(|| {
+        let recorded = if path.exists() {
+            File::open(&path).map_default(~"", |mut f| f.read_to_str())
+        } else {
+            ~""
+        };
+        for (old, id) in recorded.lines().map(|l| l.trim()).filter(|l| !l.is_empty()).enumerate() {
+            match config.stable_ids.iter().position(|s| s.as_slice() == id) {
+                Some(new) if new != old => return Some(format!(
+                    "replica {} would move from ReplicaID {} to {}; its ID and those of the \
+                     other replicas must keep their order (or remove {} to start over)",
+                    id, old, new, path.display())),
+                _ => (),
+            }
+        }
+        File::create(&path).map(|mut f| {
+            for id in config.stable_ids.iter() {
+                f.write(id.as_bytes());
+                f.write(bytes!("\n"));
+            }
+            f.fsync();
+        });
+        None
+    });
+    match (failure, moved) {
+        (None, None) => Ok(()),
+        (Some(err), _) => Err(InvalidField(~"data_dir",
+            format!("can't record the replicas in {}: {}", path.display(), err))),
+        (None, Some(reason)) => Err(InvalidField(~"peers", reason)),
+    }
+}
+
+// Picks an incarnation for the replica that's higher than that of any of its
+// earlier runs; see first_instance.  It's the wall clock in seconds, or if
+// the replica has a data_dir, one more than the incarnation recorded there
+// when that's higher, so restarts within a second or after the clock went
+// back are covered as well.
+fn next_incarnation(config: &ReplicaConfig) -> Result<u64, ConfigError> {
+    let now = time::get_time().sec as u64;
+    let path = match config.data_dir {
+        Some(ref dir) => dir.join(INCARNATION_FILE),
+        None => return Ok(now),
+    };
+    let mut failure = None;
+    let incarnation = io_error::cond.trap(|err| {
+        failure = Some(err.to_str());
+    }).inside(|| {
+        let last = if path.exists() {
+            File::open(&path).map(|mut f| f.read_to_str())
+        } else {
+            None
+        };
+        let last = match last {
+            Some(s) => match from_str::<u64>(s.trim()) {
+                Some(n) => n,
+                None => return None,
+            },
+            None => 0,
+        };
+        let incarnation = if last >= now { last + 1 } else { now };
+        File::create(&path).map(|mut f| {
+            f.write(incarnation.to_str().as_bytes());
+            f.fsync();
+        });
+        Some(incarnation)
+    });
+    match (failure, incarnation) {
+        (None, Some(incarnation)) => Ok(incarnation),
+        (Some(err), _) => Err(InvalidField(~"data_dir",
+            format!("can't record the incarnation in {}: {}", path.display(), err))),
+        (None, None) => Err(InvalidField(~"data_dir",
+            format!("{} doesn't hold an incarnation", path.display()))),
+    }
+}
+
+// Loads the certificates of the config's tls section, if it has one, into
+// the context every connection of the replica shares
+fn load_tls(config: &ReplicaConfig) -> Result<Option<TlsContext>, ConfigError> {
+    match config.tls {
+        Some(ref tls) => match TlsContext::new(tls) {
+            Ok(ctx) => Ok(Some(ctx)),
+            Err(err) => Err(InvalidField(~"tls", err)),
+        },
+        None => Ok(None),
+    }
+}
+
+// Listens at the config's client_address, if it has one
+fn listen_for_clients<T: Transport>(transport: &T, config: &ReplicaConfig)
+                                    -> Result<Option<~TransportListener>, ConfigError> {
+    match config.client_address {
+        Some(ref addr) => match transport.listen(addr) {
+            Some(listener) => Ok(Some(listener)),
+            None => Err(ListenFailed(addr.to_str())),
+        },
+        None => Ok(None),
+    }
+}
+
+// Listens for gossip, if the config has a gossip section
+fn listen_for_gossip<T: Transport>(transport: &T, config: &ReplicaConfig)
+                                   -> Result<Option<~TransportListener>, ConfigError> {
+    match config.gossip {
+        Some(ref gossip) => {
+            let bind = gossip.bind_address();
+            match transport.listen(&bind) {
+                Some(listener) => Ok(Some(listener)),
+                None => Err(ListenFailed(bind.to_str())),
+            }
+        },
+        None => Ok(None),
+    }
+}
+
+// Starts instances with this replica as the proposer.  The replica and its
+// batcher each have one.
+#[deriving(Clone)]
+pub struct Proposer {
+    id: ReplicaID,
+    // The number of the first instance of this incarnation
+    first_instance: u64,
+    // How many instances have been started, shared by every Proposer of the
+    // replica
+    started: UnsafeArc<AtomicUint>,
+    dispatcher: SharedChan<DispatcherMessage>,
+    peers: ~[Peer],
+    // Whether the communicator for each peer has a full outbound queue
+    congested: ~[UnsafeArc<AtomicBool>],
+    // Bounds the instances in flight, shared by every Proposer of the replica
+    window: InFlightWindow,
+}
+
+impl Proposer {
+    // Starts an instance to get the value decided, and returns it along with
+    // a port that says whether it committed.  Waits for room in the window
+    // first.  Fails if too many peers are backed up for the instance to reach
+    // a majority.
+    pub fn propose(&self, value: ~[u8]) -> Result<(InstanceID, Port<bool>), SubmitError> {
+        if !self.is_available() {
+            return Err(Congested);
+        }
+        self.window.acquire();
+        Ok(self.start(value))
+    }
+
+    // Like propose, but fails with Busy instead of waiting for room in the
+    // window
+    pub fn try_propose(&self, value: ~[u8]) -> Result<(InstanceID, Port<bool>), SubmitError> {
+        if !self.is_available() {
+            return Err(Congested);
+        }
+        if !self.window.try_acquire() {
+            return Err(Busy);
+        }
+        Ok(self.start(value))
+    }
+
+    // Whether enough peers are keeping up for an instance to reach a majority
+    fn is_available(&self) -> bool {
+        let mut available = 0;
+        for flag in self.congested.iter() {
+            if unsafe { !(*flag.get()).load(SeqCst) } {
+                available += 1;
+            }
+        }
+        available >= self.congested.len() / 2 + 1
+    }
+
+    // Starts an instance in a slot of the window that's already been taken
+    fn start(&self, value: ~[u8]) -> (InstanceID, Port<bool>) {
+        let n = unsafe { (*self.started.get()).fetch_add(1, SeqCst) };
+        let iid = (self.id, self.first_instance + n as u64);
+        let (port, inbox) = SharedChan::new();
+        self.dispatcher.send(Started(iid, inbox));
+
+        let instance = Instance::new_as_proposer(self.id, iid, value);
+        let done = spawn_instance(instance, port, self.peers.clone(), self.dispatcher.clone(),
+                                  Some(self.window.clone()));
+        (iid, done)
+    }
+}
+
+// These start whole clusters, whose tasks never finish and would keep the
+// test binary running after the other tests are done, so they're ignored by
+// default; see the README.
+#[cfg(test)]
+mod test {
+    use std::comm::Select;
+    use std::io::{io_error, standard_error, BrokenPipe};
+    use std::io::timer::Timer;
+    use std::sync::arc::UnsafeArc;
+    use std::unstable::atomics::{AtomicUint, SeqCst};
+
+    use super::super::memory::MemoryTransport;
+    use super::super::transport::{Address, Transport, TransportStream, TransportListener};
+    use super::super::config::ReplicaConfig;
+    use super::super::batcher::Decision;
+    use super::Replica;
+
+    // How long a decision may take, in milliseconds.  Proposers resend every
+    // second, so this leaves room for several rounds.
+    static TIMEOUT: u64 = 30000;
+
+    fn start_cluster<T: Transport>(transport: T) -> ~[Replica] {
+        let peers: ~[Address] = ["127.0.0.1:9000", "127.0.0.1:9001", "127.0.0.1:9002"].iter()
+            .map(|s| from_str::<Address>(*s).unwrap()).collect();
+        range(0, peers.len()).map(|id| {
+            let config = ReplicaConfig::new(id, peers.clone());
+            Replica::from_config(config, transport.clone()).unwrap()
+        }).collect()
+    }
+
+    // The next decision on the port, or None if it took longer than TIMEOUT
+    fn next_decision(decisions: &mut Port<Decision>) -> Option<Decision> {
+        let mut timer = Timer::new().unwrap();
+        let mut timeout = timer.oneshot(TIMEOUT);
+        let sel = Select::new();
+        let mut d = sel.add(decisions);
+        let _t = sel.add(&mut timeout);
+        if sel.wait() == d.id { d.recv_opt() } else { None }
+    }
+
+    // Submits the value to the first replica, and checks that every replica
+    // sees it decided
+    fn submit_and_check(replicas: &mut ~[Replica], value: ~[u8]) {
+        let mut ports: ~[Port<Decision>] = replicas.iter().map(|r| r.decisions()).collect();
+        let iid = replicas[0].submit(value.clone()).unwrap();
+        for (id, port) in ports.mut_iter().enumerate() {
+            match next_decision(port) {
+                Some(decision) => {
+                    assert_eq!(decision.instance_id, iid);
+                    assert_eq!(decision.commands, ~[value.clone()]);
+                },
+                None => fail!("replica {} saw no decision for instance {:?}", id, iid),
+            }
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn test_submitted_values_are_decided_on_every_replica() {
+        let mut replicas = start_cluster(MemoryTransport::new());
+        submit_and_check(&mut replicas, ~[1u8, 2, 3]);
+        submit_and_check(&mut replicas, ~[4u8]);
+    }
+
+    // A MemoryTransport whose connections can all be broken at once, as if
+    // the network went down for a moment
+    #[deriving(Clone)]
+    struct FlakyTransport {
+        inner: MemoryTransport,
+        // Bumped to break every connection opened so far
+        generation: UnsafeArc<AtomicUint>,
+        connects: UnsafeArc<AtomicUint>,
+    }
+
+    impl FlakyTransport {
+        fn new() -> FlakyTransport {
+            FlakyTransport{
+                inner: MemoryTransport::new(),
+                generation: UnsafeArc::new(AtomicUint::new(0)),
+                connects: UnsafeArc::new(AtomicUint::new(0)),
+            }
+        }
+
+        fn break_connections(&self) {
+            unsafe { (*self.generation.get()).fetch_add(1, SeqCst); }
+        }
+
+        // How many connections have been opened
+        fn connects(&self) -> uint {
+            unsafe { (*self.connects.get()).load(SeqCst) }
+        }
+
+        fn wrap(&self, stream: ~TransportStream) -> ~TransportStream {
+            ~FlakyStream{
+                inner: stream,
+                generation: self.generation.clone(),
+                opened: unsafe { (*self.generation.get()).load(SeqCst) },
+            } as ~TransportStream
+        }
+    }
+
+    impl Transport for FlakyTransport {
+        fn connect(&self, addr: &Address) -> Option<~TransportStream> {
+            unsafe { (*self.connects.get()).fetch_add(1, SeqCst); }
+            match self.inner.connect(addr) {
+                Some(stream) => Some(self.wrap(stream)),
+                None => None,
+            }
+        }
+
+        fn listen(&self, addr: &Address) -> Option<~TransportListener> {
+            match self.inner.listen(addr) {
+                Some(listener) => Some(~FlakyListener{ inner: listener, transport: self.clone() }
+                                       as ~TransportListener),
+                None => None,
+            }
+        }
+    }
+
+    struct FlakyListener {
+        inner: ~TransportListener,
+        transport: FlakyTransport,
+    }
+
+    impl TransportListener for FlakyListener {
+        fn accept(&mut self) -> Option<~TransportStream> {
+            match self.inner.accept() {
+                Some(stream) => Some(self.transport.wrap(stream)),
+                None => None,
+            }
+        }
+
+        fn address(&self) -> Address {
+            self.inner.address()
+        }
+    }
+
+    // Fails every write once its transport broke the connections it was
+    // opened before.  Both ends send heartbeats, so both notice soon.
+    struct FlakyStream {
+        inner: ~TransportStream,
+        generation: UnsafeArc<AtomicUint>,
+        opened: uint,
+    }
+
+    impl TransportStream for FlakyStream {}
+
+    impl Reader for FlakyStream {
+        fn read(&mut self, buf: &mut [u8]) -> Option<uint> {
+            self.inner.read(buf)
+        }
+
+        fn eof(&mut self) -> bool {
+            self.inner.eof()
+        }
+    }
+
+    impl Writer for FlakyStream {
+        fn write(&mut self, buf: &[u8]) {
+            if unsafe { (*self.generation.get()).load(SeqCst) } != self.opened {
+                io_error::cond.raise(standard_error(BrokenPipe));
+            } else {
+                self.inner.write(buf);
+            }
+        }
+
+        fn flush(&mut self) {
+            self.inner.flush();
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn test_values_are_decided_after_links_break() {
+        let transport = FlakyTransport::new();
+        let mut replicas = start_cluster(transport.clone());
+        // Once this is decided, every link is up
+        submit_and_check(&mut replicas, ~[1u8]);
+
+        let connects = transport.connects();
+        transport.break_connections();
+        submit_and_check(&mut replicas, ~[2u8]);
+        // The replicas had to reconnect to get it decided
+        assert!(transport.connects() > connects);
+    }
+}
@@ -0,0 +1,151 @@
+use extra::arc::Arc;
+
+use openssl::ssl::{SslContext, SslStream, Sslv23, SslVerifyPeer};
+use openssl::x509::PEM;
+use openssl::nid;
+
+use super::transport::TransportStream;
+
+// Paths to the PEM files a replica uses for mutual TLS.  Every replica
+// presents its own certificate and only accepts peers whose certificate is
+// signed by the cluster's CA.  A replica's certificate must have the common
+// name "replica-<stable id>"; this is what ties a TLS connection to the
+// replica claimed in the handshake.  A peer that presents no certificate is
+// refused, on either side of the connection.
+#[deriving(Clone, Eq)]
+pub struct TlsConfig {
+    certificate: Path,
+    private_key: Path,
+    ca: Path,
+}
+
+// The byte stream underneath a connection to a peer
+pub enum PeerStream {
+    Plain(~TransportStream),
+    Tls(SslStream<~TransportStream>),
+}
+
+impl Reader for PeerStream {
+    fn read(&mut self, buf: &mut [u8]) -> Option<uint> {
+        match *self {
+            Plain(ref mut s) => s.read(buf),
+            Tls(ref mut s) => s.read(buf),
+        }
+    }
+
+    fn eof(&mut self) -> bool {
+        match *self {
+            Plain(ref mut s) => s.eof(),
+            Tls(ref mut s) => s.eof(),
+        }
+    }
+}
+
+impl Writer for PeerStream {
+    fn write(&mut self, buf: &[u8]) {
+        match *self {
+            Plain(ref mut s) => s.write(buf),
+            Tls(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) {
+        match *self {
+            Plain(ref mut s) => s.flush(),
+            Tls(ref mut s) => s.flush(),
+        }
+    }
+}
+
+impl PeerStream {
+    // The identity in the peer's certificate, None on a plaintext
+    // connection, or an error if the peer presented no certificate over TLS
+    pub fn peer_identity(&self) -> Result<Option<~str>, ~str> {
+        match *self {
+            Plain(..) => Ok(None),
+            Tls(ref s) => match s.get_peer_certificate() {
+                Some(cert) => match cert.subject_name().text_by_nid(nid::CN) {
+                    Some(name) => Ok(Some(name)),
+                    None => Err(~"the peer's certificate has no common name"),
+                },
+                None => Err(~"the peer presented no certificate"),
+            },
+        }
+    }
+}
+
+// The identity a replica's certificate must carry, given its stable ID
+pub fn replica_identity(stable_id: &str) -> ~str {
+    format!("replica-{}", stable_id)
+}
+
+// The TLS settings of a replica, with its certificates loaded once and
+// shared by all its connections
+#[deriving(Clone)]
+pub struct TlsContext {
+    priv ctx: Arc<SslContext>,
+}
+
+impl TlsContext {
+    pub fn new(config: &TlsConfig) -> Result<TlsContext, ~str> {
+        let mut ctx = SslContext::new(Sslv23);
+        // The bindings don't expose SSL_VERIFY_FAIL_IF_NO_PEER_CERT, so a
+        // server accepts a client without a certificate.  accept refuses
+        // those once the TLS handshake is done.
+        ctx.set_verify(SslVerifyPeer, None);
+        let results = [
+            ctx.set_CA_file(&config.ca),
+            ctx.set_certificate_file(&config.certificate, PEM),
+            ctx.set_private_key_file(&config.private_key, PEM),
+        ];
+        for res in results.iter() {
+            match *res {
+                Some(ref err) => return Err(format!("{:?}", err)),
+                None => (),
+            }
+        }
+        Ok(TlsContext{ ctx: Arc::new(ctx) })
+    }
+}
+
+// Wraps a connection we initiated.  With TLS turned off, the stream is used
+// as is.
+pub fn connect(ctx: &Option<TlsContext>, stream: ~TransportStream) -> Result<PeerStream, ~str> {
+    match *ctx {
+        None => Ok(Plain(stream)),
+        Some(ref ctx) => match SslStream::new(ctx.ctx.get(), stream) {
+            Ok(s) => Ok(Tls(s)),
+            Err(err) => Err(format!("{:?}", err)),
+        },
+    }
+}
+
+// Wraps a connection we accepted.  Over TLS, the peer must have presented a
+// certificate signed by the cluster's CA.
+pub fn accept(ctx: &Option<TlsContext>, stream: ~TransportStream) -> Result<PeerStream, ~str> {
+    match *ctx {
+        None => Ok(Plain(stream)),
+        Some(ref ctx) => {
+            let peer = match SslStream::new_server(ctx.ctx.get(), stream) {
+                Ok(s) => Tls(s),
+                Err(err) => return Err(format!("{:?}", err)),
+            };
+            match peer.peer_identity() {
+                Ok(_) => Ok(peer),
+                Err(err) => Err(err),
+            }
+        },
+    }
+}
+
+// Checks that the certificate presented on a connection belongs to the
+// replica with the given stable ID.  Only passes without a certificate on
+// plaintext connections, i.e. when TLS is off.
+pub fn check_identity(identity: &Result<Option<~str>, ~str>, stable_id: &str) -> Result<(), ~str> {
+    match *identity {
+        Ok(None) => Ok(()),
+        Ok(Some(ref name)) if *name == replica_identity(stable_id) => Ok(()),
+        Ok(Some(ref name)) => Err(format!("certificate of {} presented by replica {}", *name, stable_id)),
+        Err(ref err) => Err(err.clone()),
+    }
+}
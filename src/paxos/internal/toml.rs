@@ -0,0 +1,444 @@
+use std::char;
+use std::str;
+
+use extra::json::{Json, Object, List, Number, String, Boolean};
+use extra::treemap::TreeMap;
+
+// Parses the subset of TOML that configs need into the same JSON tree that
+// JSON configs are read into, so both go through the same checks.
+//
+// Supported: comments, [tables] and [dotted.tables], bare, quoted and dotted
+// keys, basic and literal strings, integers, floats, booleans, arrays (which
+// may span lines) and inline tables.  Not supported: multi-line strings,
+// dates and times, and arrays of tables.
+pub fn parse(s: &str) -> Result<Json, ~str> {
+    let mut parser = Parser{ chars: s.chars().collect(), pos: 0, line: 1 };
+    match parser.document() {
+        Ok(root) => Ok(Object(~root)),
+        Err(err) => Err(format!("line {}: {}", parser.line, err)),
+    }
+}
+
+struct Parser {
+    chars: ~[char],
+    pos: uint,
+    line: uint,
+}
+
+impl Parser {
+    fn document(&mut self) -> Result<TreeMap<~str, Json>, ~str> {
+        let mut root = TreeMap::new();
+        // The keys of the table that the following key/value pairs go into
+        let mut table: ~[~str] = ~[];
+        loop {
+            self.skip_whitespace_and_newlines();
+            match self.peek() {
+                None => return Ok(root),
+                Some('[') => {
+                    self.pos += 1;
+                    if self.peek() == Some('[') {
+                        return Err(~"arrays of tables are not supported");
+                    }
+                    self.skip_whitespace();
+                    table = match self.key() {
+                        Ok(keys) => keys,
+                        Err(err) => return Err(err),
+                    };
+                    self.skip_whitespace();
+                    if !self.eat(']') {
+                        return Err(~"expected `]` after the table name");
+                    }
+                    match lookup(&mut root, table.as_slice(), true) {
+                        Ok(_) => (),
+                        Err(err) => return Err(err),
+                    }
+                },
+                Some(_) => {
+                    let keys = match self.key() {
+                        Ok(keys) => keys,
+                        Err(err) => return Err(err),
+                    };
+                    self.skip_whitespace();
+                    if !self.eat('=') {
+                        return Err(~"expected `=` after the key");
+                    }
+                    self.skip_whitespace();
+                    let value = match self.value() {
+                        Ok(value) => value,
+                        Err(err) => return Err(err),
+                    };
+                    let path = table + keys;
+                    match insert(&mut root, path.as_slice(), value) {
+                        Ok(()) => (),
+                        Err(err) => return Err(err),
+                    }
+                },
+            }
+            self.skip_whitespace();
+            self.skip_comment();
+            match self.peek() {
+                None | Some('\n') | Some('\r') => (),
+                Some(c) => return Err(format!("unexpected `{}` at the end of the line", c)),
+            }
+        }
+    }
+
+    // A possibly dotted key
+    fn key(&mut self) -> Result<~[~str], ~str> {
+        let mut keys = ~[];
+        loop {
+            let key = match self.peek() {
+                Some('"') => self.basic_string(),
+                Some('\'') => self.literal_string(),
+                _ => {
+                    let start = self.pos;
+                    while self.peek().map_default(false, |c| is_bare_key_char(c)) {
+                        self.pos += 1;
+                    }
+                    if self.pos == start {
+                        Err(~"expected a key")
+                    } else {
+                        Ok(str::from_chars(self.chars.slice(start, self.pos)))
+                    }
+                },
+            };
+            match key {
+                Ok(key) => keys.push(key),
+                Err(err) => return Err(err),
+            }
+            self.skip_whitespace();
+            if !self.eat('.') {
+                return Ok(keys);
+            }
+            self.skip_whitespace();
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, ~str> {
+        match self.peek() {
+            Some('"') => self.basic_string().map(|s| String(s)),
+            Some('\'') => self.literal_string().map(|s| String(s)),
+            Some('[') => self.array(),
+            Some('{') => self.inline_table(),
+            Some('t') | Some('f') => {
+                if self.eat_word("true") {
+                    Ok(Boolean(true))
+                } else if self.eat_word("false") {
+                    Ok(Boolean(false))
+                } else {
+                    Err(~"expected a value")
+                }
+            },
+            Some(c) if c == '+' || c == '-' || char::is_digit(c) => self.number(),
+            Some(c) => Err(format!("unexpected `{}` where a value should be", c)),
+            None => Err(~"expected a value"),
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, ~str> {
+        self.pos += 1;
+        let mut items = ~[];
+        loop {
+            self.skip_whitespace_and_newlines();
+            if self.eat(']') {
+                return Ok(List(items));
+            }
+            match self.value() {
+                Ok(v) => items.push(v),
+                Err(err) => return Err(err),
+            }
+            self.skip_whitespace_and_newlines();
+            if self.eat(']') {
+                return Ok(List(items));
+            }
+            if !self.eat(',') {
+                return Err(~"expected `,` or `]` in the array");
+            }
+        }
+    }
+
+    fn inline_table(&mut self) -> Result<Json, ~str> {
+        self.pos += 1;
+        let mut table = TreeMap::new();
+        self.skip_whitespace();
+        if self.eat('}') {
+            return Ok(Object(~table));
+        }
+        loop {
+            self.skip_whitespace();
+            let keys = match self.key() {
+                Ok(keys) => keys,
+                Err(err) => return Err(err),
+            };
+            self.skip_whitespace();
+            if !self.eat('=') {
+                return Err(~"expected `=` after the key");
+            }
+            self.skip_whitespace();
+            let value = match self.value() {
+                Ok(v) => v,
+                Err(err) => return Err(err),
+            };
+            match insert(&mut table, keys.as_slice(), value) {
+                Ok(()) => (),
+                Err(err) => return Err(err),
+            }
+            self.skip_whitespace();
+            if self.eat('}') {
+                return Ok(Object(~table));
+            }
+            if !self.eat(',') {
+                return Err(~"expected `,` or `}` in the inline table");
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, ~str> {
+        let start = self.pos;
+        while self.peek().map_default(false, |c| {
+            char::is_alphanumeric(c) || c == '+' || c == '-' || c == '.' || c == '_'
+        }) {
+            self.pos += 1;
+        }
+        // Underscores may separate digits, and from_str doesn't take a
+        // leading +
+        let text: ~str = self.chars.slice(start, self.pos).iter()
+            .filter(|&c| *c != '_').map(|&c| c).collect();
+        let text = if text.starts_with("+") { text.slice_from(1).to_owned() } else { text };
+        match from_str::<f64>(text) {
+            Some(n) => Ok(Number(n)),
+            None => Err(format!("`{}` is not a number", text)),
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<~str, ~str> {
+        self.pos += 1;
+        let mut s = ~"";
+        loop {
+            let c = match self.next() {
+                Some('\n') | None => return Err(~"unterminated string"),
+                Some(c) => c,
+            };
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let escaped = match self.next() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('u') => match self.unicode_escape() {
+                            Ok(c) => c,
+                            Err(err) => return Err(err),
+                        },
+                        _ => return Err(~"invalid escape in string"),
+                    };
+                    s.push_char(escaped);
+                },
+                c => s.push_char(c),
+            }
+        }
+    }
+
+    fn unicode_escape(&mut self) -> Result<char, ~str> {
+        let mut code = 0u32;
+        for _ in range(0, 4) {
+            match self.next().and_then(|c| char::to_digit(c, 16)) {
+                Some(d) => code = code * 16 + d as u32,
+                None => return Err(~"invalid unicode escape in string"),
+            }
+        }
+        match char::from_u32(code) {
+            Some(c) => Ok(c),
+            None => Err(~"invalid unicode escape in string"),
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<~str, ~str> {
+        self.pos += 1;
+        let mut s = ~"";
+        loop {
+            match self.next() {
+                Some('\'') => return Ok(s),
+                Some('\n') | None => return Err(~"unterminated string"),
+                Some(c) => s.push_char(c),
+            }
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek() == Some(' ') || self.peek() == Some('\t') {
+            self.pos += 1;
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while self.peek().is_some() && self.peek() != Some('\n') {
+                self.pos += 1;
+            }
+        }
+    }
+
+    fn skip_whitespace_and_newlines(&mut self) {
+        loop {
+            self.skip_whitespace();
+            self.skip_comment();
+            match self.peek() {
+                Some('\n') => {
+                    self.line += 1;
+                    self.pos += 1;
+                },
+                Some('\r') => self.pos += 1,
+                _ => return,
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        if self.pos < self.chars.len() {
+            Some(self.chars[self.pos])
+        } else {
+            None
+        }
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        if c.is_some() {
+            self.pos += 1;
+        }
+        c
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_word(&mut self, word: &str) -> bool {
+        let chars: ~[char] = word.chars().collect();
+        let end = self.pos + chars.len();
+        if end <= self.chars.len() && self.chars.slice(self.pos, end) == chars.as_slice() {
+            self.pos = end;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+fn is_bare_key_char(c: char) -> bool {
+    char::is_alphanumeric(c) || c == '_' || c == '-'
+}
+
+// Finds the table at the path, creating the tables along it as needed.  A
+// table header may not name a table that's already been defined.
+fn lookup<'a>(root: &'a mut TreeMap<~str, Json>, path: &[~str], header: bool)
+              -> Result<&'a mut TreeMap<~str, Json>, ~str> {
+    if path.is_empty() {
+        return Ok(root);
+    }
+    let key = path[0].clone();
+    if !root.contains_key(&key) {
+        root.insert(key.clone(), Object(~TreeMap::new()));
+    } else if header && path.len() == 1 {
+        return Err(format!("table `{}` is defined twice", key));
+    }
+    match root.find_mut(&key) {
+        Some(value) => match *value {
+            Object(ref mut table) => lookup(&mut **table, path.slice_from(1), header),
+            _ => Err(format!("`{}` is not a table", key)),
+        },
+        None => unreachable!(),
+    }
+}
+
+fn insert(root: &mut TreeMap<~str, Json>, path: &[~str], value: Json) -> Result<(), ~str> {
+    if path.is_empty() {
+        return Err(~"expected a key");
+    }
+    let last = &path[path.len() - 1];
+    let table = match lookup(root, path.slice_to(path.len() - 1), false) {
+        Ok(table) => table,
+        Err(err) => return Err(err),
+    };
+    if table.contains_key(last) {
+        return Err(format!("`{}` is defined twice", *last));
+    }
+    table.insert(last.clone(), value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use extra::json;
+
+    use super::parse;
+
+    // Checks that the TOML parses into the same tree as the JSON
+    fn same(toml: &str, json: &str) {
+        assert_eq!(parse(toml), Ok(json::from_str(json).unwrap()));
+    }
+
+    fn fails(toml: &str) {
+        assert!(parse(toml).is_err());
+    }
+
+    #[test]
+    fn test_values() {
+        same("a = \"x\\ty\\u00e9\"\nb = 'c:\\dir'\nc = 42\nd = -1.5\ne = +1_000\nf = true\ng = false",
+             "{\"a\": \"x\\ty\\u00e9\", \"b\": \"c:\\\\dir\", \"c\": 42, \"d\": -1.5, \"e\": 1000, \
+               \"f\": true, \"g\": false}");
+    }
+
+    #[test]
+    fn test_tables() {
+        same("id = 0\n\n[tls]\nca = \"ca.pem\"\n\n[gossip.timing]\nprobe = 1\n",
+             "{\"id\": 0, \"tls\": {\"ca\": \"ca.pem\"}, \"gossip\": {\"timing\": {\"probe\": 1}}}");
+    }
+
+    #[test]
+    fn test_dotted_and_quoted_keys() {
+        same("send_queue.max_bytes = 1\n\"a b\" = 2\n'c.d' = 3",
+             "{\"send_queue\": {\"max_bytes\": 1}, \"a b\": 2, \"c.d\": 3}");
+    }
+
+    #[test]
+    fn test_arrays_and_inline_tables() {
+        same("peers = [\n  \"a\", # first\n  \"b\",\n]\nx = { y = 1, z.w = [] }",
+             "{\"peers\": [\"a\", \"b\"], \"x\": {\"y\": 1, \"z\": {\"w\": []}}}");
+    }
+
+    #[test]
+    fn test_comments_and_blank_lines() {
+        same("# a config\n\n  a = 1 # one\r\n\n", "{\"a\": 1}");
+    }
+
+    #[test]
+    fn test_errors() {
+        fails("a = 1\na = 2");
+        fails("[t]\n[t]");
+        fails("a = 1\n[a]");
+        fails("[[t]]");
+        fails("a = \"unterminated");
+        fails("a = 1 b = 2");
+        fails("a = [1 2]");
+        fails("a = 1x");
+        fails("a");
+        fails("a = \"\\q\"");
+    }
+
+    #[test]
+    fn test_error_names_the_line() {
+        match parse("a = 1\n\nb = ?") {
+            Err(err) => assert!(err.starts_with("line 3:")),
+            Ok(_) => fail!(),
+        }
+    }
+}
@@ -0,0 +1,320 @@
+use std::char;
+use std::libc;
+use std::unstable::intrinsics;
+use std::from_str::FromStr;
+use std::io::{Listener, Acceptor, io_error};
+use std::io::fs;
+use std::io::net::ip::{SocketAddr, Ipv4Addr, Ipv6Addr};
+use std::io::net::addrinfo::get_host_addresses;
+use std::io::net::tcp::{TcpStream, TcpListener, TcpAcceptor};
+use std::io::net::unix::{UnixStream, UnixListener, UnixAcceptor};
+
+// Where a replica listens.  In config files, Unix domain sockets are written
+// as "unix:/path/to/sock", IP addresses as "ip:port" or "[ipv6]:port", and
+// anything else as "host:port".
+#[deriving(Clone, Eq, IterBytes)]
+pub enum Address {
+    InetAddress(SocketAddr),
+    // A DNS name, looked up every time it's connected to, so a peer whose
+    // name moves to another IP is found on the next attempt
+    HostAddress(~str, u16),
+    UnixAddress(Path),
+}
+
+impl Address {
+    // The IP addresses to try for the address, or None if it's not an IP
+    // address or a name that resolves to any
+    pub fn resolve(&self) -> Option<~[SocketAddr]> {
+        match *self {
+            InetAddress(addr) => Some(~[addr]),
+            HostAddress(ref host, port) => {
+                let ips = io_error::cond.trap(|err| {
+                    debug!("Failed to look up {}: {}", *host, err.to_str());
+                }).inside(|| get_host_addresses(*host));
+                match ips {
+                    Some(ref ips) if !ips.is_empty() => {
+                        Some(ips.iter().map(|&ip| SocketAddr{ ip: ip, port: port }).collect())
+                    },
+                    _ => None,
+                }
+            },
+            UnixAddress(..) => None,
+        }
+    }
+
+    // Whether only this host can connect to the address: a Unix domain
+    // socket, a loopback IP or localhost
+    pub fn is_local(&self) -> bool {
+        match *self {
+            InetAddress(SocketAddr{ ip: Ipv4Addr(127, _, _, _), .. }) => true,
+            InetAddress(SocketAddr{ ip: Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 1), .. }) => true,
+            InetAddress(..) => false,
+            HostAddress(ref host, _) => host.as_slice() == "localhost",
+            UnixAddress(..) => true,
+        }
+    }
+}
+
+impl FromStr for Address {
+    fn from_str(s: &str) -> Option<Address> {
+        if s.starts_with("unix:") {
+            return Some(UnixAddress(Path::new(s.slice_from(5))));
+        }
+        match from_str::<SocketAddr>(s) {
+            Some(addr) => return Some(InetAddress(addr)),
+            None => (),
+        }
+        let (host, port) = match s.rfind(':') {
+            Some(i) => (s.slice_to(i), s.slice_from(i + 1)),
+            None => return None,
+        };
+        if host.is_empty() || !host.chars().all(|c| char::is_alphanumeric(c) || c == '-' || c == '.') {
+            return None;
+        }
+        from_str::<u16>(port).map(|port| HostAddress(host.to_owned(), port))
+    }
+}
+
+impl ToStr for Address {
+    fn to_str(&self) -> ~str {
+        match *self {
+            InetAddress(ref addr) => addr.to_str(),
+            HostAddress(ref host, port) => format!("{}:{}", *host, port),
+            UnixAddress(ref path) => format!("unix:{}", path.display()),
+        }
+    }
+}
+
+// A transport is how replicas reach each other.  The ConnectionHandler uses it
+// to open connections to peers and to accept theirs; everything above it
+// (TLS, framing, the handshake) works the same on any transport.
+//
+// Transports are cloned into every task that opens connections, so clones
+// must refer to the same underlying network.
+pub trait Transport: Send + Clone {
+    // Opens a connection to the replica listening at the address
+    fn connect(&self, addr: &Address) -> Option<~TransportStream>;
+
+    // Starts listening for connections at the address
+    fn listen(&self, addr: &Address) -> Option<~TransportListener>;
+}
+
+// A connection opened by a transport
+pub trait TransportStream: Reader + Writer + Send {}
+
+pub trait TransportListener: Send {
+    // Blocks until a peer connects.  Returns None if the listener can't
+    // accept connections anymore.
+    fn accept(&mut self) -> Option<~TransportStream>;
+
+    // Where the listener is listening.  Differs from the address it was
+    // asked to listen at if that had port 0.
+    fn address(&self) -> Address;
+}
+
+impl Reader for ~TransportStream {
+    fn read(&mut self, buf: &mut [u8]) -> Option<uint> {
+        (**self).read(buf)
+    }
+
+    fn eof(&mut self) -> bool {
+        (**self).eof()
+    }
+}
+
+impl Writer for ~TransportStream {
+    fn write(&mut self, buf: &[u8]) {
+        (**self).write(buf)
+    }
+
+    fn flush(&mut self) {
+        (**self).flush()
+    }
+}
+
+// The transport used in production: TCP or Unix domain sockets, depending on
+// the address
+#[deriving(Clone)]
+pub struct SocketTransport;
+
+impl Transport for SocketTransport {
+    fn connect(&self, addr: &Address) -> Option<~TransportStream> {
+        match *addr {
+            UnixAddress(..) => UnixTransport.connect(addr),
+            _ => TcpTransport.connect(addr),
+        }
+    }
+
+    fn listen(&self, addr: &Address) -> Option<~TransportListener> {
+        match *addr {
+            UnixAddress(..) => UnixTransport.listen(addr),
+            _ => TcpTransport.listen(addr),
+        }
+    }
+}
+
+// Plain TCP, to IP addresses or to names, which are looked up on every
+// connection attempt
+#[deriving(Clone)]
+pub struct TcpTransport;
+
+impl TransportStream for TcpStream {}
+
+struct TcpTransportListener {
+    acceptor: TcpAcceptor,
+    address: Address,
+}
+
+impl Transport for TcpTransport {
+    fn connect(&self, addr: &Address) -> Option<~TransportStream> {
+        let inets = match *addr {
+            UnixAddress(..) => {
+                error!("TCP can't connect to {}", addr.to_str());
+                return None;
+            },
+            _ => match addr.resolve() {
+                Some(inets) => inets,
+                None => {
+                    debug!("Failed to resolve {}", addr.to_str());
+                    return None;
+                },
+            },
+        };
+        // Try every address the name resolves to, in order
+        for &inet in inets.iter() {
+            let stream = io_error::cond.trap(|err| {
+                debug!("Failed to connect to {} ({}): {}", addr.to_str(), inet.to_str(), err.to_str());
+            }).inside(|| {
+                TcpStream::connect(inet)
+            });
+            match stream {
+                Some(s) => return Some(~s as ~TransportStream),
+                None => (),
+            }
+        }
+        None
+    }
+
+    fn listen(&self, addr: &Address) -> Option<~TransportListener> {
+        // Names are bound to the first address they resolve to
+        let inet = match *addr {
+            UnixAddress(..) => {
+                error!("TCP can't listen on {}", addr.to_str());
+                return None;
+            },
+            _ => match addr.resolve() {
+                Some(inets) => inets[0],
+                None => {
+                    error!("Failed to resolve {}", addr.to_str());
+                    return None;
+                },
+            },
+        };
+        io_error::cond.trap(|err| {
+            error!("Failed to listen on {}: {}", addr.to_str(), err.to_str());
+        }).inside(|| {
+            TcpListener::bind(inet).and_then(|mut listener| {
+                // The port the OS picked, if we asked for port 0
+                let bound = listener.socket_name().unwrap_or(inet);
+                listener.listen().map(|acceptor| {
+                    ~TcpTransportListener{
+                        acceptor: acceptor,
+                        address: InetAddress(bound),
+                    } as ~TransportListener
+                })
+            })
+        })
+    }
+}
+
+impl TransportListener for TcpTransportListener {
+    fn accept(&mut self) -> Option<~TransportStream> {
+        io_error::cond.trap(|err| {
+            warn!("Failed to accept a connection: {}", err.to_str());
+        }).inside(|| {
+            self.acceptor.accept().map(|s| ~s as ~TransportStream)
+        })
+    }
+
+    fn address(&self) -> Address {
+        self.address.clone()
+    }
+}
+
+// Unix domain sockets, for replicas running on the same host
+#[deriving(Clone)]
+pub struct UnixTransport;
+
+impl TransportStream for UnixStream {}
+
+struct UnixTransportListener {
+    acceptor: UnixAcceptor,
+    path: Path,
+}
+
+impl Transport for UnixTransport {
+    fn connect(&self, addr: &Address) -> Option<~TransportStream> {
+        let path = match *addr {
+            UnixAddress(ref path) => path,
+            _ => {
+                error!("Unix sockets can't connect to {}", addr.to_str());
+                return None;
+            },
+        };
+        io_error::cond.trap(|err| {
+            debug!("Failed to connect to {}: {}", addr.to_str(), err.to_str());
+        }).inside(|| {
+            UnixStream::connect(path).map(|s| ~s as ~TransportStream)
+        })
+    }
+
+    fn listen(&self, addr: &Address) -> Option<~TransportListener> {
+        let path = match *addr {
+            UnixAddress(ref path) => path,
+            _ => {
+                error!("Unix sockets can't listen on {}", addr.to_str());
+                return None;
+            },
+        };
+        io_error::cond.trap(|err| {
+            error!("Failed to listen on {}: {}", addr.to_str(), err.to_str());
+        }).inside(|| {
+            // A socket file left behind by a previous run would make bind
+            // fail.  Anything else at the path, or a socket someone is still
+            // listening on, is left alone and bind fails.
+            if is_stale_socket(path) {
+                fs::unlink(path);
+            }
+            UnixListener::bind(path).listen().map(|acceptor| {
+                ~UnixTransportListener{ acceptor: acceptor, path: path.clone() } as ~TransportListener
+            })
+        })
+    }
+}
+
+// Whether there's a socket at the path that nothing listens on anymore
+fn is_stale_socket(path: &Path) -> bool {
+    // Sockets are one of the file types io::FileStat doesn't tell apart
+    static S_IFMT: libc::mode_t = 0xf000;
+    static S_IFSOCK: libc::mode_t = 0xc000;
+    let mut st: libc::stat = unsafe { intrinsics::uninit() };
+    let res = path.with_c_str(|p| unsafe { libc::lstat(p, &mut st) });
+    if res != 0 || st.st_mode & S_IFMT != S_IFSOCK {
+        return false;
+    }
+    io_error::cond.trap(|_| {}).inside(|| UnixStream::connect(path)).is_none()
+}
+
+impl TransportListener for UnixTransportListener {
+    fn accept(&mut self) -> Option<~TransportStream> {
+        io_error::cond.trap(|err| {
+            warn!("Failed to accept a connection: {}", err.to_str());
+        }).inside(|| {
+            self.acceptor.accept().map(|s| ~s as ~TransportStream)
+        })
+    }
+
+    fn address(&self) -> Address {
+        UnixAddress(self.path.clone())
+    }
+}
@@ -0,0 +1,111 @@
+use extra::arc::MutexArc;
+
+pub static DEFAULT_MAX_IN_FLIGHT: uint = 64;
+
+// Limits how many instances a replica has in flight as the proposer.  Shared
+// between the replica, its batcher, and the instances themselves, which give
+// their slot back once they're done.
+#[deriving(Clone)]
+pub struct InFlightWindow {
+    priv state: MutexArc<WindowState>,
+}
+
+struct WindowState {
+    in_flight: uint,
+    limit: uint,
+}
+
+impl InFlightWindow {
+    // The limit comes from a validated config, which makes sure it's
+    // positive; a window of 0 would never let an instance start
+    pub fn new(limit: uint) -> InFlightWindow {
+        InFlightWindow{
+            state: MutexArc::new(WindowState{ in_flight: 0, limit: limit }),
+        }
+    }
+
+    // Takes a slot if one is free.  Returns false otherwise.
+    pub fn try_acquire(&self) -> bool {
+        self.state.access(|s| {
+            if s.in_flight < s.limit {
+                s.in_flight += 1;
+                true
+            } else {
+                false
+            }
+        })
+    }
+
+    // Takes a slot, waiting for one to be released if need be
+    pub fn acquire(&self) {
+        self.state.access_cond(|s, cond| {
+            while s.in_flight >= s.limit {
+                cond.wait();
+            }
+            s.in_flight += 1;
+        })
+    }
+
+    pub fn release(&self) {
+        self.state.access_cond(|s, cond| {
+            assert!(s.in_flight > 0);
+            s.in_flight -= 1;
+            cond.signal();
+        })
+    }
+
+    // Changes how many instances may be in flight.  Instances already over
+    // a lower limit run to completion.
+    pub fn set_limit(&self, limit: uint) {
+        self.state.access_cond(|s, cond| {
+            s.limit = limit;
+            cond.broadcast();
+        })
+    }
+
+    pub fn in_flight(&self) -> uint {
+        self.state.access(|s| s.in_flight)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::InFlightWindow;
+
+    #[test]
+    fn test_try_acquire_fails_at_the_limit_until_a_release() {
+        let window = InFlightWindow::new(2);
+        assert!(window.try_acquire());
+        assert!(window.try_acquire());
+        assert!(!window.try_acquire());
+        assert_eq!(window.in_flight(), 2);
+        window.release();
+        assert!(window.try_acquire());
+        assert!(!window.try_acquire());
+    }
+
+    #[test]
+    fn test_raising_the_limit_makes_room() {
+        let window = InFlightWindow::new(1);
+        assert!(window.try_acquire());
+        assert!(!window.try_acquire());
+        window.set_limit(2);
+        assert!(window.try_acquire());
+        assert!(!window.try_acquire());
+    }
+
+    #[test]
+    fn test_acquire_waits_for_a_release() {
+        let window = InFlightWindow::new(1);
+        window.acquire();
+        let (port, chan) = Chan::new();
+        let waiter = window.clone();
+        do spawn {
+            waiter.acquire();
+            chan.send(());
+        }
+        window.release();
+        port.recv();
+        assert_eq!(window.in_flight(), 1);
+    }
+}
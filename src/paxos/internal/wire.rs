@@ -0,0 +1,1010 @@
+// The wire protocol spoken between replicas.
+//
+// Every message travels in its own frame.  All integers are big-endian.
+//
+//     0        2        3        4                 8                 12
+//     +--------+--------+--------+-----------------+-----------------+----------
+//     | magic  | version| type   | payload length  | CRC-32 of       | payload
+//     | "PX"   |        |        |                 | the payload     | ...
+//     +--------+--------+--------+-----------------+-----------------+----------
+//
+// The version of a frame is the protocol version negotiated during the
+// handshake (see handshake.rs).  The handshake itself is always sent with
+// MIN_PROTOCOL_VERSION.
+//
+// From version 4 on, the header is followed by a CRC-32 of its 12 bytes:
+//
+//     12                16
+//     +-----------------+----------
+//     | CRC-32 of the   | payload
+//     | header          | ...
+//     +-----------------+----------
+//
+// The length is only trusted once the header checksum matches.  A frame whose
+// header doesn't match is dropped without skipping its payload, and the
+// decoder looks for the next magic right after the header, so a corrupted
+// length can't make it skip over good frames.  Once a stream has switched to
+// version 4 or later, frames of earlier versions are taken as corrupted
+// headers as well.
+//
+// Otherwise the layout of the header is the same for every protocol version,
+// so a frame of an unsupported version (or of an unknown type) can still be
+// skipped without losing track of where the next frame starts.  Payloads
+// larger than MAX_PAYLOAD_LEN are skipped as well.  If the magic doesn't match,
+// the decoder scans forward until it finds one.
+//
+// Payload of a NetworkMessage (type 1):
+//
+//     tag: u8, followed by
+//         0 Hello: cluster id, min version: u8, max version: u8, replica id: u64
+//         1 Reject: reason
+//         2 Heartbeat: sender's clock in nanoseconds: u64 (version 2 and up)
+//         3 HeartbeatAck: the clock of the Heartbeat: u64 (version 2 and up)
+//         4 Identify: stable id (version 3 and up)
+//         5 Nonce: u64 (version 5 and up)
+//     where strings are their length as a u16 followed by UTF-8 bytes.
+//
+// Payload of a PaxosMessage (type 2):
+//
+//     instance id: (replica id: u64, instance number: u64)
+//     content tag: u8, followed by the fields of the content:
+//         0 Propose(seq)           3 Request(seq, value)     6 Commit(seq)
+//         1 Promise(seq)           4 Accept(seq)             7 Acknowledge(seq)
+//         2 RejectPropose(s1, s2)  5 RejectRequest(s1, s2)
+//     where a sequence id is (sequence number: u64, replica id: u64) and a
+//     value is its length as a u32 followed by the bytes.
+//
+// Payload of an authenticated PaxosMessage (type 3), used instead of type 2
+// when the cluster has a shared key (see auth.rs):
+//
+//     counter: u64, HMAC tag: 32 bytes, payload of a PaxosMessage
+//
+// Payload of a GossipMessage (type 4):
+//
+//     cluster id, seq: u64, kind tag: u8, followed by
+//         0 Ping
+//         1 PingReq: replica id: u64
+//         2 Ack
+//     number of updates: u16, followed by every update as
+//         replica id: u64, address, gossip address, incarnation: u64,
+//         state: u8 (0 Alive, 1 Suspect, 2 Dead)
+//     where addresses are strings as in config files.
+//
+// Payload of an authenticated GossipMessage (type 6), used instead of type 4
+// when the cluster has a shared key (see auth.rs):
+//
+//     HMAC tag: 32 bytes, payload of a GossipMessage
+//
+// Payload of a ClientMessage (type 5), only spoken on client ports:
+//
+//     tag: u8, followed by
+//         0 SubmitRequest: value
+//         1 GetRequest: instance id
+//         2 StatusRequest
+//         3 Submitted: instance id, position: u32
+//         4 Decided: instance id, number of commands: u32, then every
+//                    command as a value
+//         5 Undecided: instance id
+//         6 StatusReply: replica id: u64, cluster id, in flight: u32,
+//                        number of replicas: u16, then for every replica
+//                        stable id, address ("" if unknown), alive: u8,
+//                        milliseconds since last heard from: u64
+//                        (0xffffffffffffffff if never)
+//         7 Failure: reason
+//     where instance ids and values are as in a PaxosMessage.
+
+use std::str;
+use std::io::{Reader, Writer, Buffer, io_error};
+use std::io::mem::{MemWriter, BufReader};
+
+use super::replica::ReplicaID;
+use super::instance::{InstanceID, SequenceID};
+use super::auth::{Authenticator, TAG_LEN, gossip_tag, check_gossip_tag};
+use super::transport::Address;
+use super::membership::{Member, MemberState, Alive, Suspect, Dead};
+use super::client::{ClusterStatus, ReplicaStatus};
+use super::message::{Message, NetworkM, PaxosM, GossipM, ClientM, NetworkMessage, Hello, Reject,
+    Heartbeat, HeartbeatAck, Identify, Nonce, Handshake, PaxosMessage,
+    GossipMessage, GossipKind, Ping, PingReq, Ack,
+    ClientMessage, SubmitRequest, GetRequest, StatusRequest, Submitted, Decided, Undecided,
+    StatusReply, Failure,
+    PaxosMessageContent, Propose, Promise, RejectPropose, Request, Accept,
+    RejectRequest, Commit, Acknowledge};
+
+pub static MAGIC: u16 = 0x5058;
+pub static MIN_PROTOCOL_VERSION: u8 = 1;
+pub static MAX_PROTOCOL_VERSION: u8 = 5;
+pub static MAX_PAYLOAD_LEN: uint = 16 * 1024 * 1024;
+
+// What the payload of a Request adds to the value it carries, at most: the
+// instance id, the content tag, the sequence id and the value's length, plus
+// the counter and the tag of an authenticated message
+pub static MAX_REQUEST_OVERHEAD: uint = 16 + 1 + 16 + 4 + 8 + TAG_LEN;
+
+static NETWORK_MESSAGE: u8 = 1;
+static PAXOS_MESSAGE: u8 = 2;
+static AUTHENTICATED_PAXOS_MESSAGE: u8 = 3;
+static GOSSIP_MESSAGE: u8 = 4;
+static CLIENT_MESSAGE: u8 = 5;
+static AUTHENTICATED_GOSSIP_MESSAGE: u8 = 6;
+
+// The first protocol version whose frame headers have their own checksum
+static HEADER_CHECKSUM_VERSION: u8 = 4;
+
+// Stands for "never" where a time is expected
+static NEVER: u64 = 0xffffffffffffffff;
+
+#[deriving(ToStr)]
+pub enum FrameError {
+    // The underlying connection failed; nothing more can be read from it
+    ConnectionFailed(~str),
+    UnsupportedVersion(u8),
+    UnknownMessageType(u8),
+    Oversized(uint),
+    // (#expected, #actual)
+    ChecksumMismatch(u32, u32),
+    // The header checksum doesn't match, or a checksum is missing
+    CorruptHeader,
+    MalformedPayload,
+    // The message is missing or failed authentication
+    Unauthenticated(~str),
+}
+
+impl FrameError {
+    // Whether the stream is unusable after this error.  All other errors
+    // only cost the offending frame.
+    pub fn is_fatal(&self) -> bool {
+        match *self {
+            ConnectionFailed(..) => true,
+            _ => false,
+        }
+    }
+}
+
+// Reads and writes framed messages on top of a byte stream.
+pub struct FrameStream<S> {
+    priv inner: S,
+    // The version frames are sent with
+    priv version: u8,
+    // Authenticates Paxos messages when the cluster has a shared key
+    priv auth: Option<Authenticator>,
+    // The shared key, on gossip connections
+    priv gossip_key: Option<~[u8]>,
+}
+
+impl<S: Reader + Writer> FrameStream<S> {
+    pub fn new(inner: S) -> FrameStream<S> {
+        FrameStream{
+            inner: inner,
+            version: MIN_PROTOCOL_VERSION,
+            auth: None,
+            gossip_key: None,
+        }
+    }
+
+    // Switches to the version negotiated during the handshake
+    pub fn set_version(&mut self, version: u8) {
+        self.version = version;
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    // From now on, Paxos messages must be authenticated in both directions
+    pub fn set_authenticator(&mut self, auth: Authenticator) {
+        self.auth = Some(auth);
+    }
+
+    // From now on, gossip messages must be tagged with the key in both
+    // directions
+    pub fn set_gossip_key(&mut self, key: ~[u8]) {
+        self.gossip_key = Some(key);
+    }
+
+    // Writes a message as a single frame.  IO errors are raised through
+    // io_error, like any other write.
+    pub fn send(&mut self, msg: &Message) {
+        self.write_frame(msg);
+        self.inner.flush();
+    }
+
+    // Like send, but returns false instead of raising if the connection is
+    // broken, in which case the message might not have been delivered.
+    pub fn try_send(&mut self, msg: &Message) -> bool {
+        self.try_send_all([msg.clone()])
+    }
+
+    // Writes several messages, one frame each, and flushes them together.
+    // Returns false if the connection is broken, in which case any of the
+    // messages might not have been delivered.
+    pub fn try_send_all(&mut self, msgs: &[Message]) -> bool {
+        let mut ok = true;
+        io_error::cond.trap(|err| {
+            debug!("Failed to write to connection: {}", err.to_str());
+            ok = false;
+        }).inside(|| {
+            for msg in msgs.iter() {
+                self.write_frame(msg);
+            }
+            self.inner.flush();
+        });
+        ok
+    }
+
+    fn write_frame(&mut self, msg: &Message) {
+        let (mut msg_type, mut payload) = encode(msg);
+        if msg_type == PAXOS_MESSAGE {
+            match self.auth {
+                Some(ref mut auth) => {
+                    let (counter, tag) = auth.seal(payload);
+                    let mut w = MemWriter::new();
+                    w.write_be_u64(counter);
+                    w.write(tag);
+                    w.write(payload);
+                    msg_type = AUTHENTICATED_PAXOS_MESSAGE;
+                    payload = w.inner();
+                },
+                None => (),
+            }
+        }
+        if msg_type == GOSSIP_MESSAGE {
+            for key in self.gossip_key.iter() {
+                let mut w = MemWriter::new();
+                w.write(gossip_tag(*key, payload));
+                w.write(payload);
+                msg_type = AUTHENTICATED_GOSSIP_MESSAGE;
+                payload = w.inner();
+            }
+        }
+        let header = encode_header(self.version, msg_type, payload.len() as u32, crc32(payload));
+        self.inner.write(header);
+        if self.version >= HEADER_CHECKSUM_VERSION {
+            self.inner.write_be_u32(crc32(header));
+        }
+        self.inner.write(payload);
+    }
+
+    // Reads the next frame.  Corrupt, oversized or unsupported frames are
+    // consumed and reported as non-fatal errors, so the caller can simply
+    // keep reading.
+    pub fn recv(&mut self) -> Result<Message, FrameError> {
+        let mut failure = None;
+        let res = io_error::cond.trap(|err| {
+            if failure.is_none() {
+                failure = Some(err.to_str());
+            }
+        }).inside(|| self.read_frame());
+        match failure {
+            Some(desc) => Err(ConnectionFailed(desc)),
+            None => res,
+        }
+    }
+
+    fn read_frame(&mut self) -> Result<Message, FrameError> {
+        // Find the magic, skipping over any garbage
+        let mut skipped = 0;
+        let mut prev = match self.inner.read_byte() {
+            Some(b) => b,
+            None => return Err(ConnectionFailed(~"end of stream")),
+        };
+        loop {
+            let b = match self.inner.read_byte() {
+                Some(b) => b,
+                None => return Err(ConnectionFailed(~"end of stream")),
+            };
+            if ((prev as u16) << 8 | b as u16) == MAGIC {
+                break;
+            }
+            prev = b;
+            skipped += 1;
+        }
+        if skipped > 0 {
+            warn!("Skipped {} bytes of garbage before a frame", skipped);
+        }
+
+        let version = self.inner.read_u8();
+        let msg_type = self.inner.read_u8();
+        let len = self.inner.read_be_u32() as uint;
+        let checksum = self.inner.read_be_u32();
+        if version >= HEADER_CHECKSUM_VERSION {
+            let header = encode_header(version, msg_type, len as u32, checksum);
+            if self.inner.read_be_u32() != crc32(header) {
+                return Err(CorruptHeader);
+            }
+        } else if self.version >= HEADER_CHECKSUM_VERSION {
+            return Err(CorruptHeader);
+        }
+
+        if len > MAX_PAYLOAD_LEN {
+            self.skip(len);
+            return Err(Oversized(len));
+        }
+        let payload = self.inner.read_bytes(len);
+
+        if version < MIN_PROTOCOL_VERSION || version > MAX_PROTOCOL_VERSION {
+            return Err(UnsupportedVersion(version));
+        }
+        let actual = crc32(payload);
+        if actual != checksum {
+            return Err(ChecksumMismatch(checksum, actual));
+        }
+
+        if (msg_type == PAXOS_MESSAGE && self.auth.is_some()) ||
+                (msg_type == GOSSIP_MESSAGE && self.gossip_key.is_some()) {
+            return Err(Unauthenticated(~"message is not authenticated"));
+        }
+        if msg_type == AUTHENTICATED_GOSSIP_MESSAGE {
+            let key = match self.gossip_key {
+                Some(ref key) => key,
+                None => return Err(Unauthenticated(~"no key to verify message with")),
+            };
+            if payload.len() < TAG_LEN {
+                return Err(MalformedPayload);
+            }
+            let (tag, inner) = (payload.slice_to(TAG_LEN), payload.slice_from(TAG_LEN));
+            if !check_gossip_tag(*key, inner, tag) {
+                return Err(Unauthenticated(~"invalid message authentication code"));
+            }
+            return decode(GOSSIP_MESSAGE, inner);
+        }
+        if msg_type != AUTHENTICATED_PAXOS_MESSAGE {
+            return decode(msg_type, payload);
+        }
+
+        let auth = match self.auth {
+            Some(ref mut auth) => auth,
+            None => return Err(Unauthenticated(~"no key to verify message with")),
+        };
+        if payload.len() < 8 + TAG_LEN {
+            return Err(MalformedPayload);
+        }
+        let counter = BufReader::new(payload.slice_to(8)).read_be_u64();
+        let tag = payload.slice(8, 8 + TAG_LEN);
+        let inner = payload.slice_from(8 + TAG_LEN);
+        match auth.open(counter, inner, tag) {
+            Ok(()) => decode(PAXOS_MESSAGE, inner),
+            Err(reason) => Err(Unauthenticated(reason)),
+        }
+    }
+
+    fn skip(&mut self, mut len: uint) {
+        let mut buf = [0u8, ..4096];
+        while len > 0 {
+            let n = if len < buf.len() { len } else { buf.len() };
+            match self.inner.read(buf.mut_slice_to(n)) {
+                Some(read) => len -= read,
+                None => return,
+            }
+        }
+    }
+}
+
+fn encode_header(version: u8, msg_type: u8, len: u32, checksum: u32) -> ~[u8] {
+    let mut w = MemWriter::new();
+    w.write_be_u16(MAGIC);
+    w.write_u8(version);
+    w.write_u8(msg_type);
+    w.write_be_u32(len);
+    w.write_be_u32(checksum);
+    w.inner()
+}
+
+fn encode(msg: &Message) -> (u8, ~[u8]) {
+    let mut w = MemWriter::new();
+    let msg_type = match *msg {
+        NetworkM(Hello(ref h)) => {
+            w.write_u8(0);
+            write_str(&mut w, h.cluster_id);
+            w.write_u8(h.min_version);
+            w.write_u8(h.max_version);
+            w.write_be_u64(h.replica_id as u64);
+            NETWORK_MESSAGE
+        },
+        NetworkM(Reject(ref reason)) => {
+            w.write_u8(1);
+            write_str(&mut w, *reason);
+            NETWORK_MESSAGE
+        },
+        NetworkM(Heartbeat(clock)) => {
+            w.write_u8(2);
+            w.write_be_u64(clock);
+            NETWORK_MESSAGE
+        },
+        NetworkM(HeartbeatAck(clock)) => {
+            w.write_u8(3);
+            w.write_be_u64(clock);
+            NETWORK_MESSAGE
+        },
+        NetworkM(Identify(ref stable_id)) => {
+            w.write_u8(4);
+            write_str(&mut w, *stable_id);
+            NETWORK_MESSAGE
+        },
+        NetworkM(Nonce(nonce)) => {
+            w.write_u8(5);
+            w.write_be_u64(nonce);
+            NETWORK_MESSAGE
+        },
+        PaxosM(ref m) => {
+            let (rid, n) = m.instance_id;
+            w.write_be_u64(rid as u64);
+            w.write_be_u64(n);
+            encode_content(&mut w, &m.content);
+            PAXOS_MESSAGE
+        },
+        GossipM(ref m) => {
+            encode_gossip(&mut w, m);
+            GOSSIP_MESSAGE
+        },
+        ClientM(ref m) => {
+            encode_client(&mut w, m);
+            CLIENT_MESSAGE
+        },
+    };
+    (msg_type, w.inner())
+}
+
+fn encode_client(w: &mut MemWriter, m: &ClientMessage) {
+    match *m {
+        SubmitRequest(ref value) => { w.write_u8(0); write_value(w, *value); },
+        GetRequest(iid) => { w.write_u8(1); write_iid(w, iid); },
+        StatusRequest => w.write_u8(2),
+        Submitted(iid, position) => {
+            w.write_u8(3);
+            write_iid(w, iid);
+            w.write_be_u32(position as u32);
+        },
+        Decided(iid, ref commands) => {
+            w.write_u8(4);
+            write_iid(w, iid);
+            w.write_be_u32(commands.len() as u32);
+            for command in commands.iter() {
+                write_value(w, *command);
+            }
+        },
+        Undecided(iid) => { w.write_u8(5); write_iid(w, iid); },
+        StatusReply(ref status) => {
+            w.write_u8(6);
+            w.write_be_u64(status.replica_id as u64);
+            write_str(w, status.cluster_id);
+            w.write_be_u32(status.in_flight as u32);
+            w.write_be_u16(status.replicas.len() as u16);
+            for replica in status.replicas.iter() {
+                write_str(w, replica.stable_id);
+                write_str(w, replica.address.as_ref().map_default(~"", |a| a.to_str()));
+                w.write_u8(replica.alive as u8);
+                w.write_be_u64(replica.last_heard.unwrap_or(NEVER));
+            }
+        },
+        Failure(ref reason) => { w.write_u8(7); write_str(w, *reason); },
+    }
+}
+
+fn write_iid(w: &mut MemWriter, iid: InstanceID) {
+    let (rid, n) = iid;
+    w.write_be_u64(rid as u64);
+    w.write_be_u64(n);
+}
+
+fn write_value(w: &mut MemWriter, value: &[u8]) {
+    w.write_be_u32(value.len() as u32);
+    w.write(value);
+}
+
+fn encode_gossip(w: &mut MemWriter, m: &GossipMessage) {
+    write_str(w, m.cluster_id);
+    w.write_be_u64(m.seq);
+    match m.kind {
+        Ping => w.write_u8(0),
+        PingReq(target) => { w.write_u8(1); w.write_be_u64(target as u64); },
+        Ack => w.write_u8(2),
+    }
+    w.write_be_u16(m.updates.len() as u16);
+    for member in m.updates.iter() {
+        w.write_be_u64(member.id as u64);
+        write_str(w, member.address.to_str());
+        write_str(w, member.gossip_address.to_str());
+        w.write_be_u64(member.incarnation);
+        w.write_u8(match member.state {
+            Alive => 0,
+            Suspect => 1,
+            Dead => 2,
+        });
+    }
+}
+
+fn encode_content(w: &mut MemWriter, content: &PaxosMessageContent) {
+    match *content {
+        Propose(seq) => { w.write_u8(0); write_seq(w, seq); },
+        Promise(seq) => { w.write_u8(1); write_seq(w, seq); },
+        RejectPropose(s1, s2) => { w.write_u8(2); write_seq(w, s1); write_seq(w, s2); },
+        Request(seq, ref value) => {
+            w.write_u8(3);
+            write_seq(w, seq);
+            w.write_be_u32(value.len() as u32);
+            w.write(*value);
+        },
+        Accept(seq) => { w.write_u8(4); write_seq(w, seq); },
+        RejectRequest(s1, s2) => { w.write_u8(5); write_seq(w, s1); write_seq(w, s2); },
+        Commit(seq) => { w.write_u8(6); write_seq(w, seq); },
+        Acknowledge(seq) => { w.write_u8(7); write_seq(w, seq); },
+    }
+}
+
+fn write_str(w: &mut MemWriter, s: &str) {
+    let bytes = s.as_bytes();
+    w.write_be_u16(bytes.len() as u16);
+    w.write(bytes);
+}
+
+fn write_seq(w: &mut MemWriter, seq: SequenceID) {
+    let (n, rid) = seq;
+    w.write_be_u64(n as u64);
+    w.write_be_u64(rid as u64);
+}
+
+fn decode(msg_type: u8, payload: &[u8]) -> Result<Message, FrameError> {
+    if msg_type != NETWORK_MESSAGE && msg_type != PAXOS_MESSAGE && msg_type != GOSSIP_MESSAGE &&
+            msg_type != CLIENT_MESSAGE {
+        return Err(UnknownMessageType(msg_type));
+    }
+
+    let mut r = BufReader::new(payload);
+    let mut truncated = false;
+    let msg = io_error::cond.trap(|_| {
+        truncated = true;
+    }).inside(|| {
+        if msg_type == NETWORK_MESSAGE {
+            decode_network_message(&mut r).map(|m| NetworkM(m))
+        } else if msg_type == GOSSIP_MESSAGE {
+            decode_gossip(&mut r).map(|m| GossipM(m))
+        } else if msg_type == CLIENT_MESSAGE {
+            decode_client(&mut r).map(|m| ClientM(m))
+        } else {
+            let instance_id = (r.read_be_u64() as ReplicaID, r.read_be_u64());
+            decode_content(&mut r).map(|content| {
+                PaxosM(PaxosMessage{
+                    instance_id: instance_id,
+                    content: content,
+                })
+            })
+        }
+    });
+
+    // Trailing bytes are as suspicious as missing ones
+    match msg {
+        Some(msg) if !truncated && r.eof() => Ok(msg),
+        _ => Err(MalformedPayload),
+    }
+}
+
+fn decode_network_message(r: &mut BufReader) -> Option<NetworkMessage> {
+    match r.read_u8() {
+        0 => {
+            let cluster_id = match read_str(r) {
+                Some(s) => s,
+                None => return None,
+            };
+            let min_version = r.read_u8();
+            let max_version = r.read_u8();
+            let replica_id = r.read_be_u64() as ReplicaID;
+            Some(Hello(Handshake{
+                cluster_id: cluster_id,
+                min_version: min_version,
+                max_version: max_version,
+                replica_id: replica_id,
+            }))
+        },
+        1 => read_str(r).map(|reason| Reject(reason)),
+        2 => Some(Heartbeat(r.read_be_u64())),
+        3 => Some(HeartbeatAck(r.read_be_u64())),
+        4 => read_str(r).map(|stable_id| Identify(stable_id)),
+        5 => Some(Nonce(r.read_be_u64())),
+        _ => None,
+    }
+}
+
+fn decode_gossip(r: &mut BufReader) -> Option<GossipMessage> {
+    let cluster_id = match read_str(r) {
+        Some(s) => s,
+        None => return None,
+    };
+    let seq = r.read_be_u64();
+    let kind: GossipKind = match r.read_u8() {
+        0 => Ping,
+        1 => PingReq(r.read_be_u64() as ReplicaID),
+        2 => Ack,
+        _ => return None,
+    };
+    let count = r.read_be_u16() as uint;
+    if count > max_items(r, MIN_UPDATE_LEN) {
+        return None;
+    }
+    let mut updates = ~[];
+    for _ in range(0, count) {
+        let id = r.read_be_u64() as ReplicaID;
+        let address = match read_address(r) {
+            Some(a) => a,
+            None => return None,
+        };
+        let gossip_address = match read_address(r) {
+            Some(a) => a,
+            None => return None,
+        };
+        let incarnation = r.read_be_u64();
+        let state: MemberState = match r.read_u8() {
+            0 => Alive,
+            1 => Suspect,
+            2 => Dead,
+            _ => return None,
+        };
+        updates.push(Member{
+            id: id,
+            address: address,
+            gossip_address: gossip_address,
+            incarnation: incarnation,
+            state: state,
+        });
+    }
+    Some(GossipMessage{
+        cluster_id: cluster_id,
+        seq: seq,
+        kind: kind,
+        updates: updates,
+    })
+}
+
+fn decode_client(r: &mut BufReader) -> Option<ClientMessage> {
+    let msg = match r.read_u8() {
+        0 => match read_value(r) {
+            Some(value) => SubmitRequest(value),
+            None => return None,
+        },
+        1 => GetRequest(read_iid(r)),
+        2 => StatusRequest,
+        3 => {
+            let iid = read_iid(r);
+            Submitted(iid, r.read_be_u32() as uint)
+        },
+        4 => {
+            let iid = read_iid(r);
+            let count = r.read_be_u32() as uint;
+            if count > max_items(r, MIN_VALUE_LEN) {
+                return None;
+            }
+            let mut commands = ~[];
+            for _ in range(0, count) {
+                match read_value(r) {
+                    Some(command) => commands.push(command),
+                    None => return None,
+                }
+            }
+            Decided(iid, commands)
+        },
+        5 => Undecided(read_iid(r)),
+        6 => {
+            let replica_id = r.read_be_u64() as ReplicaID;
+            let cluster_id = match read_str(r) {
+                Some(s) => s,
+                None => return None,
+            };
+            let in_flight = r.read_be_u32() as uint;
+            let count = r.read_be_u16() as uint;
+            if count > max_items(r, MIN_REPLICA_STATUS_LEN) {
+                return None;
+            }
+            let mut replicas = ~[];
+            for id in range(0, count) {
+                let stable_id = match read_str(r) {
+                    Some(s) => s,
+                    None => return None,
+                };
+                let address = match read_str(r) {
+                    Some(ref s) if s.is_empty() => None,
+                    Some(s) => match from_str::<Address>(s) {
+                        Some(addr) => Some(addr),
+                        None => return None,
+                    },
+                    None => return None,
+                };
+                let alive = r.read_u8() != 0;
+                let last_heard = r.read_be_u64();
+                replicas.push(ReplicaStatus{
+                    id: id,
+                    stable_id: stable_id,
+                    address: address,
+                    alive: alive,
+                    last_heard: if last_heard == NEVER { None } else { Some(last_heard) },
+                });
+            }
+            StatusReply(ClusterStatus{
+                replica_id: replica_id,
+                cluster_id: cluster_id,
+                in_flight: in_flight,
+                replicas: replicas,
+            })
+        },
+        7 => match read_str(r) {
+            Some(reason) => Failure(reason),
+            None => return None,
+        },
+        _ => return None,
+    };
+    Some(msg)
+}
+
+fn read_iid(r: &mut BufReader) -> InstanceID {
+    let rid = r.read_be_u64() as ReplicaID;
+    (rid, r.read_be_u64())
+}
+
+fn read_value(r: &mut BufReader) -> Option<~[u8]> {
+    let len = r.read_be_u32() as uint;
+    if len > MAX_PAYLOAD_LEN {
+        return None;
+    }
+    read_exactly(r, len)
+}
+
+// The smallest encodings of the items that come in counted lists: a value
+// is at least its length, a gossip update an ID, two empty addresses, an
+// incarnation and a state, and a replica's status an ID, an address, a flag
+// and a time
+static MIN_VALUE_LEN: uint = 4;
+static MIN_UPDATE_LEN: uint = 8 + 2 + 2 + 8 + 1;
+static MIN_REPLICA_STATUS_LEN: uint = 2 + 2 + 1 + 8;
+
+// The most items of at least min_len bytes that the rest of the payload can
+// hold.  Counts read off the wire are checked against it, so that a bogus
+// one can't make us allocate or loop for more than the message holds.
+fn max_items(r: &mut BufReader, min_len: uint) -> uint {
+    r.fill().len() / min_len
+}
+
+// Reads len bytes, or nothing if the payload ends first
+fn read_exactly(r: &mut BufReader, len: uint) -> Option<~[u8]> {
+    if len > r.fill().len() {
+        return None;
+    }
+    Some(r.read_bytes(len))
+}
+
+fn read_address(r: &mut BufReader) -> Option<Address> {
+    read_str(r).and_then(|s| from_str::<Address>(s))
+}
+
+fn decode_content(r: &mut BufReader) -> Option<PaxosMessageContent> {
+    let content = match r.read_u8() {
+        0 => Propose(read_seq(r)),
+        1 => Promise(read_seq(r)),
+        2 => {
+            let s1 = read_seq(r);
+            RejectPropose(s1, read_seq(r))
+        },
+        3 => {
+            let seq = read_seq(r);
+            let len = r.read_be_u32() as uint;
+            if len > MAX_PAYLOAD_LEN {
+                return None;
+            }
+            match read_exactly(r, len) {
+                Some(value) => Request(seq, value),
+                None => return None,
+            }
+        },
+        4 => Accept(read_seq(r)),
+        5 => {
+            let s1 = read_seq(r);
+            RejectRequest(s1, read_seq(r))
+        },
+        6 => Commit(read_seq(r)),
+        7 => Acknowledge(read_seq(r)),
+        _ => return None,
+    };
+    Some(content)
+}
+
+fn read_str(r: &mut BufReader) -> Option<~str> {
+    let len = r.read_be_u16() as uint;
+    read_exactly(r, len).and_then(|bytes| str::from_utf8_owned_opt(bytes))
+}
+
+fn read_seq(r: &mut BufReader) -> SequenceID {
+    let n = r.read_be_u64() as uint;
+    (n, r.read_be_u64() as ReplicaID)
+}
+
+// CRC-32 (IEEE 802.3), computed bit by bit
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for &b in data.iter() {
+        crc ^= b as u32;
+        for _ in range(0, 8) {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use std::cmp;
+    use std::vec;
+
+    use super::{FrameStream, FrameError, UnsupportedVersion, Oversized, ChecksumMismatch,
+        CorruptHeader, MalformedPayload, MIN_PROTOCOL_VERSION, MAX_PROTOCOL_VERSION,
+        MAX_PAYLOAD_LEN, CLIENT_MESSAGE, encode_header, crc32, decode};
+    use super::super::message::{Message, NetworkM, Reject};
+
+    // A buffer that frames are written to and then read back from
+    struct Pipe {
+        buf: ~[u8],
+        pos: uint,
+    }
+
+    impl Reader for Pipe {
+        fn read(&mut self, out: &mut [u8]) -> Option<uint> {
+            if self.pos == self.buf.len() {
+                return None;
+            }
+            let n = cmp::min(out.len(), self.buf.len() - self.pos);
+            for i in range(0, n) {
+                out[i] = self.buf[self.pos + i];
+            }
+            self.pos += n;
+            Some(n)
+        }
+
+        fn eof(&mut self) -> bool {
+            self.pos == self.buf.len()
+        }
+    }
+
+    impl Writer for Pipe {
+        fn write(&mut self, buf: &[u8]) {
+            self.buf.push_all(buf);
+        }
+    }
+
+    fn pipe(version: u8) -> FrameStream<Pipe> {
+        let mut stream = FrameStream::new(Pipe{ buf: ~[], pos: 0 });
+        stream.set_version(version);
+        stream
+    }
+
+    fn reject(reason: &str) -> Message {
+        NetworkM(Reject(reason.to_owned()))
+    }
+
+    // Appends a frame with a checked header, whatever its fields say
+    fn push_frame(stream: &mut FrameStream<Pipe>, version: u8, len: uint, payload: &[u8]) {
+        let header = encode_header(version, 1, len as u32, crc32(payload));
+        stream.inner.buf.push_all(header);
+        let header_crc = crc32(header);
+        stream.inner.buf.push_all([(header_crc >> 24) as u8, (header_crc >> 16) as u8,
+                                   (header_crc >> 8) as u8, header_crc as u8]);
+        stream.inner.buf.push_all(payload);
+    }
+
+    fn expect_reject(stream: &mut FrameStream<Pipe>, expected: &str) {
+        match stream.recv() {
+            Ok(NetworkM(Reject(reason))) => assert_eq!(reason.as_slice(), expected),
+            Ok(msg) => fail!("unexpected message {}", msg.to_str()),
+            Err(err) => fail!("unexpected error {}", err.to_str()),
+        }
+    }
+
+    fn expect_error(stream: &mut FrameStream<Pipe>) -> FrameError {
+        match stream.recv() {
+            Ok(msg) => fail!("unexpected message {}", msg.to_str()),
+            Err(err) => {
+                assert!(!err.is_fatal());
+                err
+            },
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for &version in [MIN_PROTOCOL_VERSION, MAX_PROTOCOL_VERSION].iter() {
+            let mut stream = pipe(version);
+            assert!(stream.try_send(&reject("a")));
+            assert!(stream.try_send(&reject("b")));
+            expect_reject(&mut stream, "a");
+            expect_reject(&mut stream, "b");
+        }
+    }
+
+    #[test]
+    fn skips_garbage_before_the_magic() {
+        let mut stream = pipe(MAX_PROTOCOL_VERSION);
+        stream.inner.buf.push_all([0x50, 0x00, 0xff, 0x50]);
+        stream.try_send(&reject("a"));
+        expect_reject(&mut stream, "a");
+    }
+
+    #[test]
+    fn rejects_unsupported_versions() {
+        let mut stream = pipe(MAX_PROTOCOL_VERSION);
+        push_frame(&mut stream, 200, 3, [1, 2, 3]);
+        stream.try_send(&reject("a"));
+        assert_eq!(expect_error(&mut stream).to_str(), UnsupportedVersion(200).to_str());
+        expect_reject(&mut stream, "a");
+    }
+
+    #[test]
+    fn skips_oversized_payloads() {
+        let mut stream = pipe(MAX_PROTOCOL_VERSION);
+        let len = MAX_PAYLOAD_LEN + 1;
+        push_frame(&mut stream, MAX_PROTOCOL_VERSION, len, vec::from_elem(len, 0u8));
+        stream.try_send(&reject("a"));
+        assert_eq!(expect_error(&mut stream).to_str(), Oversized(len).to_str());
+        expect_reject(&mut stream, "a");
+    }
+
+    #[test]
+    fn rejects_payload_checksum_mismatches() {
+        let mut stream = pipe(MAX_PROTOCOL_VERSION);
+        stream.try_send(&reject("a"));
+        stream.try_send(&reject("b"));
+        // The last byte of the first payload, after a 16-byte header
+        stream.inner.buf[19] = 'x' as u8;
+        match expect_error(&mut stream) {
+            ChecksumMismatch(..) => (),
+            err => fail!("unexpected error {}", err.to_str()),
+        }
+        expect_reject(&mut stream, "b");
+    }
+
+    #[test]
+    fn resyncs_after_a_corrupt_length() {
+        let mut stream = pipe(MAX_PROTOCOL_VERSION);
+        stream.try_send(&reject("a"));
+        stream.try_send(&reject("b"));
+        // Make the first frame claim a payload of about 2 GB
+        stream.inner.buf[4] = 0x7f;
+        assert_eq!(expect_error(&mut stream).to_str(), CorruptHeader.to_str());
+        expect_reject(&mut stream, "b");
+    }
+
+    #[test]
+    fn rejects_unchecked_headers_after_upgrading() {
+        let mut stream = pipe(MIN_PROTOCOL_VERSION);
+        stream.try_send(&reject("a"));
+        stream.set_version(MAX_PROTOCOL_VERSION);
+        stream.try_send(&reject("b"));
+        assert_eq!(expect_error(&mut stream).to_str(), CorruptHeader.to_str());
+        expect_reject(&mut stream, "b");
+    }
+
+    // A Decided reply for instance (0, 0) with the count and the bytes after it
+    fn decided(count: u32, rest: &[u8]) -> ~[u8] {
+        let mut payload = ~[4u8];
+        payload.push_all(vec::from_elem(16, 0u8));
+        payload.push_all([(count >> 24) as u8, (count >> 16) as u8, (count >> 8) as u8, count as u8]);
+        payload.push_all(rest);
+        payload
+    }
+
+    #[test]
+    fn rejects_counts_larger_than_the_payload() {
+        match decode(CLIENT_MESSAGE, decided(0xffffffff, [])) {
+            Err(err) => assert_eq!(err.to_str(), MalformedPayload.to_str()),
+            Ok(msg) => fail!("unexpected message {}", msg.to_str()),
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_values() {
+        // Two commands, the first of which claims 5 bytes but has 3
+        match decode(CLIENT_MESSAGE, decided(2, [0, 0, 0, 5, 1, 2, 3, 0])) {
+            Err(err) => assert_eq!(err.to_str(), MalformedPayload.to_str()),
+            Ok(msg) => fail!("unexpected message {}", msg.to_str()),
+        }
+    }
+}
@@ -8,7 +8,7 @@
 // #[feature(globs)];
 
 extern mod extra;
-extern mod object_stream;
+extern mod openssl;
 
 pub mod internal;
 
@@ -1,8 +1,8 @@
 extern mod paxos;
 
+use std::libc;
 use std::path::Path;
-use std::io::fs::File;
-use std::io::timer::sleep;
+use std::io::signal::{Listener, HangUp, Interrupt};
 
 use paxos::internal::replica::Replica;
 
@@ -10,13 +10,36 @@ fn main() {
     let mut replicas = ~[];
     for i in range(1, 4) {
         let path: Path = Path::new(format!("config-{}.json", i));
-        let on_error = || fail!("open of {:?} failed", path);
-        let mut reader: File = File::open(&path).unwrap_or_else(on_error);
-        replicas.push(Replica::new(&mut reader));
+        match Replica::open(&path) {
+            Ok(r) => replicas.push(r),
+            Err(err) => fail!("{}: {}", path.display(), err.to_str()),
+        }
     }
 
-    let mut r = replicas.pop();
-    r.submit(~[0u8, 1u8, 2u8]);
+    match replicas[replicas.len() - 1].submit(~[0u8, 1u8, 2u8]) {
+        Ok(iid) => println!("Submitted instance {:?}", iid),
+        Err(err) => println!("Submit failed: {}", err.to_str()),
+    }
 
-    sleep(10000);
+    // Reload the configs on SIGHUP, until interrupted
+    let mut signals = Listener::new();
+    signals.register(HangUp);
+    signals.register(Interrupt);
+    loop {
+        match signals.port.recv() {
+            HangUp => {
+                for replica in replicas.mut_iter() {
+                    match replica.reload() {
+                        Ok(report) => println!("Reloaded replica {}: applied {:?}, refused {:?}",
+                            replica.id, report.applied, report.refused),
+                        Err(err) => println!("Reload of replica {} failed: {}", replica.id, err.to_str()),
+                    }
+                }
+            },
+            _ => break,
+        }
+    }
+    // The replicas' tasks never finish on their own, and would keep the
+    // process alive
+    unsafe { libc::exit(0) };
 }
\ No newline at end of file
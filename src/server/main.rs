@@ -0,0 +1,122 @@
+extern mod extra;
+extern mod paxos;
+
+use std::os;
+use std::libc;
+use std::path::Path;
+use std::comm::Select;
+use std::io::stderr;
+use std::io::signal::{Listener, HangUp, Interrupt, Quit};
+use std::io::timer::Timer;
+use std::unstable::atomics::{AtomicBool, INIT_ATOMIC_BOOL, SeqCst};
+
+use extra::getopts::groups::{getopts, optopt, optflag, usage};
+
+use paxos::internal::replica::Replica;
+use paxos::internal::config::ListenFailed;
+
+// Runs a single replica until it's interrupted.  SIGHUP reloads the config;
+// SIGINT, SIGQUIT or SIGTERM let the instances in flight finish and exit,
+// with EXIT_TEMPFAIL if some didn't finish in time.
+
+// Exit codes, as in sysexits.h
+static EXIT_USAGE: int = 64;
+static EXIT_UNAVAILABLE: int = 69;
+static EXIT_TEMPFAIL: int = 75;
+static EXIT_CONFIG: int = 78;
+
+// How long to wait for the instances in flight on shutdown, in milliseconds
+static SHUTDOWN_TIMEOUT: u64 = 5000;
+
+// The runtime's signal listener doesn't know SIGTERM, so it gets a handler
+// of its own, which only raises a flag that the main loop checks this often,
+// in milliseconds
+static TERMINATE_POLL_INTERVAL: u64 = 100;
+
+static mut TERMINATED: AtomicBool = INIT_ATOMIC_BOOL;
+
+extern fn on_terminate(_: libc::c_int) {
+    unsafe { TERMINATED.store(true, SeqCst) };
+}
+
+extern {
+    fn signal(signum: libc::c_int, handler: extern fn(libc::c_int)) -> *libc::c_void;
+}
+
+fn main() {
+    let args = os::args();
+    let opts = ~[
+        optopt("c", "config", "the replica's config, JSON or TOML", "PATH"),
+        optflag("h", "help", "print this help and exit"),
+    ];
+    let matches = match getopts(args.tail(), opts) {
+        Ok(m) => m,
+        Err(f) => return fail_with(EXIT_USAGE, f.to_err_msg()),
+    };
+    if matches.opt_present("help") {
+        println(usage(format!("Usage: {} --config PATH", args[0]), opts));
+        return;
+    }
+    let path = match matches.opt_str("config") {
+        Some(p) => Path::new(p),
+        None => return fail_with(EXIT_USAGE, ~"--config is required"),
+    };
+
+    // Listen for signals first, so that none is missed while starting up
+    let mut signals = Listener::new();
+    signals.register(HangUp);
+    signals.register(Interrupt);
+    signals.register(Quit);
+    unsafe { signal(libc::SIGTERM, on_terminate) };
+
+    let mut replica = match Replica::open(&path) {
+        Ok(r) => r,
+        Err(ListenFailed(addr)) => return fail_with(EXIT_UNAVAILABLE, format!("can't listen at {}", addr)),
+        Err(err) => return fail_with(EXIT_CONFIG, format!("{}: {}", path.display(), err.to_str())),
+    };
+    let stable_id = replica.stable_ids()[replica.id].clone();
+    println!("Replica {} is listening at {}", stable_id, replica.local_address().to_str());
+    for addr in replica.client_address().iter() {
+        println!("Replica {} is serving clients at {}", stable_id, addr.to_str());
+    }
+
+    let mut timer = Timer::new().unwrap();
+    let mut polls = timer.periodic(TERMINATE_POLL_INTERVAL);
+    loop {
+        let sel = Select::new();
+        let mut signal_handle = sel.add(&mut signals.port);
+        let mut poll_handle = sel.add(&mut polls);
+        let ret = sel.wait();
+        if ret == poll_handle.id {
+            poll_handle.recv();
+            if unsafe { TERMINATED.load(SeqCst) } {
+                break;
+            }
+            continue;
+        }
+        match signal_handle.recv() {
+            HangUp => match replica.reload() {
+                Ok(report) => println!("Reloaded {}: applied {:?}, refused {:?}",
+                    path.display(), report.applied, report.refused),
+                Err(err) => error!("Reload of {} failed: {}", path.display(), err.to_str()),
+            },
+            _ => break,
+        }
+    }
+
+    println!("Replica {} is shutting down", stable_id);
+    let status = if replica.drain(SHUTDOWN_TIMEOUT) {
+        0
+    } else {
+        warn!("Replica {} gave up on {} instances in flight", stable_id, replica.in_flight());
+        EXIT_TEMPFAIL
+    };
+    // The replica's tasks never finish on their own, and would keep the
+    // process alive
+    unsafe { libc::exit(status as libc::c_int) };
+}
+
+fn fail_with(code: int, msg: ~str) {
+    stderr().write_line(format!("paxos-server: {}", msg));
+    os::set_exit_status(code);
+}